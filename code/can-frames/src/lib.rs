@@ -0,0 +1,8 @@
+#![no_std]
+
+// The very same files as the firmware uses.
+#[path = "../../src/lib_gear.rs"]
+pub mod lib_gear;
+
+#[path = "../../src/lib_can_frame.rs"]
+pub mod lib_can_frame;
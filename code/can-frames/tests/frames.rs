@@ -0,0 +1,123 @@
+// Decode the frames the way the firmware does, from the MCP2518FD receive object to what the
+// car says.
+//
+// NOTE: These aren't captured off the car (yet)! The receive objects are laid out by hand
+//       from the MCP2518FD datasheet, and the payloads from the W203/W211 CAN databases -
+//       the same unverified IDs as the firmware. Replace them with real captures once there
+//       are any.
+
+use can_frames::lib_can_frame::{
+    decode, nominal_bit_timing, parse_rx_object, CanSignal, CAN_ID_TRANSMISSION, RX_OBJECT_SIZE,
+};
+use can_frames::lib_gear::Button;
+
+// A standard frame, as the controller puts it in the FIFO: ID, then DLC (no IDE, RTR or FDF),
+// then the payload.
+fn rx_object(id: u32, data: &[u8]) -> [u8; RX_OBJECT_SIZE] {
+    let mut obj = [0u8; RX_OBJECT_SIZE];
+    obj[0..4].copy_from_slice(&id.to_le_bytes());
+    obj[4] = data.len() as u8;
+    obj[8..8 + data.len()].copy_from_slice(data);
+    obj
+}
+
+#[test]
+fn transmission_in_drive() {
+    // GS_418h, 'D' shown in the cluster. The rest of the payload isn't used.
+    let obj = [
+        0x18, 0x04, 0x00, 0x00, // SID 0x418
+        0x08, 0x00, 0x00, 0x00, // DLC 8
+        b'D', 0x00, 0x3C, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    let frame = parse_rx_object(&obj);
+    assert_eq!(frame.id, CAN_ID_TRANSMISSION);
+    assert_eq!(frame.len, 8);
+    assert!(decode(&frame) == Some(CanSignal::Gear(Some(Button::D))));
+}
+
+#[test]
+fn transmission_gears() {
+    for (shown, gear) in [
+        (b'P', Some(Button::P)),
+        (b'R', Some(Button::R)),
+        (b'N', Some(Button::N)),
+        (b'D', Some(Button::D)),
+        (b'1', None), // Manual gear selection, not one of ours.
+        (0xFF, None),
+    ] {
+        let frame = parse_rx_object(&rx_object(0x418, &[shown, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(
+            decode(&frame) == Some(CanSignal::Gear(gear)),
+            "'{}' decoded wrong",
+            shown as char
+        );
+    }
+}
+
+#[test]
+fn speed_and_brake() {
+    // 50.0km/h, big endian.
+    let frame = parse_rx_object(&rx_object(0x200, &[0x01, 0xF4, 0, 0, 0, 0, 0, 0]));
+    assert!(decode(&frame) == Some(CanSignal::Speed(500)));
+
+    let frame = parse_rx_object(&rx_object(0x208, &[0x01, 0, 0, 0, 0, 0, 0, 0]));
+    assert!(decode(&frame) == Some(CanSignal::Brake(true)));
+
+    let frame = parse_rx_object(&rx_object(0x208, &[0x02, 0, 0, 0, 0, 0, 0, 0]));
+    assert!(decode(&frame) == Some(CanSignal::Brake(false)));
+}
+
+#[test]
+fn short_and_remote_frames() {
+    // Too short to say anything.
+    let frame = parse_rx_object(&rx_object(0x200, &[0x01]));
+    assert!(decode(&frame).is_none());
+
+    // A remote frame have no payload, whatever the DLC says.
+    let mut obj = rx_object(0x418, &[b'P', 0, 0, 0, 0, 0, 0, 0]);
+    obj[4] |= 1 << 5;
+    let frame = parse_rx_object(&obj);
+    assert_eq!(frame.len, 0);
+    assert!(decode(&frame).is_none());
+}
+
+#[test]
+fn extended_id() {
+    // 0x18DAF110, with the extended (IDE) flag. The base ID is the top 11 bits.
+    let id: u32 = 0x18DA_F110;
+    let word0 = (id >> 18) | ((id & 0x3FFFF) << 11);
+    let mut obj = rx_object(word0, &[0; 8]);
+    obj[4] |= 1 << 4;
+
+    let frame = parse_rx_object(&obj);
+    assert_eq!(frame.id, id);
+    assert!(decode(&frame).is_none());
+}
+
+// The bitrate and sample point the register gives.
+fn bit_timing(clock_hz: u32, nbtcfg: u32) -> (u32, u32) {
+    let brp = (nbtcfg >> 24) + 1;
+    let tseg1 = ((nbtcfg >> 16) & 0xFF) + 1;
+    let tseg2 = ((nbtcfg >> 8) & 0x7F) + 1;
+    let tq = 1 + tseg1 + tseg2;
+
+    (clock_hz / brp / tq, (1 + tseg1) * 100 / tq)
+}
+
+#[test]
+fn bit_timing_register() {
+    // The datasheet example, for 500kbps at 40MHz.
+    assert_eq!(nominal_bit_timing(40_000_000, 500_000), 0x003E_0F0F);
+
+    // CAN-C, the 125kbps in the README and CAN-B (83.3kbps).
+    for (bitrate, max_error) in [(500_000, 0), (125_000, 0), (83_333, 10)] {
+        let (actual, sample_point) =
+            bit_timing(40_000_000, nominal_bit_timing(40_000_000, bitrate));
+        assert!(
+            actual.abs_diff(bitrate) <= max_error,
+            "{bitrate}: got {actual}"
+        );
+        assert_eq!(sample_point, 80, "{bitrate}");
+    }
+}
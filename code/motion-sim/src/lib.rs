@@ -0,0 +1,10 @@
+#![no_std]
+
+// The very same file as the firmware uses. When it's built for tests, `std` is linked in and
+// its `f32` methods win over the `num_traits::Float` ones.
+#[cfg_attr(test, allow(unused_imports))]
+#[path = "../../src/lib_motion.rs"]
+pub mod lib_motion;
+
+// The actuator model to run it against. Only here, the firmware has no use for it.
+pub mod plant;
@@ -0,0 +1,98 @@
+use defmt::Format;
+
+use crate::lib_motion::{MotionConfig, MotionController, Step};
+
+// Plant model of the linear actuator, for tuning the controller without any hardware.
+// Rough figures for the linear actuator on 12V: ~400Ω/s at full duty, a time constant of
+// ~80ms and it doesn't move below ~15% duty. Above that, the speed goes up with the duty.
+
+const PLANT_SPEED: f32 = 400.0; // Ω/s at full duty.
+const PLANT_TAU: f32 = 0.08; // s
+const PLANT_STICTION: f32 = 0.15; // Duty.
+const PLANT_NOISE: u32 = 5; // ±Ω, the potentiometer reading noise.
+
+pub struct Plant {
+    pub position: f32,
+    velocity: f32,
+    seed: u32,
+}
+
+impl Plant {
+    pub fn new(position: u16) -> Self {
+        Self {
+            position: position as f32,
+            velocity: 0.0,
+            seed: 0x2545_f491,
+        }
+    }
+
+    // Advance the model by `dt` seconds with `duty` applied, return the "measured" position.
+    pub fn step(&mut self, duty: f32, dt: f32) -> u16 {
+        let drive = if duty.abs() < PLANT_STICTION {
+            0.0
+        } else {
+            (duty - duty.signum() * PLANT_STICTION) / (1.0 - PLANT_STICTION) * PLANT_SPEED
+        };
+        self.velocity += (drive - self.velocity) * dt / PLANT_TAU;
+        self.position += self.velocity * dt;
+
+        // Cheap, deterministic noise (xorshift).
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        let noise = (self.seed % (2 * PLANT_NOISE + 1)) as f32 - PLANT_NOISE as f32;
+
+        (self.position + noise).max(0.0) as u16
+    }
+}
+
+#[derive(Format)]
+pub struct SimResult {
+    pub settled: bool,
+    pub duration_ms: u64,
+    pub planned_ms: u64,
+    pub overshoot: f32, // Ω past the target.
+    pub error: f32,     // Ω from the target when done.
+    pub max_step: f32,  // Largest duty change in one period.
+}
+
+// Run one move against the plant model.
+pub fn simulate(config: MotionConfig, start: u16, target: u16) -> SimResult {
+    let dt = config.period_ms as f32 / 1_000.0;
+    let direction = if target >= start { 1.0 } else { -1.0 };
+
+    let mut plant = Plant::new(start);
+    let mut controller = MotionController::new(config, start, target);
+    let mut measured = start;
+    let mut duty = 0.0;
+    let mut overshoot: f32 = 0.0;
+    let mut max_step: f32 = 0.0;
+    let mut elapsed: u64 = 0;
+    let mut settled = false;
+
+    while elapsed < controller.timeout_ms() {
+        match controller.step(measured) {
+            Step::Drive(d) => {
+                max_step = max_step.max((d - duty).abs());
+                duty = d;
+            }
+            Step::Done => {
+                settled = true;
+                break;
+            }
+        }
+
+        measured = plant.step(duty, dt);
+        overshoot = overshoot.max((plant.position - target as f32) * direction);
+        elapsed += config.period_ms;
+    }
+
+    SimResult {
+        settled,
+        duration_ms: elapsed,
+        planned_ms: controller.planned_ms(),
+        overshoot,
+        error: (plant.position - target as f32).abs(),
+        max_step,
+    }
+}
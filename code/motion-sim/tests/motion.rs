@@ -0,0 +1,74 @@
+// Run the motion controller against the plant model, and check that it gets there in time,
+// without overshooting and without any hard duty changes.
+
+use motion_sim::lib_motion::DEFAULT_MOTION;
+use motion_sim::plant::{simulate, SimResult};
+
+// Roughly the linear actuator (see the `actuator` crate), the plant model isn't more exact
+// than that anyway.
+const THROW_MIN: u16 = 100;
+const THROW_MAX: u16 = 1_100;
+const THROW_1MM: u16 = 10;
+
+// How much worse than the plan a move can be. The potentiometer noise is ±5Ω, the settle time
+// (10 periods inside the deadband) is 50ms of that.
+const MAX_LATE_MS: u64 = 250;
+const MAX_OVERSHOOT: f32 = 10.0;
+const MAX_ERROR: f32 = DEFAULT_MOTION.deadband as f32;
+
+fn check(start: u16, target: u16) -> SimResult {
+    let result = simulate(DEFAULT_MOTION, start, target);
+    let dt = DEFAULT_MOTION.period_ms as f32 / 1_000.0;
+
+    assert!(result.settled, "{start} => {target}: didn't settle");
+    assert!(
+        result.duration_ms <= result.planned_ms + MAX_LATE_MS,
+        "{start} => {target}: took {}ms, planned {}ms",
+        result.duration_ms,
+        result.planned_ms
+    );
+    assert!(
+        result.overshoot <= MAX_OVERSHOOT,
+        "{start} => {target}: overshoot {}Ω",
+        result.overshoot
+    );
+    assert!(
+        result.error <= MAX_ERROR,
+        "{start} => {target}: {}Ω from the target",
+        result.error
+    );
+    assert!(
+        result.max_step <= DEFAULT_MOTION.slew * dt + f32::EPSILON,
+        "{start} => {target}: duty step {}",
+        result.max_step
+    );
+
+    result
+}
+
+#[test]
+fn full_throw() {
+    check(THROW_MIN, THROW_MAX);
+    check(THROW_MAX, THROW_MIN);
+}
+
+#[test]
+fn gear_to_gear() {
+    check(THROW_MIN, THROW_MIN + THROW_1MM * 30);
+    check(THROW_MIN + THROW_1MM * 30, THROW_MIN);
+}
+
+// The calibration jogs.
+#[test]
+fn short_moves() {
+    check(THROW_MIN + THROW_1MM * 10, THROW_MIN + THROW_1MM * 15);
+    check(THROW_MIN + THROW_1MM * 10, THROW_MIN + THROW_1MM * 11);
+    check(THROW_MIN + THROW_1MM * 11, THROW_MIN + THROW_1MM * 10);
+}
+
+// A drift correction, just outside the deadband.
+#[test]
+fn tiny_correction() {
+    check(THROW_MIN + THROW_1MM * 10, THROW_MIN + THROW_1MM * 10 + 20);
+    check(THROW_MIN + THROW_1MM * 10 + 20, THROW_MIN + THROW_1MM * 10);
+}
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+
+//! Learn the actuator position of each gear, and store them in the flash.
+//! Press P (it blinks orange) to start, nothing moves before that. The actuator is then
+//! jogged from one end to the other. When the lever clicks into a gear, press the matching
+//! button (P, R, N and D, in that order). Any other button moves the actuator back 1mm, in
+//! case it went past the detent, and holds it there for a while.
+
+use defmt::{error, info, unwrap};
+use {defmt_serial as _, panic_probe as _};
+
+use embassy_executor::Spawner;
+use embassy_rp::{
+    adc::InterruptHandler as ADCInterruptHandler,
+    bind_interrupts,
+    gpio::{AnyPin, Level},
+    peripherals::{PIO0, UART1},
+    pio::{InterruptHandler as PIOInterruptHandler, Pio},
+    uart::{Blocking, Config as UartConfig, InterruptHandler as UARTInterruptHandler, UartTx},
+    Peri,
+};
+use embassy_time::{Duration, Timer};
+
+use static_cell::StaticCell;
+
+use ws2812::Ws2812;
+
+// External "defines". All because we need the `Button` define!!
+pub mod lib_actuator;
+pub mod lib_buttons;
+pub mod lib_calibration;
+pub mod lib_can_bus;
+pub mod lib_can_frame;
+pub mod lib_config;
+pub mod lib_dc_actuator;
+pub mod lib_eventlog;
+pub mod lib_expander;
+pub mod lib_gear;
+pub mod lib_gear_actuator;
+pub mod lib_i2c;
+pub mod lib_leds;
+pub mod lib_linear_actuator;
+pub mod lib_motion;
+pub mod lib_motor_current;
+pub mod lib_pot;
+pub mod lib_resources;
+pub mod lib_status;
+pub mod lib_stepper_actuator;
+pub mod lib_ups;
+pub mod lib_watchdog;
+
+use crate::lib_actuator::move_to_gear;
+use crate::lib_buttons::{
+    set_led, Button, ButtonInput, Debouncer, CHANNEL_D, CHANNEL_N, CHANNEL_P, CHANNEL_R,
+};
+use crate::lib_calibration::{calibrate, CHANNEL_CALIBRATION};
+use crate::lib_config::{init_flash, resonable_defaults, write_flash, DbwConfig};
+use crate::lib_expander::{expander, expander_in_use};
+use crate::lib_gear_actuator::Actuator;
+use crate::lib_i2c::init_i2c;
+use crate::lib_leds::{led_animator, show_gear};
+use crate::lib_resources::*;
+
+bind_interrupts!(struct Irqs {
+    PIO0_IRQ_0   => PIOInterruptHandler<PIO0>;		// NeoPixel
+    UART1_IRQ    => UARTInterruptHandler<UART1>;	// Serial logging
+    ADC_IRQ_FIFO => ADCInterruptHandler;		// Actuator potentiometer
+});
+
+// Pass on every button press to the calibration.
+#[embassy_executor::task(pool_size = 4)]
+async fn read_press(button: Button, btn_pin: Peri<'static, AnyPin>) {
+    let mut btn = Debouncer::new(
+        ButtonInput::new(btn_pin, button),
+        Duration::from_millis(100),
+    );
+
+    loop {
+        if btn.debounce().await == Level::Low {
+            CHANNEL_CALIBRATION.send(button).await;
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let r = split_resources! {p};
+
+    // Initialize the serial UART for debug/log output.
+    let uart = UartTx::new(
+        r.serial.uart,
+        r.serial.tx,
+        r.serial.dma,
+        UartConfig::default(),
+    ); // => 115200/8N1 (UART1)
+    static SERIAL: StaticCell<UartTx<'_, Blocking>> = StaticCell::new();
+    defmt_serial::defmt_serial(SERIAL.init(uart));
+
+    info!("Start");
+
+    // Initialize the NeoPixel and the button LEDs, the calibration shows its progress on them.
+    let Pio {
+        mut common, sm0, ..
+    } = Pio::new(r.neopixel.pio, Irqs);
+    let neopixel = Ws2812::new(&mut common, sm0, r.neopixel.dma, r.neopixel.pin);
+    spawner.spawn(unwrap!(led_animator(neopixel)));
+
+    // Some of the buttons and LEDs might be on the GPIO expander.
+    if expander_in_use() {
+        let i2c_bus = init_i2c(r.i2c);
+        spawner.spawn(unwrap!(expander(i2c_bus, r.expander)));
+    }
+
+    #[cfg_attr(any(), rustfmt::skip)]
+    {
+        spawner.spawn(unwrap!(set_led(CHANNEL_P.receiver(), r.buttons.p_led.into(), Button::P)));
+        spawner.spawn(unwrap!(set_led(CHANNEL_R.receiver(), r.buttons.r_led.into(), Button::R)));
+        spawner.spawn(unwrap!(set_led(CHANNEL_N.receiver(), r.buttons.n_led.into(), Button::N)));
+        spawner.spawn(unwrap!(set_led(CHANNEL_D.receiver(), r.buttons.d_led.into(), Button::D)));
+
+        spawner.spawn(unwrap!(read_press(Button::P, r.buttons.p_but.into())));
+        spawner.spawn(unwrap!(read_press(Button::R, r.buttons.r_but.into())));
+        spawner.spawn(unwrap!(read_press(Button::N, r.buttons.n_but.into())));
+        spawner.spawn(unwrap!(read_press(Button::D, r.buttons.d_but.into())));
+    }
+
+    // Initialize the actuator.
+    let mut actuator = Actuator::new(r.actuator, Irqs, spawner.make_send());
+
+    // Instantiate the flash.
+    let flash = init_flash(r.flash);
+
+    match calibrate(&mut actuator).await {
+        Some(calibration) => {
+            let mut flash = flash.lock().await;
+            let mut config = match DbwConfig::read(&mut flash) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Failed to read flash: {:?}", e);
+                    resonable_defaults()
+                }
+            };
+            config.calibration = calibration;
+            config.active_button = Button::P;
+
+            write_flash(&mut flash, config).await;
+
+            // Leave the actuator in (P)ark, where the main app expects it to be.
+            move_to_gear(&mut actuator, &calibration, Button::P).await;
+            show_gear(Button::P).await;
+            info!("Calibration stored in flash");
+        }
+        None => error!("Calibration failed, flash not changed"),
+    }
+
+    loop {
+        Timer::after_secs(600).await;
+    }
+}
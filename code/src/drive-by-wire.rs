@@ -1,13 +1,15 @@
 #![no_std]
 #![no_main]
 
-use defmt::{debug, error, info, unwrap};
+use defmt::{error, info, unwrap, warn};
 
-use embassy_executor::{Executor, Spawner};
+use embassy_executor::{Executor, InterruptExecutor, Spawner};
 use embassy_rp::{
     adc::InterruptHandler as ADCInterruptHandler,
     bind_interrupts,
     gpio::{Level, Output},
+    interrupt,
+    interrupt::{InterruptExt, Priority},
     multicore::{spawn_core1, Stack},
     peripherals::{PIO0, UART0, UART1},
     pio::{InterruptHandler as PIOInterruptHandler, Pio},
@@ -18,35 +20,62 @@ use embassy_time::Timer;
 
 use static_cell::StaticCell;
 
-use actuator::Actuator;
 use r503::R503;
-use ws2812::{Colour, Ws2812};
+use ws2812::Ws2812;
 
-use {defmt_serial as _, panic_probe as _};
+use defmt_serial as _;
+
+use core::panic::PanicInfo;
 
 // External "defines".
 pub mod lib_actuator;
 pub mod lib_buttons;
+pub mod lib_calibration;
 pub mod lib_can_bus;
+pub mod lib_can_frame;
 pub mod lib_config;
 pub mod lib_core1;
+pub mod lib_dc_actuator;
+pub mod lib_eventlog;
+pub mod lib_expander;
+pub mod lib_gear;
+pub mod lib_gear_actuator;
+pub mod lib_i2c;
+pub mod lib_leds;
+pub mod lib_linear_actuator;
+pub mod lib_motion;
+pub mod lib_motor_current;
+pub mod lib_pot;
 pub mod lib_resources;
+pub mod lib_shutdown;
+pub mod lib_status;
+pub mod lib_stepper_actuator;
 pub mod lib_ups;
 pub mod lib_watchdog;
 
-use crate::lib_actuator::{actuator_control, CHANNEL_ACTUATOR};
-use crate::lib_buttons::{
-    read_button, Button, LedStatus, ScannerMutex, BUTTON_ENABLED, CHANNEL_D, CHANNEL_N, CHANNEL_P,
-    CHANNEL_R,
+use crate::lib_actuator::{
+    actuator_busy, actuator_control, boot_gear, limp_or_reset, request_gear, self_test, SelfTest,
 };
+use crate::lib_buttons::{lock_scanner, read_button, Button, ScannerMutex, BUTTON_ENABLED};
 use crate::lib_can_bus::{CANMessage, CHANNEL_CANWRITE};
-use crate::lib_config::{init_flash, DbwConfig};
+use crate::lib_config::{config_writer, init_flash, set_flash_hold, DbwConfig};
 use crate::lib_core1::core1_tasks;
+use crate::lib_eventlog::{event_logger, log_event, EventKind};
+use crate::lib_expander::{expander, expander_in_use};
+use crate::lib_gear_actuator::Actuator;
+use crate::lib_i2c::{device_present, init_i2c, scan_i2c, BusDevice};
+use crate::lib_leds::{attach_scanner, led_animator, show_gear};
+use crate::lib_motor_current::motor_current_monitor;
 use crate::lib_resources::{
-    AssignedResources, PeriActuator, PeriBuiltin, PeriButtons, PeriCan, PeriEis, PeriFPScanner,
-    PeriFlash, PeriNeopixel, PeriPowerMonitor, PeriSerial, PeriWatchdog,
+    AssignedResources, PeriActuator, PeriBuiltin, PeriButtons, PeriCan, PeriEis, PeriExpander,
+    PeriFPScanner, PeriFlash, PeriI2c, PeriNeopixel, PeriSerial, PeriWatchdog,
+};
+use crate::lib_shutdown::{shutdown_manager, EisRelays};
+use crate::lib_status::{
+    raise_fault, set_state, status_indicator, FaultCode, LimpReason, SystemState,
 };
-use crate::lib_watchdog::{StopWatchdog, CHANNEL_WATCHDOG};
+use crate::lib_ups::take_supply_capture;
+use crate::lib_watchdog::{record_panic, set_boot_stage, take_breadcrumbs, BootStage};
 
 // DMA Channels used (of 12):
 // * Fingerprint scanner:	UART0	DMA_CH[0-1]	PIN_13, PIN_16, PIN_17
@@ -63,6 +92,26 @@ bind_interrupts!(struct Irqs {
 static mut CORE1_STACK: Stack<4096> = Stack::new();
 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
+// The actuator control (and the pot sampling) runs here, pre-empting everything else on CORE0,
+// so the LEDs, the flash writes etc can't delay a stop.
+static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    EXECUTOR_HIGH.on_interrupt()
+}
+
+// Instead of `panic_probe`. Leave a note for the next boot, and let the watchdog reset us.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    record_panic(info);
+    error!("{}", defmt::Display2Format(info));
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
 // ================================================================================
 
 #[embassy_executor::main]
@@ -70,8 +119,12 @@ async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let r = split_resources! {p};
 
+    // What the last run left behind, before we start leaving our own.
+    let breadcrumbs = take_breadcrumbs();
+
     // =====
     //  1. Initialize the serial UART for debug/log output.
+    set_boot_stage(BootStage::Logging);
     let uart = UartTx::new(
         r.serial.uart,
         r.serial.tx,
@@ -95,15 +148,41 @@ async fn main(spawner: Spawner) {
 
     // =====
     //  3. Initialize the NeoPixel LED. Do this first, so we can turn on the status LED.
+    set_boot_stage(BootStage::Leds);
     let Pio {
         mut common, sm0, ..
     } = Pio::new(r.neopixel.pio, Irqs);
-    let mut neopixel = Ws2812::new(&mut common, sm0, r.neopixel.dma, r.neopixel.pin);
+    let neopixel = Ws2812::new(&mut common, sm0, r.neopixel.dma, r.neopixel.pin);
     info!("NeoPixel LED initialized");
-    neopixel.set_colour(Colour::ORANGE).await;
+
+    // The animation task owns the NeoPixel from now on, everyone else posts patterns to it.
+    // The status LED follows the system state, which starts out as `BootStarted` (RED).
+    spawner.spawn(unwrap!(led_animator(neopixel)));
+    spawner.spawn(unwrap!(status_indicator()));
 
     // =====
-    //  4. Spawn off tasks on CORE1.
+    //  4. Initialize the I²C bus, and see who's there.
+    set_boot_stage(BootStage::I2c);
+    let i2c_bus = init_i2c(r.i2c);
+    scan_i2c(i2c_bus).await;
+
+    //     Some of the buttons and LEDs might be on the GPIO expander. If it's not there, keep
+    //     trying - the task sets it up as soon as it answers.
+    if expander_in_use() {
+        if !device_present(BusDevice::Expander) {
+            error!("Buttons or LEDs on the GPIO expander, but it's not responding");
+        }
+        spawner.spawn(unwrap!(expander(i2c_bus, r.expander)));
+    }
+
+    //     The actuator motor current is measured on the bus too. Without it, only the stall
+    //     detection protects the motor.
+    if !device_present(BusDevice::MotorCurrent) {
+        warn!("No motor current monitor, the actuator is only protected against a stall");
+    }
+    spawner.spawn(unwrap!(motor_current_monitor(i2c_bus)));
+
+    //     Spawn off tasks on CORE1.
     //     * Watchdog.
     //     * CAN reader.
     //     * CAN writer.
@@ -114,7 +193,7 @@ async fn main(spawner: Spawner) {
         move || {
             let executor = EXECUTOR.init(Executor::new());
             executor.run(|spawner| {
-                spawner.spawn(unwrap!(core1_tasks(spawner, r.watchdog, r.ups, r.can)))
+                spawner.spawn(unwrap!(core1_tasks(spawner, r.watchdog, i2c_bus, r.can)))
             });
         },
     );
@@ -123,6 +202,7 @@ async fn main(spawner: Spawner) {
 
     // =====
     //  5. Initialize the MOSFET relays.
+    set_boot_stage(BootStage::Relays);
     let mut eis_lock = Output::new(r.eis.lock, Level::Low); // EIS/Steering lock (GREEN)
     let mut eis_start = Output::new(r.eis.start, Level::Low); // EIS/Start (YELLOW)
     info!("EIS relays initialized");
@@ -130,6 +210,7 @@ async fn main(spawner: Spawner) {
 
     // =====
     //  6. Initialize the flash drive where we store the state across reboots.
+    set_boot_stage(BootStage::Flash);
     info!("Initializing the flash drive");
     let flash = init_flash(r.flash);
 
@@ -141,40 +222,93 @@ async fn main(spawner: Spawner) {
     };
     info!("{:?}", config);
 
+    // Start recording events, and writing the config changes. Neither while the actuator moves.
+    set_flash_hold(actuator_busy);
+    spawner.spawn(unwrap!(event_logger(flash)));
+    spawner.spawn(unwrap!(config_writer(flash)));
+    log_event(EventKind::Boot, config.active_button, 0);
+
+    // Why we (re)started, and how far the last boot got. If it was in the middle of an
+    // actuator move, also what the supply did up to then - that never made it to the event log.
+    let button = config.active_button;
+    info!("Reset reason: {}", breadcrumbs.reason);
+    log_event(EventKind::ResetReason, button, breadcrumbs.reason as u16);
+    if let Some(stage) = breadcrumbs.stage {
+        info!("Last boot stage: {}", stage);
+        log_event(EventKind::BootStage, button, stage as u16);
+    }
+    if let Some(task) = breadcrumbs.hung {
+        error!("The watchdog reset us, the {} task was hung", task.name());
+        log_event(EventKind::TaskHung, button, task as u16);
+    }
+    if let Some(panic) = breadcrumbs.panic {
+        error!(
+            "Panicked at {}:{}:{}: {}",
+            panic.file(),
+            panic.line,
+            panic.column,
+            panic.message()
+        );
+        log_event(EventKind::Panic, button, panic.line as u16);
+    }
+    if breadcrumbs.unexpected() {
+        CHANNEL_CANWRITE.send(CANMessage::Restarted).await;
+    }
+    if let Some((capture, true)) = take_supply_capture() {
+        warn!("Reset during an actuator move, supply: {:?}", capture);
+        capture.log_events(config.active_button);
+    }
+
     // =====
     //  7a. Initialize and test the actuator.
+    set_boot_stage(BootStage::Actuator);
     info!("Initializing actuator");
     CHANNEL_CANWRITE.send(CANMessage::InitActuator).await;
-    let mut actuator = Actuator::new(
-        r.actuator.mplus.into(),  // pin_motor_plus
-        r.actuator.mminus.into(), // pin_motor_minus
-        r.actuator.vsel.into(),   // pin_volt_select - UART0
-        r.actuator.pot,           // pin_pot         - ADC2
-        r.actuator.adc,
-        Irqs,
-    );
+    interrupt::SWI_IRQ_1.set_priority(Priority::P2);
+    let spawner_high = EXECUTOR_HIGH.start(interrupt::SWI_IRQ_1);
+    let mut actuator = Actuator::new(r.actuator, Irqs, spawner_high);
+
+    // 7b. Test actuator control, once the car is stopped with the brake pressed, or in (P)ark.
+    //     Only the CAN-bus can say, without it the test is skipped.
+    match self_test(&mut actuator).await {
+        SelfTest::Passed => info!("Actuator self-test passed"),
+        SelfTest::Skipped => warn!("Actuator self-test skipped"),
+        SelfTest::Failed => {
+            // ERROR: Actuator have not moved. Resetting would just make the car undrivable,
+            // unless the policy says so keep going in limp-home mode.
+            error!("Actuator failed to move");
+            CHANNEL_CANWRITE.send(CANMessage::ActuatorTestFailed).await;
+            raise_fault(FaultCode::ActuatorTest);
+            limp_or_reset(
+                config.failure_policy,
+                LimpReason::Actuator,
+                config.active_button,
+                0,
+            )
+            .await;
+        }
+    }
 
-    // 7b. Test actuator control.
-    if !actuator.test_actuator().await {
-        // ERROR: Actuator have not moved.
-        error!("Actuator failed to move - resetting");
-        CHANNEL_CANWRITE.send(CANMessage::ActuatorTestFailed).await;
+    // 7c. Find out what gear we're really in, and where to go from there.
+    let boot = boot_gear(&mut actuator, &config).await;
+    info!("Boot gear ({}): {:?}", config.boot_gear, boot);
 
-        // Stop feeding the watchdog, resulting in a reset.
-        CHANNEL_WATCHDOG.send(StopWatchdog::Yes).await;
-    }
+    //     Set the button (gear) we're actually in before the actuator control starts, so its
+    //     idle supervision and a failed move both go back to that, and not to the default.
+    info!("Setting enabled button to {}", boot.current);
+    unsafe { BUTTON_ENABLED = boot.current };
 
-    // Actuator works. Spawn off the actuator control task.
-    spawner.spawn(unwrap!(actuator_control(
-        CHANNEL_ACTUATOR.receiver(),
-        &flash,
-        actuator
-    )));
+    // Spawn off the actuator control task, on the high priority executor.
+    spawner_high.spawn(unwrap!(actuator_control(&flash, actuator)));
     info!("Actuator controller running");
     CHANNEL_CANWRITE.send(CANMessage::ActuatorInitialized).await;
 
+    // 8. Bootup done, login started.
+    set_state(SystemState::BootDone);
+
     // =====
     // 9a. Initialize the fingerprint scanner.
+    set_boot_stage(BootStage::Login);
     info!("Initializing the fingerprint scanner");
     CHANNEL_CANWRITE.send(CANMessage::InitFP).await;
     let fp_scanner = R503::new(
@@ -188,6 +322,7 @@ async fn main(spawner: Spawner) {
     );
     static FP_SCANNER: StaticCell<ScannerMutex> = StaticCell::new();
     let fp_scanner = FP_SCANNER.init(Mutex::new(fp_scanner));
+    attach_scanner(fp_scanner);
     info!("Fingerprint scanner initialized");
     CHANNEL_CANWRITE.send(CANMessage::FPInitialized).await;
 
@@ -195,23 +330,22 @@ async fn main(spawner: Spawner) {
     info!("Authorizing use");
     CHANNEL_CANWRITE.send(CANMessage::Authorizing).await;
     if config.valet_mode {
-        neopixel.set_colour(Colour::ORANGE).await;
+        set_state(SystemState::Valet);
 
         info!("Running in VALET mode, won't authorize");
         CHANNEL_CANWRITE.send(CANMessage::ValetMode).await;
     } else {
         // Loop until we get a successful fingerprint match.
         loop {
-            neopixel.set_colour(Colour::BLUE).await;
+            set_state(SystemState::BootDone);
 
             {
                 // The fp_scanner lock is released when it goes out of scope.
-                let mut fp_scanner = fp_scanner.lock().await;
+                let mut fp_scanner = lock_scanner(fp_scanner).await;
                 if !fp_scanner.Wrapper_Verify_Fingerprint().await {
                     error!("Can't match fingerprint - retrying");
 
-                    debug!("NeoPixel RED");
-                    neopixel.set_colour(Colour::RED).await;
+                    set_state(SystemState::LoginFailed);
 
                     // Give it five seconds before we retry.
                     Timer::after_secs(5).await;
@@ -224,7 +358,7 @@ async fn main(spawner: Spawner) {
             }
         }
 
-        neopixel.set_colour(Colour::GREEN).await;
+        set_state(SystemState::LoginDone);
         info!("Use authorized");
         CHANNEL_CANWRITE.send(CANMessage::Authorized).await;
     }
@@ -232,6 +366,7 @@ async fn main(spawner: Spawner) {
     // =====
     // 10. Spawn off one button reader per button. They will then spawn off a LED controller each
     //     so thateach button can control their "own" LED.
+    set_boot_stage(BootStage::Buttons);
     info!("Initializing drive buttons");
     spawner.spawn(unwrap!(read_button(
         spawner,
@@ -269,57 +404,23 @@ async fn main(spawner: Spawner) {
     CHANNEL_CANWRITE.send(CANMessage::ButtonsInitialized).await;
 
     // =====
-    // 11. Read what button (gear) was enabled when last it changed from the flash.
-    match config.active_button {
-        Button::P => {
-            info!("Setting enabled button to P");
-            CHANNEL_P.send(LedStatus::On).await;
-            CHANNEL_R.send(LedStatus::Off).await;
-            CHANNEL_N.send(LedStatus::Off).await;
-            CHANNEL_D.send(LedStatus::Off).await;
-
-            unsafe { BUTTON_ENABLED = Button::P };
-        }
-        Button::R => {
-            info!("Setting enabled button to R");
-            CHANNEL_P.send(LedStatus::Off).await;
-            CHANNEL_R.send(LedStatus::On).await;
-            CHANNEL_N.send(LedStatus::Off).await;
-            CHANNEL_D.send(LedStatus::Off).await;
-
-            unsafe { BUTTON_ENABLED = Button::R };
-        }
-        Button::N => {
-            info!("Setting enabled button to N");
-            CHANNEL_P.send(LedStatus::Off).await;
-            CHANNEL_R.send(LedStatus::Off).await;
-            CHANNEL_N.send(LedStatus::On).await;
-            CHANNEL_D.send(LedStatus::Off).await;
-
-            unsafe { BUTTON_ENABLED = Button::N };
-        }
-        Button::D => {
-            info!("Setting enabled button to D");
-            CHANNEL_P.send(LedStatus::Off).await;
-            CHANNEL_R.send(LedStatus::Off).await;
-            CHANNEL_N.send(LedStatus::Off).await;
-            CHANNEL_D.send(LedStatus::On).await;
-
-            unsafe { BUTTON_ENABLED = Button::D };
-        }
-    }
+    // 11. Show the gear we're going to.
+    show_gear(boot.target).await;
 
-    // 12. Move the gear into the position it was last saved as.
-    info!("Changing gear to {}", config.active_button);
-    CHANNEL_ACTUATOR.send(config.active_button).await;
+    // 12. Move the gear into the position the boot gear policy says.
+    set_boot_stage(BootStage::BootGear);
+    info!("Changing gear to {}", boot.target);
+    request_gear(boot.target);
 
     // =====
     // 13. Turn on the ignition switch.
+    set_boot_stage(BootStage::Ignition);
     eis_lock.set_high();
     info!("Turning on the EIS");
 
     // =====
     // 14. Starting the car by turning on the EIS/start relay on for one sec and then turn it off.
+    set_boot_stage(BootStage::StartCar);
     if !config.valet_mode {
         // Sleep here three seconds to allow the car to "catch up".
         // Sometime, it takes a while for the car to "wake up". Not sure why..
@@ -334,7 +435,20 @@ async fn main(spawner: Spawner) {
     }
 
     // =====
-    // 15. If we exit here, the EIS lock will turn off, so loop forever.
+    // 15. Hand the relays over to the shutdown manager, it releases them when we've been on
+    //     the UPS battery for long enough.
+    spawner.spawn(unwrap!(shutdown_manager(
+        flash,
+        EisRelays {
+            lock: eis_lock,
+            start: eis_start,
+        },
+        config.shutdown_hold_secs,
+        config.park_on_shutdown,
+        config.shutdown_without_can
+    )));
+
+    set_boot_stage(BootStage::Running);
     info!("Main function complete, control handed over to subtasks.");
     loop {
         // Nothing to do, just sleep as long as we can, but 10 minutes should do it, then just loop.
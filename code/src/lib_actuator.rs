@@ -1,65 +1,692 @@
-use defmt::{error, info};
+use defmt::{debug, error, info, warn, Format};
 
-use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex,
-    channel::{Channel, Receiver},
-};
+use core::future::pending;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
 
 // External "defines".
-use crate::lib_buttons::{Button, BUTTONS_BLOCKED, BUTTON_ENABLED};
-use crate::lib_config::{resonable_defaults, write_flash, DbwConfig, FlashMutex};
+use crate::lib_buttons::{unblock_buttons, Button, BUTTONS_BLOCKED, BUTTON_ENABLED};
+use crate::lib_calibration::{calibrate, CALIBRATING, SIGNAL_CALIBRATE};
+use crate::lib_can_bus::{
+    can_alive, can_lost, engaged_gear, vehicle_state, CANMessage, CHANNEL_CANWRITE,
+};
+use crate::lib_config::{
+    resonable_defaults, BootGear, Calibration, ConfigUpdate, DbwConfig, FailurePolicy, FlashMutex,
+    CHANNEL_CONFIG,
+};
+use crate::lib_eventlog::{log_event, EventKind};
+use crate::lib_gear_actuator::{
+    moving, preempted, uncalibrated, Actuator, Blind, GearActuator, MoveError, SIGNAL_PREEMPT,
+};
+use crate::lib_leds::{show_buttons, show_gear, ACTUATOR_FAILED};
+use crate::lib_status::{
+    enter_limp_home, fault_active, limp_home, limp_reason, raise_fault, request_reset, set_state,
+    FaultCode, LimpReason, SystemState,
+};
+use crate::lib_ups::{start_supply_capture, stop_supply_capture};
+use crate::lib_watchdog::{check_in, register, unregister, Task};
+
+// The gear the driver wants. Only the latest request counts, if several come in while the
+// actuator is busy it goes straight for the last one.
+pub static SIGNAL_GEAR: Signal<CriticalSectionRawMutex, Button> = Signal::new();
+
+// The gear the actuator is moving to, if it's moving.
+pub static mut ACTUATOR_TARGET: Option<Button> = None;
+
+// How long to wait for the transmission to engage the gear, after the actuator have moved.
+const ENGAGE_TIMEOUT_MS: u64 = 1_500;
+
+// How many times to try the move again, if the actuator or the transmission failed.
+const GEAR_RETRIES: u8 = 2;
+
+// How many times to try to go back to the last gear, before giving up on the actuator.
+const RECOVERY_RETRIES: u8 = 2;
+
+// Let the motor (and the driver) cool down a little between the attempts.
+const RETRY_PAUSE_MS: u64 = 500;
+
+// How many times to run the boot self-test, before giving up on the actuator.
+const SELF_TEST_ATTEMPTS: u8 = 3;
+
+// Without a CAN-bus, there's no knowing if it's safe to run the self-test. Give the CAN-bus
+// this long to come up, then skip it.
+const SELF_TEST_WAIT_NO_CAN_MS: u64 = 10_000;
+
+// How long to wait at boot for the transmission to tell us what gear it's in.
+const BOOT_GEAR_WAIT_MS: u64 = 1_000;
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum SelfTest {
+    Passed,
+    Skipped,
+    Failed,
+}
+
+// How often to check that the actuator stays where we left it, between the gear changes.
+const IDLE_CHECK_MS: u64 = 1_000;
+
+// Give up correcting the drift if it doesn't stay put after this many tries.
+const MAX_CORRECTIONS: u8 = 3;
+
+// How a gear change went.
+#[derive(Copy, Clone, PartialEq)]
+enum GearChange {
+    Done,
+    Failed,
+    Preempted,
+}
+
+// What the idle supervision have seen since the last gear change.
+#[derive(Copy, Clone, PartialEq)]
+enum Idle {
+    InPlace,
+    Drifting(u8), // Number of corrections done.
+    Moved,
+}
+
+// Ask for a gear. (P)ark and (N)eutral are the safe gears, they don't wait for a move to
+// another gear to finish. That move is stopped, and the actuator goes there instead.
+pub fn request_gear(button: Button) {
+    SIGNAL_GEAR.signal(button);
+
+    if let Some(target) = unsafe { ACTUATOR_TARGET } {
+        if target != button && matches!(button, Button::P | Button::N) {
+            info!(
+                "{} requested while moving to {}, pre-empting",
+                button, target
+            );
+            SIGNAL_PREEMPT.signal(());
+        }
+    }
+}
+
+// Erasing or writing the flash stops the whole chip while it's at it, both cores and the
+// interrupts included - the code runs from the flash. Leave it until the actuator is done,
+// the gear change included. A move that starts while the flash is busy is only held up
+// until it's done, it's stopping one that's already under way that's dangerous.
+// Handed to `set_flash_hold` by the firmware.
+pub fn actuator_busy() -> bool {
+    unsafe { ACTUATOR_TARGET.is_some() }
+    || moving()
+}
+
+// Move the actuator to the gear, and check that it ended up inside the gear window.
+pub async fn move_to_gear(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    button: Button,
+) -> bool {
+    let target = calibration.position(button);
+    debug!("Moving actuator to {} ({})", button, target);
+    let move_id = start_supply_capture();
+    let result = actuator.move_to_gear(calibration, button).await;
+    stop_supply_capture(move_id);
+    match result {
+        Ok(_) => {}
+        // Not a failure, there's somewhere more important to go.
+        Err(MoveError::Preempted { .. }) => return false,
+        Err(e) => {
+            error!("Actuator failed to move to {}: {:?}", button, e);
+            raise_fault(match e {
+                MoveError::Stall { .. } => FaultCode::ActuatorStall,
+                MoveError::OverCurrent { .. } => FaultCode::ActuatorOverCurrent,
+                _ => FaultCode::ActuatorMove,
+            });
+            return false;
+        }
+    }
+
+    let position = actuator.read_position().await;
+    if !calibration.in_window(button, position) {
+        error!(
+            "Actuator at {}, outside the {} window ({} ±{})",
+            position, button, target, calibration.tolerance
+        );
+        raise_fault(FaultCode::ActuatorMove);
+        return false;
+    }
+
+    true
+}
+
+// Wait for the transmission to report, on the CAN-bus, that it have engaged the gear.
+// Without a CAN-bus, all we have is the actuator position, so take that.
+async fn gear_engaged(button: Button) -> bool {
+    if !can_alive() {
+        warn!(
+            "No transmission status on the CAN-bus, can't verify {}",
+            button
+        );
+        return true;
+    }
+
+    let started = Instant::now();
+    loop {
+        check_in(Task::Actuator);
+
+        let engaged = engaged_gear();
+        if engaged == Some(button) {
+            debug!(
+                "Transmission engaged {} after {}ms",
+                button,
+                started.elapsed().as_millis()
+            );
+            return true;
+        }
+
+        // Don't hold up the next gear, it doesn't matter if this one engaged.
+        if preempted() {
+            return false;
+        }
+
+        if started.elapsed().as_millis() > ENGAGE_TIMEOUT_MS {
+            error!(
+                "Transmission didn't engage {} (reports {:?})",
+                button, engaged
+            );
+            return false;
+        }
+
+        Timer::after_millis(20).await;
+    }
+}
+
+// Move to the gear and make sure the transmission follows. Retry the move if either of
+// them fails. Give up on it, without retrying, if it's pre-empted.
+async fn change_gear(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    button: Button,
+) -> GearChange {
+    let mut mismatch = false;
+    for attempt in 0..=GEAR_RETRIES {
+        // The retries (and the recovery) can take a lot longer than the watchdog allows, so
+        // check in once for every move.
+        check_in(Task::Actuator);
+        if preempted() {
+            return GearChange::Preempted;
+        }
+        if attempt > 0 {
+            warn!("Moving to {} again, attempt {}", button, attempt + 1);
+            Timer::after_millis(RETRY_PAUSE_MS).await;
+        }
+
+        mismatch = false;
+        if !move_to_gear(actuator, calibration, button).await {
+            continue;
+        }
+        if gear_engaged(button).await {
+            return GearChange::Done;
+        }
+        mismatch = true;
+    }
+
+    if preempted() {
+        return GearChange::Preempted;
+    }
+    if mismatch {
+        raise_fault(FaultCode::GearMismatch);
+    }
+    GearChange::Failed
+}
+
+// The actuator (or the CAN-bus) can't be trusted any more. Depending on the policy, either
+// limp home with only (P)ark and (N)eutral, or reset and hope it comes back.
+pub async fn limp_or_reset(
+    policy: FailurePolicy,
+    reason: LimpReason,
+    button: Button,
+    position: u16,
+) {
+    match policy {
+        FailurePolicy::LimpHome => {
+            error!("{} failed - limp home", reason);
+            enter_limp_home(reason);
+            raise_fault(FaultCode::LimpHome);
+            log_event(EventKind::LimpHome, button, position);
+            CHANNEL_CANWRITE.send(CANMessage::LimpHome).await;
+        }
+        FailurePolicy::Reset => {
+            error!("{} failed - resetting", reason);
+            show_buttons(ACTUATOR_FAILED).await;
+            set_state(SystemState::Fatal);
+            request_reset();
+
+            // Nothing more to do, wait for the watchdog.
+            pending::<()>().await;
+        }
+    }
+}
+
+// The actuator position can't be trusted, so move it open loop. (P)ark is at the retracted
+// end, so get there by running into the end stop. (N)eutral is then a timed move out from
+// there. No retries, it will only do the same thing again.
+async fn limp_move(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    button: Button,
+) -> bool {
+    info!("Moving to {}, open loop", button);
+    let move_id = start_supply_capture();
+    let moved = limp_drive(actuator, calibration, button).await;
+    stop_supply_capture(move_id);
+
+    moved
+}
+
+// The open loop moves themselves.
+async fn limp_drive(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    button: Button,
+) -> bool {
+    // All the way in, with a margin. Each of the blind moves take seconds.
+    check_in(Task::Actuator);
+    let throw_mm = (Actuator::POSITION_MAX - Actuator::POSITION_MIN) / Actuator::POSITION_1MM;
+    match actuator.drive_blind(false, throw_mm + 5).await {
+        Ok(Blind::EndStop) => {}
+        // Some actuators stop by themselves at the end, without a current spike. Either way,
+        // it's as far in as it goes.
+        Ok(Blind::Done) => debug!("Actuator retracted, without hitting the end stop"),
+        Err(e) => {
+            error!("Actuator failed to retract: {:?}", e);
+            return false;
+        }
+    }
+    if button == Button::P {
+        return true;
+    }
+
+    let mm = calibration
+        .position(button)
+        .saturating_sub(calibration.end_min)
+        / Actuator::POSITION_1MM;
+    check_in(Task::Actuator);
+    if let Err(e) = actuator.drive_blind(true, mm).await {
+        error!("Actuator failed to move out to {}: {:?}", button, e);
+        return false;
+    }
+
+    true
+}
+
+// The gear change failed. Go back to the last gear the transmission confirmed, and show
+// that on the buttons. If we can't even do that, give up on the actuator.
+async fn recover(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    policy: FailurePolicy,
+) {
+    let previous = unsafe { BUTTON_ENABLED };
+    CHANNEL_CANWRITE.send(CANMessage::GearChangeFailed).await;
+
+    for attempt in 1..=RECOVERY_RETRIES {
+        warn!(
+            "Going back to {}, attempt {}/{}",
+            previous, attempt, RECOVERY_RETRIES
+        );
+
+        // A (P)ark or (N)eutral request stops the recovery as well.
+        unsafe { ACTUATOR_TARGET = Some(previous) };
+        SIGNAL_PREEMPT.reset();
+        let change = change_gear(actuator, calibration, previous).await;
+        unsafe { ACTUATOR_TARGET = None };
+        SIGNAL_PREEMPT.reset();
+
+        match change {
+            GearChange::Done => {
+                info!("Recovered, back in {}", previous);
+                show_gear(previous).await;
+                return;
+            }
+            GearChange::Preempted => {
+                // (P)ark or (N)eutral is just as good as the last gear. It's waiting in
+                // `SIGNAL_GEAR`.
+                info!("Recovery pre-empted");
+                return;
+            }
+            GearChange::Failed => {}
+        }
+        Timer::after_millis(RETRY_PAUSE_MS).await;
+    }
 
-use actuator::Actuator;
+    // Latch the fault, it stays until restart.
+    error!("Actuator failed");
+    actuator.stop();
+    raise_fault(FaultCode::ActuatorFailed);
+    let position = actuator.read_position().await;
+    log_event(EventKind::ActuatorFailed, previous, position);
+    CHANNEL_CANWRITE.send(CANMessage::ActuatorFailed).await;
+    limp_or_reset(policy, LimpReason::Actuator, previous, position).await;
 
-pub static CHANNEL_ACTUATOR: Channel<CriticalSectionRawMutex, Button, 64> = Channel::new();
+    // Still here, so we're limping. Only (P)ark and (N)eutral from now on.
+    show_gear(previous).await;
+}
+
+// The actuator may only be moved for the self-test when the transmission says it's in (P)ark,
+// or the car is standing still with the brake pressed. What the flash says isn't good enough,
+// the lever might have been moved since - without the CAN-bus, there's no self-test.
+fn safe_to_test() -> bool {
+    let state = vehicle_state();
+    let parked = state.gear == Some(Button::P);
+    let stopped = state.speed == Some(0) && state.brake == Some(true);
+
+    parked || stopped
+}
+
+// Test the actuator at boot, once it's safe to do so.
+pub async fn self_test(actuator: &mut Actuator<'static>) -> SelfTest {
+    for attempt in 1..=SELF_TEST_ATTEMPTS {
+        let started = Instant::now();
+        let mut waiting = false;
+        while !safe_to_test() {
+            if !can_alive() && started.elapsed().as_millis() > SELF_TEST_WAIT_NO_CAN_MS {
+                warn!("No CAN-bus, skipping the actuator self-test");
+                return SelfTest::Skipped;
+            }
+            if !waiting {
+                info!("Waiting for the car to stop, with the brake pressed, to test the actuator");
+                waiting = true;
+            }
+            Timer::after_millis(250).await;
+        }
+
+        if actuator.self_test().await {
+            return SelfTest::Passed;
+        }
+        warn!(
+            "Actuator self-test failed, attempt {}/{}",
+            attempt, SELF_TEST_ATTEMPTS
+        );
+        Timer::after_millis(RETRY_PAUSE_MS).await;
+    }
+
+    SelfTest::Failed
+}
+
+// What gear we're in at boot, and what gear to go to.
+#[derive(Copy, Clone, Format)]
+pub struct BootDecision {
+    pub current: Button,
+    pub target: Button,
+}
+
+// Work out what gear we're really in at boot. The flash only says what it was when it was
+// last written - the lever might have been moved by hand while we were off, or the write
+// lost. The transmission knows best (if it says), then where the actuator is.
+pub async fn boot_gear(actuator: &mut Actuator<'static>, config: &DbwConfig) -> BootDecision {
+    let stored = config.active_button;
+
+    // Give the CAN-bus a moment to hear from the transmission.
+    let started = Instant::now();
+    while !can_alive() && started.elapsed().as_millis() < BOOT_GEAR_WAIT_MS {
+        Timer::after_millis(50).await;
+    }
+    let transmission = if can_alive() { engaged_gear() } else { None };
+
+    // Without a calibration, the gear windows are only a guess. And in limp-home mode, the
+    // position is anyone's guess.
+    let position = actuator.read_position().await;
+    let lever = if config.calibration.valid && limp_reason() != Some(LimpReason::Actuator) {
+        config.calibration.gear_at(position)
+    } else {
+        None
+    };
+    info!(
+        "Boot gear: flash {}, transmission {:?}, actuator {:?} ({})",
+        stored, transmission, lever, position
+    );
+
+    if let (Some(transmission), Some(lever)) = (transmission, lever) {
+        if transmission != lever {
+            warn!(
+                "Transmission in {}, but the actuator is at {}",
+                transmission, lever
+            );
+        }
+    }
+
+    let current = transmission.or(lever).unwrap_or(stored);
+    if current != stored {
+        warn!("In {}, but the flash says {}", current, stored);
+        log_event(EventKind::BootGearMismatch, current, position);
+    }
+
+    // Never change gear under way, whatever the policy says.
+    let target = if vehicle_state().speed.is_some_and(|speed| speed > 0) {
+        warn!("Car is moving, staying in {}", current);
+        current
+    } else {
+        match config.boot_gear {
+            BootGear::RestoreLast => stored,
+            BootGear::Park => Button::P,
+            BootGear::FollowTransmission => current,
+        }
+    };
+
+    BootDecision { current, target }
+}
+
+// Check that the actuator is still inside the window of the gear we're in. A small drift
+// (vibrations, the lever spring etc) is corrected. Anything bigger means that someone have
+// moved the lever by hand - don't fight that, just warn about it.
+async fn supervise_idle(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    idle: &mut Idle,
+) {
+    let button = unsafe { BUTTON_ENABLED };
+    let position = actuator.read_position().await;
+    if calibration.in_window(button, position) {
+        if *idle != Idle::InPlace {
+            info!("Actuator back inside the {} window ({})", button, position);
+        }
+        *idle = Idle::InPlace;
+        return;
+    }
+
+    if position.abs_diff(calibration.position(button)) > calibration.tolerance * 2 {
+        if *idle != Idle::Moved {
+            warn!(
+                "Actuator moved to {} while in {} ({}) - lever moved by hand?",
+                position,
+                button,
+                calibration.position(button)
+            );
+            log_event(EventKind::UnexpectedMovement, button, position);
+            CHANNEL_CANWRITE.send(CANMessage::LeverMoved).await;
+        }
+        *idle = Idle::Moved;
+        return;
+    }
+
+    let corrections = match *idle {
+        Idle::Drifting(n) => n,
+        _ => 0,
+    };
+    if corrections >= MAX_CORRECTIONS {
+        // Already warned about it.
+        return;
+    }
+
+    warn!(
+        "Actuator drifted to {} while in {} ({}), correcting",
+        position,
+        button,
+        calibration.position(button)
+    );
+    log_event(EventKind::PositionDrift, button, position);
+    if move_to_gear(actuator, calibration, button).await {
+        log_event(
+            EventKind::DriftCorrected,
+            button,
+            actuator.read_position().await,
+        );
+    }
+
+    *idle = Idle::Drifting(corrections + 1);
+    if corrections + 1 == MAX_CORRECTIONS {
+        warn!("Actuator keeps drifting, not correcting it any more");
+    }
+}
 
 // Control the actuator. Wait for a button press, then move it to the
 // desired drive mode position.
 #[embassy_executor::task]
-pub async fn actuator_control(
-    receiver: Receiver<'static, CriticalSectionRawMutex, Button, 64>,
-    flash: &'static FlashMutex,
-    mut actuator: Actuator<'static>,
-) {
+pub async fn actuator_control(flash: &'static FlashMutex, mut actuator: Actuator<'static>) {
     info!("Started actuator control task");
 
+    let (mut calibration, policy) = {
+        // The flash lock is released when it goes out of scope.
+        let mut flash = flash.lock().await;
+        let config = match DbwConfig::read(&mut flash) {
+            Ok(config) => config,
+            Err(_) => resonable_defaults(),
+        };
+        (config.calibration, config.failure_policy)
+    };
+    if !calibration.valid {
+        warn!("Actuator not calibrated, using the default gear positions");
+        calibration = uncalibrated();
+    }
+
+    // Stop as soon as we're well inside the gear window.
+    actuator.set_deadband(calibration.tolerance / 2);
+
+    register(Task::Actuator);
+
+    let mut idle = Idle::InPlace;
     loop {
-        // Block waiting for button press.
-        let button = receiver.receive().await;
+        check_in(Task::Actuator);
 
-        // TODO: We need to check that we're not moving etc !!
+        // Block waiting for button press, or a request to calibrate. Keep an eye on the
+        // actuator position while we wait.
+        let button = match select3(
+            SIGNAL_GEAR.wait(),
+            SIGNAL_CALIBRATE.wait(),
+            Timer::after_millis(IDLE_CHECK_MS),
+        )
+        .await
+        {
+            Either3::First(button) => button,
+            Either3::Third(_) => {
+                // We've heard from the transmission, so it's there. It shouldn't go quiet.
+                if can_lost() && !fault_active(FaultCode::CanBusLost) {
+                    raise_fault(FaultCode::CanBusLost);
+                    let button = unsafe { BUTTON_ENABLED };
+                    let position = actuator.read_position().await;
+                    limp_or_reset(policy, LimpReason::CanBus, button, position).await;
+                }
 
-        // Move the actuator to the gear mode selected.
-        if !actuator.change_gear_mode(Button::to_gearmode(button)).await {
-            error!("Actuator failed to move to {}", Button::to_gearmode(button));
+                // Leave it alone while something else is going on, or if we can't trust it.
+                if !unsafe { BUTTONS_BLOCKED } && limp_reason() != Some(LimpReason::Actuator) {
+                    supervise_idle(&mut actuator, &calibration, &mut idle).await;
+                }
+                continue;
+            }
+            Either3::Second(_) => {
+                info!("Entering actuator calibration mode");
+                unsafe { CALIBRATING = true };
+
+                // It takes as long as it takes to find the gears.
+                unregister(Task::Actuator);
+
+                match calibrate(&mut actuator).await {
+                    Some(new) => {
+                        calibration = new;
+                        actuator.set_deadband(calibration.tolerance / 2);
+                        CHANNEL_CONFIG.send(ConfigUpdate::Calibration(new)).await;
+                    }
+                    None => error!("Calibration failed, keeping the old gear positions"),
+                }
+
+                unsafe { CALIBRATING = false };
+                register(Task::Actuator);
+
+                // Go back to where we were before the calibration started.
+                let button = unsafe { BUTTON_ENABLED };
+                show_gear(button).await;
+                if !move_to_gear(&mut actuator, &calibration, button).await {
+                    error!("Actuator failed to move back to {}", button);
+                }
+                unblock_buttons();
+                idle = Idle::InPlace;
+                continue;
+            }
+        };
+
+        if limp_home() && !matches!(button, Button::P | Button::N) {
+            warn!("Limp-home mode, ignoring {}", button);
+            show_gear(unsafe { BUTTON_ENABLED }).await;
             continue;
         }
 
-        // Now that we're done moving the actuator, Enable reading buttons again.
-        unsafe { BUTTONS_BLOCKED = false };
+        // From here on, a (P)ark or (N)eutral request will stop the move.
+        unsafe { ACTUATOR_TARGET = Some(button) };
+        SIGNAL_PREEMPT.reset();
 
-        // .. and update the button enabled.
-        unsafe { BUTTON_ENABLED = button };
+        // A newer request came in while we got ready, go for that instead.
+        if SIGNAL_GEAR.signaled() {
+            debug!("{} replaced by a newer request", button);
+            unsafe { ACTUATOR_TARGET = None };
+            continue;
+        }
 
-        // .. and write it to flash.
-        {
-            // Read the existing values from the flash.
-            // The flash lock is released when it goes out of scope.
-            let mut flash = flash.lock().await;
-            let mut config = match DbwConfig::read(&mut flash) {
-                // Read the old/current values.
-                Ok(config) => config,
-                Err(e) => {
-                    error!("Failed to read flash: {:?}", e);
-                    resonable_defaults()
+        // Move the actuator to the gear mode selected.
+        let change = if limp_reason() == Some(LimpReason::Actuator) {
+            if limp_move(&mut actuator, &calibration, button).await && gear_engaged(button).await {
+                GearChange::Done
+            } else {
+                GearChange::Failed
+            }
+        } else {
+            change_gear(&mut actuator, &calibration, button).await
+        };
+        unsafe { ACTUATOR_TARGET = None };
+        SIGNAL_PREEMPT.reset();
+
+        match change {
+            GearChange::Done => {}
+            GearChange::Preempted => {
+                // The actuator is stopped, somewhere on the way. The new gear is waiting in
+                // `SIGNAL_GEAR`.
+                warn!("Move to {} pre-empted", button);
+                idle = Idle::InPlace;
+                continue;
+            }
+            GearChange::Failed => {
+                error!("Actuator failed to move to {}", button);
+                log_event(
+                    EventKind::GearChangeFailed,
+                    button,
+                    actuator.read_position().await,
+                );
+                if limp_reason() == Some(LimpReason::Actuator) {
+                    // There's nothing to go back to, let the driver try again.
+                    raise_fault(FaultCode::ActuatorMove);
+                    show_gear(unsafe { BUTTON_ENABLED }).await;
+                } else {
+                    recover(&mut actuator, &calibration, policy).await;
                 }
-            };
+                idle = Idle::InPlace;
+                continue;
+            }
+        }
+        log_event(
+            EventKind::GearChanged,
+            button,
+            actuator.read_position().await,
+        );
+        idle = Idle::InPlace;
 
-            // Set new value.
-            config.active_button = button;
+        // Now that we're done moving the actuator, update the button enabled.
+        unsafe { BUTTON_ENABLED = button };
 
-            // Write the config to flash.
-            write_flash(&mut flash, config).await;
-        }
+        // .. and write it to flash.
+        CHANNEL_CONFIG
+            .send(ConfigUpdate::ActiveButton(button))
+            .await;
     }
 }
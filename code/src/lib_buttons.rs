@@ -1,4 +1,4 @@
-use defmt::{debug, error, info, unwrap, Format};
+use defmt::{debug, error, info, unwrap, warn};
 
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
@@ -9,84 +9,101 @@ use embassy_rp::{
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
     channel::{Channel, Receiver},
-    mutex::Mutex,
+    mutex::{Mutex, MutexGuard},
     pubsub::{PubSubChannel, WaitResult},
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 
 pub type ScannerMutex = Mutex<NoopRawMutex, r503::R503<'static>>;
 
 // External "defines".
-use crate::lib_actuator::CHANNEL_ACTUATOR;
+use crate::lib_actuator::{request_gear, ACTUATOR_TARGET, SIGNAL_GEAR};
+use crate::lib_calibration::{CALIBRATING, CHANNEL_CALIBRATION, SIGNAL_CALIBRATE};
 use crate::lib_can_bus::{CANMessage, CHANNEL_CANWRITE};
 use crate::lib_config::{write_flash, DbwConfig, FlashMutex};
+use crate::lib_expander::{ExpanderInput, ExpanderOutput};
+pub use crate::lib_gear::Button;
+use crate::lib_leds::{
+    clear, clear_buttons, show, show_buttons, show_gear, LedTarget, Priority, BUTTONS_DISABLED,
+    GEAR_ALREADY_SELECTED, GEAR_GESTURE,
+};
+use crate::lib_resources::{EXPANDER_BUTTONS, EXPANDER_LEDS};
+use crate::lib_status::limp_home;
+use crate::lib_watchdog::{register_since, unregister, Task};
 
-use actuator::GearModes;
 use r503;
 
-#[derive(Copy, Clone, Format, PartialEq)]
-#[repr(u8)]
-pub enum Button {
-    P,
-    R,
-    N,
-    D,
-}
-
 #[derive(Copy, Clone)]
 pub enum ButtonState {
     Stop,
     Start,
 }
 
-// https://medium.com/@mikecode/rust-conversion-between-enum-and-integer-0e10e613573c
-impl Button {
-    pub fn from_integer(v: u8) -> Self {
-        match v {
-            0 => Self::P,
-            1 => Self::R,
-            2 => Self::N,
-            3 => Self::D,
-            _ => panic!("Unknown value: {}", v),
+pub enum LedStatus {
+    On,
+    Off,
+}
+
+// A button is either on a Pico pin, or on the GPIO expander (see `EXPANDER_BUTTONS`).
+pub enum ButtonInput {
+    Native(Input<'static>),
+    Expander(ExpanderInput),
+}
+
+impl ButtonInput {
+    pub fn new(btn_pin: Peri<'static, AnyPin>, button: Button) -> Self {
+        match EXPANDER_BUTTONS[button as usize] {
+            Some(pin) => Self::Expander(ExpanderInput::new(pin)),
+            None => Self::Native(Input::new(btn_pin, Pull::Up)),
         }
     }
 
-    pub fn from(v: Self) -> u8 {
-        match v {
-            Self::P => 0,
-            Self::R => 1,
-            Self::N => 2,
-            Self::D => 3,
+    pub fn get_level(&mut self) -> Level {
+        match self {
+            Self::Native(input) => input.get_level(),
+            Self::Expander(input) => input.get_level(),
         }
     }
 
-    pub fn to_gearmode(v: Self) -> GearModes {
-        match v {
-            Self::P => GearModes::P,
-            Self::R => GearModes::R,
-            Self::N => GearModes::N,
-            Self::D => GearModes::D,
+    pub async fn wait_for_any_edge(&mut self) {
+        match self {
+            Self::Native(input) => input.wait_for_any_edge().await,
+            Self::Expander(input) => input.wait_for_any_edge().await,
         }
     }
+}
 
-    pub fn iterator() -> impl Iterator<Item = Button> {
-        [Self::P, Self::R, Self::N, Self::D].iter().copied()
-    }
+// Same for the LEDs (see `EXPANDER_LEDS`).
+pub enum LedOutput {
+    Native(Output<'static>),
+    Expander(ExpanderOutput),
 }
 
-pub enum LedStatus {
-    On,
-    Off,
+impl LedOutput {
+    pub fn new(led_pin: Peri<'static, AnyPin>, button: Button) -> Self {
+        match EXPANDER_LEDS[button as usize] {
+            Some(pin) => Self::Expander(ExpanderOutput::new(pin)),
+            None => Self::Native(Output::new(led_pin, Level::Low)),
+        }
+    }
+
+    pub fn set_level(&mut self, level: Level) {
+        match self {
+            Self::Native(output) => output.set_level(level),
+            Self::Expander(output) => output.set_level(level),
+        }
+    }
 }
 
 // https://github.com/embassy-rs/embassy/blob/main/examples/rp/src/bin/debounce.rs
-pub struct Debouncer<'a> {
-    input: Input<'a>,
+pub struct Debouncer {
+    input: ButtonInput,
     debounce: Duration,
 }
 
-impl<'a> Debouncer<'a> {
-    pub fn new(input: Input<'a>, debounce: Duration) -> Self {
+impl Debouncer {
+    pub fn new(input: ButtonInput, debounce: Duration) -> Self {
         Self { input, debounce }
     }
 
@@ -115,29 +132,104 @@ pub static CHANNEL_D: Channel<CriticalSectionRawMutex, LedStatus, 64> = Channel:
 pub static CHANNEL_BUTTON_STATE: PubSubChannel<CriticalSectionRawMutex, ButtonState, 4, 4, 4> =
     PubSubChannel::new();
 
+// When each of those waiting for the scanner started waiting (ms since boot, 0 is a free
+// slot). The login, and the button tasks.
+const SCANNER_WAITERS: usize = 5;
+static SCANNER_WAITING: [AtomicU32; SCANNER_WAITERS] =
+    [const { AtomicU32::new(0) }; SCANNER_WAITERS];
+
+// Keep the watchdog on whoever have waited the longest, if anyone is waiting.
+fn watch_scanner_waiters() {
+    match SCANNER_WAITING
+        .iter()
+        .map(|waiting| waiting.load(Ordering::SeqCst))
+        .filter(|since| *since != 0)
+        .min()
+    {
+        Some(since) => register_since(Task::Scanner, Instant::from_millis(since as u64)),
+        None => unregister(Task::Scanner),
+    }
+}
+
+// A slot in `SCANNER_WAITING`, for as long as the wait goes on. Given back when dropped, also
+// when the wait is given up on.
+struct ScannerWaiter(Option<usize>);
+
+impl ScannerWaiter {
+    fn new() -> Self {
+        let now = (Instant::now().as_millis() as u32).max(1);
+        let slot = SCANNER_WAITING.iter().position(|waiting| {
+            waiting
+                .compare_exchange(0, now, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        if slot.is_none() {
+            warn!("Too many waiting for the fingerprint scanner, can't watch them all");
+        }
+        watch_scanner_waiters();
+
+        Self(slot)
+    }
+}
+
+impl Drop for ScannerWaiter {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            SCANNER_WAITING[slot].store(0, Ordering::SeqCst);
+        }
+        watch_scanner_waiters();
+    }
+}
+
+// Whoever has the scanner should be done with it well within the watchdog deadline. If not,
+// they're stuck - and so is everyone waiting for it.
+pub async fn lock_scanner(
+    fp_scanner: &'static ScannerMutex,
+) -> MutexGuard<'static, NoopRawMutex, r503::R503<'static>> {
+    let _waiter = ScannerWaiter::new();
+    fp_scanner.lock().await
+}
+
 // Start with the button UNSET, then change it when we know what gear the car is in.
 pub static mut BUTTON_ENABLED: Button = Button::P;
 
-// When the actuator is moving, we need to set this to `true` to block input.
+// Set to `true` to block input - on battery, while shutting down, and while a button gesture
+// or the calibration is under way. A gear change doesn't block the buttons, a press while the
+// actuator is moving is the new gear (see `request_gear`).
 pub static mut BUTTONS_BLOCKED: bool = false;
 
+// Set for the shutdown. The buttons stay blocked, whatever else is done with them.
+static BUTTONS_HELD: AtomicBool = AtomicBool::new(false);
+
+pub fn hold_buttons(held: bool) {
+    BUTTONS_HELD.store(held, Ordering::SeqCst);
+    unsafe { BUTTONS_BLOCKED = held };
+}
+
+// Done with whatever blocked the buttons, let them through again. Unless they're held.
+pub fn unblock_buttons() {
+    if !BUTTONS_HELD.load(Ordering::SeqCst) {
+        unsafe { BUTTONS_BLOCKED = false };
+    }
+}
+
 // Control the drive button LEDs - four buttons, four LEDs.
 // The `button` parameter is only here to prettify the log output :).
 #[embassy_executor::task(pool_size = 4)]
-async fn set_led(
+pub async fn set_led(
     receiver: Receiver<'static, CriticalSectionRawMutex, LedStatus, 64>,
     led_pin: Peri<'static, AnyPin>,
     button: Button,
 ) {
     debug!("Button::{}: Started button LED control task", button);
 
-    let mut led = Output::new(led_pin, Level::Low); // Always start with the LED off.
+    let mut led = LedOutput::new(led_pin, button); // Always start with the LED off.
 
     loop {
         // Block waiting for data.
         match receiver.receive().await {
-            LedStatus::On => led.set_high(),
-            LedStatus::Off => led.set_low(),
+            LedStatus::On => led.set_level(Level::High),
+            LedStatus::Off => led.set_level(Level::Low),
         }
     }
 }
@@ -153,7 +245,10 @@ pub async fn read_button(
     led_pin: Peri<'static, AnyPin>,
 ) {
     // Initialize the button listener.
-    let mut btn = Debouncer::new(Input::new(btn_pin, Pull::Up), Duration::from_millis(100));
+    let mut btn = Debouncer::new(
+        ButtonInput::new(btn_pin, button),
+        Duration::from_millis(100),
+    );
 
     // Spawn off a LED driver for this button.
     match button {
@@ -192,10 +287,9 @@ pub async fn read_button(
                         unsafe { BUTTONS_BLOCKED = true };
 
                         // Also turn off all the LEDs to indicate this.
-                        CHANNEL_P.send(LedStatus::Off).await;
-                        CHANNEL_N.send(LedStatus::Off).await;
-                        CHANNEL_R.send(LedStatus::Off).await;
-                        CHANNEL_D.send(LedStatus::Off).await;
+                        if button == Button::P {
+                            show_buttons(BUTTONS_DISABLED).await;
+                        }
                     }
                     WaitResult::Message(ButtonState::Start) => {
                         // We're told to processing button presses again - we're back on power!
@@ -203,13 +297,11 @@ pub async fn read_button(
                             "Button::{}: Start processing button presses - unblock buttons",
                             button
                         );
-                        unsafe { BUTTONS_BLOCKED = false };
+                        unblock_buttons();
 
-                        match unsafe { BUTTON_ENABLED } {
-                            Button::P => CHANNEL_P.send(LedStatus::On).await,
-                            Button::N => CHANNEL_N.send(LedStatus::On).await,
-                            Button::R => CHANNEL_R.send(LedStatus::On).await,
-                            Button::D => CHANNEL_D.send(LedStatus::On).await,
+                        // Removing the block reveals the enabled button LED again.
+                        if button == Button::P {
+                            clear_buttons(BUTTONS_DISABLED.priority).await;
                         }
                     }
                     _ => {}
@@ -219,6 +311,19 @@ pub async fn read_button(
             }
         }
 
+        // While calibrating, the buttons are used to confirm the gear positions.
+        if unsafe { CALIBRATING } {
+            debug!("Button::{}: Calibration confirmation", button);
+            CHANNEL_CALIBRATION.send(button).await;
+            continue;
+        }
+
+        // In limp-home mode, only (P)ark and (N)eutral can be selected.
+        if limp_home() && !matches!(button, Button::P | Button::N) {
+            debug!("Button::{}: Limp-home mode, ignoring press", button);
+            continue;
+        }
+
         if unsafe { BUTTONS_BLOCKED } {
             debug!("Button::{}: Buttons blocked", button);
 
@@ -230,13 +335,13 @@ pub async fn read_button(
                 );
 
                 // Turn on the 'P' and 'N' LEDs, to indicate that both have been pressed.
-                CHANNEL_P.send(LedStatus::On).await;
-                CHANNEL_N.send(LedStatus::On).await;
+                show(LedTarget::Button(Button::P), GEAR_GESTURE).await;
+                show(LedTarget::Button(Button::N), GEAR_GESTURE).await;
 
                 {
                     // Verify with a valid fingerprint that we're authorized to change Valet Mode.
                     // The fp_scanner lock is released when it goes out of scope.
-                    let mut fp_scanner = fp_scanner.lock().await;
+                    let mut fp_scanner = lock_scanner(fp_scanner).await;
                     if !fp_scanner.Wrapper_Verify_Fingerprint().await {
                         error!("Can't match fingerprint, will not toggle Valet Mode");
 
@@ -246,8 +351,9 @@ pub async fn read_button(
                         // Turn off the aura.
                         fp_scanner.Wrapper_AuraSet_Off().await;
 
-                        // Turn off the 'N' LED.
-                        CHANNEL_N.send(LedStatus::Off).await;
+                        // Drop the gesture indication, leaving only the 'P' LED on.
+                        clear(LedTarget::Button(Button::P), Priority::Normal).await;
+                        clear(LedTarget::Button(Button::N), Priority::Normal).await;
 
                         // Restart loop.
                         continue;
@@ -274,137 +380,92 @@ pub async fn read_button(
                             write_flash(&mut flash, config).await;
                         }
 
-                        // Turn off the 'N' LED.
-                        CHANNEL_N.send(LedStatus::Off).await;
+                        // Drop the gesture indication, leaving only the 'P' LED on.
+                        clear(LedTarget::Button(Button::P), Priority::Normal).await;
+                        clear(LedTarget::Button(Button::N), Priority::Normal).await;
                     }
                 }
 
                 // Give it a second, so we don't *also* deal with the enabled button.
                 // As in, let the button block "reach" the 'N' button task.
                 Timer::after_secs(1).await;
-                unsafe { BUTTONS_BLOCKED = false };
-            }
-
-            continue;
-        }
+                unblock_buttons();
+            } else if unsafe { BUTTON_ENABLED == Button::P } && button == Button::D {
+                // NOTE: Same as for Valet Mode, but with 'P' and 'D'.
+                debug!(
+                    "Button::{}: Both 'P' and 'D' pressed - actuator calibration",
+                    button
+                );
 
-        // Disable reading buttons as soon as possible, while we deal with this one.
-        // If this isn't "us", then the buttons will be re-enabled in the actuator task,
-        // once the actuator have finished moving..
-        // If this IS "us", we re-enable the buttons again after we've blinked "our" LED.
-        unsafe { BUTTONS_BLOCKED = true };
+                show(LedTarget::Button(Button::P), GEAR_GESTURE).await;
+                show(LedTarget::Button(Button::D), GEAR_GESTURE).await;
 
-        // We know who WE are, so turn ON our own LED and turn off all the other LEDs.
-        info!("Button::{}: Button press detected", button);
-        match button {
-            Button::P => {
-                if unsafe { button == BUTTON_ENABLED } {
-                    // Already enabled => blink *our* LED three times.
-                    debug!(
-                        "Button::{}: Already enabled, blinking LED three times",
-                        button
-                    );
-
-                    for _i in 0..3 {
-                        CHANNEL_P.send(LedStatus::Off).await;
-                        Timer::after_millis(500).await;
-                        CHANNEL_P.send(LedStatus::On).await;
-                        Timer::after_millis(500).await;
+                let authorized = {
+                    // Verify with a valid fingerprint that we're authorized to calibrate.
+                    // The fp_scanner lock is released when it goes out of scope.
+                    let mut fp_scanner = lock_scanner(fp_scanner).await;
+                    let authorized = fp_scanner.Wrapper_Verify_Fingerprint().await;
+                    if !authorized {
+                        error!("Can't match fingerprint, will not calibrate the actuator");
+                        Timer::after_secs(5).await;
                     }
+                    fp_scanner.Wrapper_AuraSet_Off().await;
 
-                    unsafe { BUTTONS_BLOCKED = false };
-                } else {
-                    CHANNEL_P.send(LedStatus::On).await;
-                    CHANNEL_N.send(LedStatus::Off).await;
-                    CHANNEL_R.send(LedStatus::Off).await;
-                    CHANNEL_D.send(LedStatus::Off).await;
-                    Timer::after_millis(100).await; // Give the LED some time to light up.
-
-                    // Trigger the actuator to switch to (P)ark.
-                    CHANNEL_ACTUATOR.send(Button::P).await;
-                }
-            }
-            Button::N => {
-                if unsafe { button == BUTTON_ENABLED } {
-                    // Already enabled => blink *our* LED three times.
-                    debug!(
-                        "Button::{}: Already enabled, blinking LED three times",
-                        button
-                    );
-
-                    for _i in 0..3 {
-                        CHANNEL_N.send(LedStatus::Off).await;
-                        Timer::after_millis(500).await;
-                        CHANNEL_N.send(LedStatus::On).await;
-                        Timer::after_millis(500).await;
-                    }
+                    authorized
+                };
 
-                    unsafe { BUTTONS_BLOCKED = false };
-                } else {
-                    CHANNEL_P.send(LedStatus::Off).await;
-                    CHANNEL_N.send(LedStatus::On).await;
-                    CHANNEL_R.send(LedStatus::Off).await;
-                    CHANNEL_D.send(LedStatus::Off).await;
-                    Timer::after_millis(100).await; // Give the LED some time to light up.
-
-                    // Trigger the actuator to switch to (N)eutral.
-                    CHANNEL_ACTUATOR.send(Button::N).await;
-                }
-            }
-            Button::R => {
-                if unsafe { button == BUTTON_ENABLED } {
-                    // Already enabled => blink *our* LED three times.
-                    debug!(
-                        "Button::{}: Already enabled, blinking LED three times",
-                        button
-                    );
-
-                    for _i in 0..3 {
-                        CHANNEL_R.send(LedStatus::Off).await;
-                        Timer::after_millis(500).await;
-                        CHANNEL_R.send(LedStatus::On).await;
-                        Timer::after_millis(500).await;
-                    }
+                clear(LedTarget::Button(Button::P), Priority::Normal).await;
+                clear(LedTarget::Button(Button::D), Priority::Normal).await;
 
-                    unsafe { BUTTONS_BLOCKED = false };
+                if authorized {
+                    // The buttons stay blocked until the actuator task is done calibrating.
+                    SIGNAL_CALIBRATE.signal(());
                 } else {
-                    CHANNEL_P.send(LedStatus::Off).await;
-                    CHANNEL_N.send(LedStatus::Off).await;
-                    CHANNEL_R.send(LedStatus::On).await;
-                    CHANNEL_D.send(LedStatus::Off).await;
-                    Timer::after_millis(100).await; // Give the LED some time to light up.
-
-                    // Trigger the actuator to switch to (R)everse.
-                    CHANNEL_ACTUATOR.send(Button::R).await;
+                    unblock_buttons();
                 }
             }
-            Button::D => {
-                if unsafe { button == BUTTON_ENABLED } {
-                    // Already enabled => blink *our* LED three times.
-                    debug!(
-                        "Button::{}: Already enabled, blinking LED three times",
-                        button
-                    );
-
-                    for _i in 0..3 {
-                        CHANNEL_D.send(LedStatus::Off).await;
-                        Timer::after_millis(500).await;
-                        CHANNEL_D.send(LedStatus::On).await;
-                        Timer::after_millis(500).await;
-                    }
 
-                    unsafe { BUTTONS_BLOCKED = false };
-                } else {
-                    CHANNEL_P.send(LedStatus::Off).await;
-                    CHANNEL_N.send(LedStatus::Off).await;
-                    CHANNEL_R.send(LedStatus::Off).await;
-                    CHANNEL_D.send(LedStatus::On).await;
-                    Timer::after_millis(100).await; // Give the LED some time to light up.
-
-                    // Trigger the actuator to switch to (D)rive.
-                    CHANNEL_ACTUATOR.send(Button::D).await;
-                }
+            continue;
+        }
+
+        // The actuator is busy with another gear. Take the press as the new gear, the actuator
+        // goes there once it's done (or right away, for (P)ark and (N)eutral). Unless the
+        // buttons are blocked, see above.
+        if let Some(target) = unsafe { ACTUATOR_TARGET } {
+            if button != target {
+                info!(
+                    "Button::{}: Button press while moving to {}",
+                    button, target
+                );
+                show_gear(button).await;
+                request_gear(button);
             }
+            continue;
+        }
+
+        // We know who WE are, so turn ON our own LED and turn off all the other LEDs.
+        info!("Button::{}: Button press detected", button);
+        if unsafe { button == BUTTON_ENABLED } && !SIGNAL_GEAR.signaled() {
+            // Already enabled => blink *our* LED three times.
+            debug!(
+                "Button::{}: Already enabled, blinking LED three times",
+                button
+            );
+
+            // Disable reading buttons as soon as possible, while we blink "our" LED. We
+            // re-enable them again once it's done.
+            unsafe { BUTTONS_BLOCKED = true };
+            show(LedTarget::Button(button), GEAR_ALREADY_SELECTED).await;
+
+            // Stay blocked while the LED blinks, that's the window for the button gestures.
+            Timer::after_millis(3_000).await;
+            unblock_buttons();
+        } else {
+            show_gear(button).await;
+            Timer::after_millis(100).await; // Give the LED some time to light up.
+
+            // Trigger the actuator to switch to the new gear mode.
+            request_gear(button);
         }
 
         // Don't allow another button for quarter second.
@@ -0,0 +1,185 @@
+use defmt::{debug, error, info, warn};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+
+// External "defines".
+use crate::lib_buttons::Button;
+use crate::lib_can_bus::{can_alive, vehicle_state};
+use crate::lib_config::Calibration;
+use crate::lib_gear_actuator::{Actuator, GearActuator};
+use crate::lib_leds::{clear_buttons, show, LedColour, LedTarget, Pattern, Priority};
+
+// How long to wait between each 1mm jog, while looking for a detent.
+const JOG_PAUSE_MS: u64 = 700;
+
+// After a step back, hold still this long before jogging on. Time enough to press the gear,
+// or to step back again.
+const JOG_HOLD_MS: u64 = 5_000;
+
+// How long the operator have to say go, before the calibration is called off.
+const CONFIRM_TIMEOUT_SECS: u64 = 30;
+
+// (P)ark blinks while waiting for the go ahead.
+const CALIBRATE_CONFIRM: Pattern = Pattern::blink(LedColour::Orange, Priority::Normal, 0, 300);
+
+// The expected gear LED blinks while we're looking for its detent.
+const CALIBRATE_SEARCHING: Pattern = Pattern::blink(LedColour::Green, Priority::Normal, 0, 150);
+const CALIBRATE_FOUND: Pattern = Pattern::solid(LedColour::Green, Priority::Normal);
+
+// Button presses are sent here instead of to the actuator while calibrating.
+pub static CHANNEL_CALIBRATION: Channel<CriticalSectionRawMutex, Button, 4> = Channel::new();
+
+// Ask the actuator task to enter calibration mode.
+pub static SIGNAL_CALIBRATE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Set while the calibration is running, so the button tasks know where to send the presses.
+pub static mut CALIBRATING: bool = false;
+
+// The car is standing still, with the brake pressed. Without the CAN-bus we can't tell, then
+// it's all up to the operator.
+fn safe_to_calibrate() -> bool {
+    let state = vehicle_state();
+    !can_alive() || (state.speed == Some(0) && state.brake == Some(true))
+}
+
+// The calibration sweeps the lever through all the gears, so nothing moves until the
+// operator presses (P) - with the brake pressed. Any other button, or no answer, calls it off.
+async fn confirmed() -> bool {
+    // Throw away any presses that came in before we asked.
+    while CHANNEL_CALIBRATION.try_receive().is_ok() {}
+
+    info!("Calibration: Press the brake, then (P) to start. Any other button cancels");
+    show(LedTarget::Button(Button::P), CALIBRATE_CONFIRM).await;
+    let answer = with_timeout(
+        Duration::from_secs(CONFIRM_TIMEOUT_SECS),
+        CHANNEL_CALIBRATION.receive(),
+    )
+    .await;
+    clear_buttons(Priority::Normal).await;
+
+    if !matches!(answer, Ok(Button::P)) {
+        warn!("Calibration: Not confirmed, cancelled");
+        return false;
+    }
+    if !safe_to_calibrate() {
+        error!("Calibration: The car must stand still, with the brake pressed");
+        return false;
+    }
+
+    true
+}
+
+// Find the end stops, then jog the actuator from one end to the other. For each gear (P, R,
+// N, D - in that order), the operator presses the matching button when the transmission
+// have engaged it. Pressing any other button steps back 1mm, in case we overshot, and holds
+// the jog for a while.
+pub async fn calibrate(actuator: &mut Actuator<'static>) -> Option<Calibration> {
+    if !confirmed().await {
+        return None;
+    }
+
+    info!("Calibration: Finding the end stops");
+
+    // These might time out if the lever stops us before the actuator does, that's fine,
+    // then that's where the end stop is.
+    let _ = actuator.move_to(Actuator::POSITION_MAX).await;
+    let end_max = actuator.read_position().await;
+    let _ = actuator.move_to(Actuator::POSITION_MIN).await;
+    let end_min = actuator.read_position().await;
+    info!("Calibration: End stops at {} and {}", end_min, end_max);
+
+    if end_max <= end_min + Actuator::POSITION_1MM * 10 {
+        error!("Calibration: Actuator haven't moved between the end stops");
+        return None;
+    }
+
+    // Throw away any presses that came in before we were ready.
+    while CHANNEL_CALIBRATION.try_receive().is_ok() {}
+
+    let mut gears = [0u16; 4];
+    let mut position = end_min;
+    for gear in Button::iterator() {
+        info!("Calibration: Looking for {}, press it when engaged", gear);
+        show(LedTarget::Button(gear), CALIBRATE_SEARCHING).await;
+
+        let mut held = false;
+        loop {
+            let pause = if held { JOG_HOLD_MS } else { JOG_PAUSE_MS };
+            match select(CHANNEL_CALIBRATION.receive(), Timer::after_millis(pause)).await {
+                Either::First(button) if button == gear => {
+                    gears[Button::from(gear) as usize] = actuator.read_position().await;
+                    info!(
+                        "Calibration: {} at {}",
+                        gear,
+                        gears[Button::from(gear) as usize]
+                    );
+                    break;
+                }
+                Either::First(_) => {
+                    position = position.saturating_sub(Actuator::POSITION_1MM).max(end_min);
+                    debug!("Calibration: Back to {}, holding", position);
+                    let _ = actuator.move_to(position).await;
+                    held = true;
+                }
+                Either::Second(_) => {
+                    held = false;
+                    if !safe_to_calibrate() {
+                        error!("Calibration: The car is moving, or the brake released");
+                        clear_buttons(Priority::Normal).await;
+                        return None;
+                    }
+                    if position + Actuator::POSITION_1MM > end_max {
+                        error!("Calibration: Reached the end stop without finding {}", gear);
+                        clear_buttons(Priority::Normal).await;
+                        return None;
+                    }
+
+                    position += Actuator::POSITION_1MM;
+                    debug!("Calibration: Forward to {}", position);
+                    if let Err(e) = actuator.move_to(position).await {
+                        error!("Calibration: Jog failed: {:?}", e);
+                        clear_buttons(Priority::Normal).await;
+                        return None;
+                    }
+                }
+            }
+        }
+
+        show(LedTarget::Button(gear), CALIBRATE_FOUND).await;
+    }
+
+    clear_buttons(Priority::Normal).await;
+
+    // The detents must be in order, and far enough apart to tell them from each other.
+    let mut spacing = u16::MAX;
+    for pair in gears.windows(2) {
+        if pair[1] <= pair[0] {
+            error!("Calibration: Gear positions out of order: {}", gears);
+            return None;
+        }
+        spacing = spacing.min(pair[1] - pair[0]);
+    }
+
+    let tolerance = (spacing / 4).max(Actuator::POSITION_1MM);
+    if tolerance * 2 >= spacing {
+        warn!(
+            "Calibration: Gears only {} apart, tolerance {}",
+            spacing, tolerance
+        );
+    }
+
+    let calibration = Calibration {
+        valid: true,
+        gears,
+        end_min,
+        end_max,
+        tolerance,
+    };
+    info!("Calibration: Done - {:?}", calibration);
+
+    Some(calibration)
+}
@@ -1,18 +1,23 @@
-use defmt::{debug, error, info, unwrap};
+use defmt::{debug, error, info, trace, unwrap, warn, Format};
 
 use embassy_executor::Spawner;
 use embassy_rp::{
     gpio::{Level, Output},
     peripherals::SPI0,
-    spi::{Async, Config, Spi},
+    spi::{Async, Config, Error as SpiError, Spi},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering};
 
 use static_cell::StaticCell;
 
-use crate::lib_buttons::{ButtonState, CHANNEL_BUTTON_STATE};
+use crate::lib_buttons::Button;
+use crate::lib_can_frame::{
+    decode, nominal_bit_timing, parse_rx_object, CanFrame, CanSignal, CAN_IDS, RX_OBJECT_SIZE,
+};
 use crate::lib_resources::PeriCan;
+use crate::lib_watchdog::{check_in, register, Task, CHECK_IN_SECS};
 
 pub enum CANMessage {
     Starting,
@@ -21,6 +26,14 @@ pub enum CANMessage {
     InitActuator,
     ActuatorInitialized,
     ActuatorTestFailed,
+    LimpHome,
+    GearChangeFailed,
+    ActuatorFailed,
+    LeverMoved,
+    PowerLost,
+    SupplyLost,
+    Restarted,
+    ShuttingDown,
     RelaysInitialized,
     ButtonsInitialized,
     ValetMode,
@@ -33,7 +46,270 @@ pub enum CANMessage {
 
 pub static CHANNEL_CANWRITE: Channel<CriticalSectionRawMutex, CANMessage, 64> = Channel::new();
 
-type SpiBus = Mutex<CriticalSectionRawMutex, Spi<'static, SPI0, Async>>;
+// Frames received from the CAN controller, waiting to be decoded.
+pub static CHANNEL_CANREAD: Channel<CriticalSectionRawMutex, CanFrame, 16> = Channel::new();
+
+// The CAN-C bus, where the transmission is.
+// NOTE: Check the bitrate against the car, and the clock against the board, before trusting
+//       the vehicle state! Neither have been verified.
+const CAN_CLOCK_HZ: u32 = 40_000_000;
+const CAN_BITRATE: u32 = 500_000;
+
+// The controller FIFO holds 16 frames, at a couple of hundred a second (only the IDs we
+// listen for are let through) that's almost 100ms. Read it well before it fills up.
+const CAN_POLL_MS: u64 = 5;
+const RX_FIFO_DEPTH: u32 = 16;
+
+// These are all sent every 10-50ms, if we haven't heard from one in this long the CAN bus
+// is down (or not connected).
+const CAN_ALIVE_MS: u64 = 500;
+
+const GEAR_UNKNOWN: u8 = 0xFF;
+
+static ENGAGED_GEAR: AtomicU8 = AtomicU8::new(GEAR_UNKNOWN);
+static VEHICLE_SPEED: AtomicU16 = AtomicU16::new(0);
+static BRAKE_PRESSED: AtomicBool = AtomicBool::new(false);
+static IGNITION_ON: AtomicBool = AtomicBool::new(false);
+
+// When we last heard from each of them, in ms since boot.
+static LAST_TRANSMISSION_FRAME: AtomicU64 = AtomicU64::new(0);
+static LAST_SPEED_FRAME: AtomicU64 = AtomicU64::new(0);
+static LAST_BRAKE_FRAME: AtomicU64 = AtomicU64::new(0);
+static LAST_IGNITION_FRAME: AtomicU64 = AtomicU64::new(0);
+
+// What we know about the car. `None` when we haven't heard about it lately.
+#[derive(Copy, Clone, Format)]
+pub struct VehicleState {
+    pub speed: Option<u16>, // 0.1km/h
+    pub brake: Option<bool>,
+    pub gear: Option<Button>,
+    pub ignition: Option<bool>, // Terminal 15.
+}
+
+fn fresh(last: &AtomicU64) -> bool {
+    let last = last.load(Ordering::SeqCst);
+    last != 0 && Instant::now().as_millis() - last < CAN_ALIVE_MS
+}
+
+pub fn vehicle_state() -> VehicleState {
+    VehicleState {
+        speed: fresh(&LAST_SPEED_FRAME).then(|| VEHICLE_SPEED.load(Ordering::SeqCst)),
+        brake: fresh(&LAST_BRAKE_FRAME).then(|| BRAKE_PRESSED.load(Ordering::SeqCst)),
+        gear: if fresh(&LAST_TRANSMISSION_FRAME) {
+            engaged_gear()
+        } else {
+            None
+        },
+        ignition: fresh(&LAST_IGNITION_FRAME).then(|| IGNITION_ON.load(Ordering::SeqCst)),
+    }
+}
+
+// The gear the transmission says it have engaged.
+pub fn engaged_gear() -> Option<Button> {
+    match ENGAGED_GEAR.load(Ordering::SeqCst) {
+        GEAR_UNKNOWN => None,
+        v => Some(Button::from_integer(v)),
+    }
+}
+
+// Have we heard from the transmission lately?
+pub fn can_alive() -> bool {
+    fresh(&LAST_TRANSMISSION_FRAME)
+}
+
+// Have we heard from the transmission before, but not lately? Unlike `!can_alive()`, that
+// isn't just a car without a CAN-bus connected.
+pub fn can_lost() -> bool {
+    LAST_TRANSMISSION_FRAME.load(Ordering::SeqCst) != 0 && !can_alive()
+}
+
+fn decode_frame(frame: &CanFrame) {
+    let now = Instant::now().as_millis();
+
+    match decode(frame) {
+        Some(CanSignal::Gear(gear)) => {
+            let gear = gear.map_or(GEAR_UNKNOWN, |gear| gear as u8);
+            if ENGAGED_GEAR.swap(gear, Ordering::SeqCst) != gear {
+                debug!("Transmission reports gear {:?}", engaged_gear());
+            }
+            LAST_TRANSMISSION_FRAME.store(now, Ordering::SeqCst);
+        }
+        Some(CanSignal::Speed(speed)) => {
+            VEHICLE_SPEED.store(speed, Ordering::SeqCst);
+            LAST_SPEED_FRAME.store(now, Ordering::SeqCst);
+        }
+        Some(CanSignal::Brake(pressed)) => {
+            if BRAKE_PRESSED.swap(pressed, Ordering::SeqCst) != pressed {
+                trace!("Brake pedal pressed: {}", pressed);
+            }
+            LAST_BRAKE_FRAME.store(now, Ordering::SeqCst);
+        }
+        Some(CanSignal::Ignition(on)) => {
+            if IGNITION_ON.swap(on, Ordering::SeqCst) != on {
+                debug!("Ignition on: {}", on);
+            }
+            LAST_IGNITION_FRAME.store(now, Ordering::SeqCst);
+        }
+        None => trace!("Ignoring CAN frame {:?}", frame),
+    }
+}
+
+// ==========
+// The MCP2518FD CAN controller, on SPI. Every transfer starts with a 16 bit command: the
+// instruction in the top four bits, the address in the lower twelve.
+
+const MCP_RESET: u16 = 0x0;
+const MCP_WRITE: u16 = 0x2;
+const MCP_READ: u16 = 0x3;
+
+// Registers.
+const C1CON: u16 = 0x000;
+const C1NBTCFG: u16 = 0x004;
+const C1FIFOCON1: u16 = 0x05C;
+const C1FIFOSTA1: u16 = 0x060;
+const C1FIFOUA1: u16 = 0x064;
+const C1FLTCON0: u16 = 0x1D0;
+const C1FLTOBJ0: u16 = 0x1F0; // The filter objects and masks take turns, 8 bytes apart.
+const C1MASK0: u16 = 0x1F4;
+const OSC: u16 = 0xE00;
+const RAM_START: u16 = 0x400; // The FIFO user address is from here.
+
+const OSC_READY: u32 = 1 << 10;
+const FIFO_NOT_EMPTY: u32 = 1 << 0;
+const FIFO_OVERFLOW: u32 = 1 << 3;
+const FIFO_UINC: u8 = 1 << 0; // In the second byte of C1FIFOCON.
+const FILTER_TO_FIFO1: u8 = 0x80 | 1; // Enabled, matches go to FIFO1.
+const MASK_STANDARD_ID: u32 = (1 << 30) | 0x7FF; // Only standard frames, all of the ID.
+
+// Operating modes, requested in the top byte of C1CON and read back in bits 23:21.
+const MODE_CONFIG: u8 = 4;
+const MODE_LISTEN_ONLY: u8 = 3;
+
+#[derive(Format)]
+pub enum CanError {
+    Spi,
+    NoClock,
+    Mode(u8), // The mode it's stuck in.
+}
+
+impl From<SpiError> for CanError {
+    fn from(_: SpiError) -> Self {
+        Self::Spi
+    }
+}
+
+pub struct Mcp2518fd {
+    spi: Spi<'static, SPI0, Async>,
+    cs: Output<'static>,
+}
+
+impl Mcp2518fd {
+    async fn transfer(
+        &mut self,
+        instruction: u16,
+        addr: u16,
+        buf: &mut [u8],
+    ) -> Result<(), CanError> {
+        let command = (instruction << 12) | (addr & 0x0FFF);
+        buf[..2].copy_from_slice(&command.to_be_bytes());
+
+        self.cs.set_low();
+        let result = self.spi.transfer_in_place(buf).await;
+        self.cs.set_high();
+
+        Ok(result?)
+    }
+
+    async fn read(&mut self, addr: u16) -> Result<u32, CanError> {
+        let mut buf = [0u8; 6];
+        self.transfer(MCP_READ, addr, &mut buf).await?;
+        Ok(u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]))
+    }
+
+    async fn write(&mut self, addr: u16, value: u32) -> Result<(), CanError> {
+        let mut buf = [0u8; 6];
+        buf[2..].copy_from_slice(&value.to_le_bytes());
+        self.transfer(MCP_WRITE, addr, &mut buf).await
+    }
+
+    async fn write_byte(&mut self, addr: u16, value: u8) -> Result<(), CanError> {
+        let mut buf = [0u8, 0u8, value];
+        self.transfer(MCP_WRITE, addr, &mut buf).await
+    }
+
+    async fn mode(&mut self) -> Result<u8, CanError> {
+        Ok(((self.read(C1CON).await? >> 21) & 0x07) as u8)
+    }
+
+    async fn request_mode(&mut self, mode: u8) -> Result<(), CanError> {
+        self.write_byte(C1CON + 3, mode).await?;
+
+        // It finishes the frame on the bus first.
+        for _ in 0..10 {
+            if self.mode().await? == mode {
+                return Ok(());
+            }
+            Timer::after_millis(1).await;
+        }
+
+        Err(CanError::Mode(self.mode().await?))
+    }
+
+    // Reset it, and set it up to listen for the frames we care about. Listen only, so that it
+    // never puts anything on the bus - not even an acknowledge.
+    pub async fn init(&mut self) -> Result<(), CanError> {
+        self.transfer(MCP_RESET, 0, &mut [0u8; 2]).await?;
+        Timer::after_millis(3).await;
+
+        if self.read(OSC).await? & OSC_READY == 0 {
+            return Err(CanError::NoClock);
+        }
+        self.request_mode(MODE_CONFIG).await?;
+
+        self.write(C1NBTCFG, nominal_bit_timing(CAN_CLOCK_HZ, CAN_BITRATE))
+            .await?;
+
+        // FIFO1 receives, 8 byte payloads without time stamps.
+        self.write(C1FIFOCON1, (RX_FIFO_DEPTH - 1) << 24).await?;
+
+        // One filter per frame, all into FIFO1. Anything else the controller drops by itself.
+        let mut filters = [0u8; 4];
+        for (i, id) in CAN_IDS.iter().enumerate() {
+            let offset = i as u16 * 8;
+            self.write(C1FLTOBJ0 + offset, *id).await?;
+            self.write(C1MASK0 + offset, MASK_STANDARD_ID).await?;
+            filters[i] = FILTER_TO_FIFO1;
+        }
+        self.write(C1FLTCON0, u32::from_le_bytes(filters)).await?;
+
+        self.request_mode(MODE_LISTEN_ONLY).await
+    }
+
+    // The next frame in FIFO1, if any.
+    pub async fn receive(&mut self) -> Result<Option<CanFrame>, CanError> {
+        let status = self.read(C1FIFOSTA1).await?;
+        if status & FIFO_OVERFLOW != 0 {
+            warn!("CAN controller FIFO overflowed, frames lost");
+            self.write_byte(C1FIFOSTA1, 0).await?;
+        }
+        if status & FIFO_NOT_EMPTY == 0 {
+            return Ok(None);
+        }
+
+        let addr = RAM_START + self.read(C1FIFOUA1).await? as u16;
+        let mut buf = [0u8; 2 + RX_OBJECT_SIZE];
+        self.transfer(MCP_READ, addr, &mut buf).await?;
+
+        // Done with it, on to the next one.
+        self.write_byte(C1FIFOCON1 + 1, FIFO_UINC).await?;
+
+        let mut obj = [0u8; RX_OBJECT_SIZE];
+        obj.copy_from_slice(&buf[2..]);
+        Ok(Some(parse_rx_object(&obj)))
+    }
+}
+
+type CanBus = Mutex<CriticalSectionRawMutex, Mcp2518fd>;
 
 #[embassy_executor::task]
 pub async fn can_manager(spawner: Spawner, can: PeriCan) {
@@ -49,23 +325,57 @@ pub async fn can_manager(spawner: Spawner, can: PeriCan) {
         can.recv_dma,
         spi_cfg,
     );
-    static SPI_BUS: StaticCell<SpiBus> = StaticCell::new();
-    let spi = SPI_BUS.init(Mutex::new(spi));
+    let cs = Output::new(can.csn_pin, Level::High);
 
-    // TODO: Do I need two pins for this - one for the writer and one for the reader?
-    let _cs = Output::new(can.csn_pin, Level::High);
+    static CAN_BUS: StaticCell<CanBus> = StaticCell::new();
+    let can = CAN_BUS.init(Mutex::new(Mcp2518fd { spi, cs }));
 
-    spawner.spawn(unwrap!(read_can(spi))); // Spawn the CAN reader.
-    spawner.spawn(unwrap!(write_can(spi))); // Spawn the CAN writer.
+    spawner.spawn(unwrap!(read_can(can))); // Spawn the CAN reader.
+    spawner.spawn(unwrap!(write_can(can))); // Spawn the CAN writer.
+    spawner.spawn(unwrap!(decode_can())); // Spawn the CAN decoder.
+}
+
+// Keep track of the vehicle state, from the CAN-bus messages we care about.
+#[embassy_executor::task]
+pub async fn decode_can() {
+    info!("CAN bus decoder running");
+    register(Task::CanDecoder);
+
+    loop {
+        check_in(Task::CanDecoder);
+
+        // Block waiting for data, but not for so long that the watchdog gives up on us.
+        match with_timeout(
+            Duration::from_secs(CHECK_IN_SECS),
+            CHANNEL_CANREAD.receive(),
+        )
+        .await
+        {
+            Ok(frame) => decode_frame(&frame),
+            Err(_) => continue,
+        }
+    }
 }
 
 // Write messages to CAN-bus.
 #[embassy_executor::task]
-pub async fn write_can(_spi: &'static SpiBus) {
+pub async fn write_can(_can: &'static CanBus) {
     info!("CAN bus writer running");
+    register(Task::CanWriter);
 
     loop {
-        let message = CHANNEL_CANWRITE.receive().await; // Block waiting for data.
+        check_in(Task::CanWriter);
+
+        // Block waiting for data, but not for so long that the watchdog gives up on us.
+        let message = match with_timeout(
+            Duration::from_secs(CHECK_IN_SECS),
+            CHANNEL_CANWRITE.receive(),
+        )
+        .await
+        {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
         match message {
             CANMessage::Starting => {
                 info!("=> 'Starting Drive-By-Wire system'");
@@ -85,6 +395,30 @@ pub async fn write_can(_spi: &'static SpiBus) {
             CANMessage::ActuatorTestFailed => {
                 error!("=> 'Actuator failed to move'");
             }
+            CANMessage::LimpHome => {
+                error!("=> 'Gear selector limp-home mode, P and N only - visit a workshop'");
+            }
+            CANMessage::GearChangeFailed => {
+                error!("=> 'Gear change failed, still in the old gear'");
+            }
+            CANMessage::ActuatorFailed => {
+                error!("=> 'Gear selector failed - stop the car safely'");
+            }
+            CANMessage::PowerLost => {
+                info!("=> 'On battery, shutting down soon'");
+            }
+            CANMessage::SupplyLost => {
+                error!("=> 'Gear selector supply lost, on battery - check the fuse'");
+            }
+            CANMessage::Restarted => {
+                error!("=> 'Gear selector restarted after a fault'");
+            }
+            CANMessage::ShuttingDown => {
+                info!("=> 'Drive-By-Wire system shutting down'");
+            }
+            CANMessage::LeverMoved => {
+                error!("=> 'Gear lever moved, check the gear'");
+            }
             CANMessage::RelaysInitialized => {
                 info!("=> 'Relays initialized");
             }
@@ -113,35 +447,38 @@ pub async fn write_can(_spi: &'static SpiBus) {
     }
 }
 
-// Read CAN-bus messages.
+// Read CAN-bus messages, and send them on to the decoder.
 #[embassy_executor::task]
-pub async fn read_can(_spi: &'static SpiBus) {
-    // Become a publisher on the button state channel.
-    let publisher = CHANNEL_BUTTON_STATE.publisher().unwrap();
-
+pub async fn read_can(can: &'static CanBus) {
     info!("CAN bus reader running");
+    register(Task::CanReader);
 
-    // TODO: How do we know if we're on battery power?
-    //       If we are, we should *not* enable buttons here, no matter what.
+    // Without the controller, there's no vehicle state. Everything else works without it, so
+    // keep trying.
     loop {
-        // TODO: Just test that this works.
-        debug!("Testing button state publisher");
-        publisher.publish_immediate(ButtonState::Stop);
-        Timer::after_secs(15).await;
-        publisher.publish_immediate(ButtonState::Start);
-
-        // TODO: Read CAN-bus messages (blocking).
+        check_in(Task::CanReader);
 
-        // TODO: If we're moving, disable buttons.
-        //publisher.publish_immediate(ButtonState::Stop);
-
-        // TODO: If we're NOT moving, and brake pedal is NOT depressed, disable buttons.
-        //publisher.publish_immediate(ButtonState::Stop);
+        match can.lock().await.init().await {
+            Ok(()) => break,
+            Err(e) => error!("CAN controller init failed: {:?}", e),
+        }
+        Timer::after_secs(CHECK_IN_SECS).await;
+    }
+    info!("CAN controller listening at {}kbps", CAN_BITRATE / 1_000);
 
-        // TODO: If we're NOT moving, and brake pedal is depressed, enable buttons.
-        //publisher.publish_immediate(ButtonState::Start);
+    loop {
+        check_in(Task::CanReader);
 
-        //Timer::after_secs(600).await; // TODO: Nothing to do yet, just sleep as long as we can, but 10 minutes should do it.
-        Timer::after_secs(45).await;
+        // The lock is released as soon as we have the frame.
+        let received = can.lock().await.receive().await;
+        match received {
+            // There might be more where that came from.
+            Ok(Some(frame)) => CHANNEL_CANREAD.send(frame).await,
+            Ok(None) => Timer::after_millis(CAN_POLL_MS).await,
+            Err(e) => {
+                error!("CAN controller read failed: {:?}", e);
+                Timer::after_millis(CAN_POLL_MS).await;
+            }
+        }
     }
 }
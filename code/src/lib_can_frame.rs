@@ -0,0 +1,119 @@
+use defmt::Format;
+
+// External "defines".
+use crate::lib_gear::Button;
+
+// The CAN frames, from the MCP2518FD receive FIFO to what they tell us about the car. No
+// hardware in here, so it can be tested on the host (see `can-frames`).
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub len: u8,
+    pub data: [u8; 8],
+}
+
+// The frames we listen for.
+// NOTE: NONE of these are verified on the car! They're all from the W203/W211 CAN databases,
+//       check each of them against a capture from the car.
+//
+// Transmission status from the 7G-Tronic (GS_418h on CAN-C). The first byte is the gear
+// shown in the instrument cluster, as an ASCII character ('P', 'R', 'N', 'D').
+pub const CAN_ID_TRANSMISSION: u32 = 0x418;
+
+// Vehicle speed from the ESP (BS_200h, CAN-C). The first two bytes, big endian, in 0.1km/h.
+pub const CAN_ID_SPEED: u32 = 0x200;
+
+// Brake pedal switch from the ESP (BS_208h, CAN-C). Bit 0 of the first byte.
+pub const CAN_ID_BRAKE: u32 = 0x208;
+
+// Ignition switch status from the EIS (EZS_240h). Bit 0 of the first byte is terminal 15
+// (ignition on). It's on CAN-B, and might not be passed on to CAN-C at all.
+pub const CAN_ID_IGNITION: u32 = 0x240;
+
+// The controller only lets these through.
+pub const CAN_IDS: [u32; 4] = [
+    CAN_ID_TRANSMISSION,
+    CAN_ID_SPEED,
+    CAN_ID_BRAKE,
+    CAN_ID_IGNITION,
+];
+
+// What a frame tells us.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum CanSignal {
+    Gear(Option<Button>), // `None` while it's between gears (or shows something else).
+    Speed(u16),           // 0.1km/h
+    Brake(bool),
+    Ignition(bool), // Terminal 15.
+}
+
+pub fn decode(frame: &CanFrame) -> Option<CanSignal> {
+    match frame.id {
+        CAN_ID_TRANSMISSION if frame.len >= 1 => Some(CanSignal::Gear(match frame.data[0] {
+            b'P' => Some(Button::P),
+            b'R' => Some(Button::R),
+            b'N' => Some(Button::N),
+            b'D' => Some(Button::D),
+            _ => None,
+        })),
+        CAN_ID_SPEED if frame.len >= 2 => Some(CanSignal::Speed(u16::from_be_bytes([
+            frame.data[0],
+            frame.data[1],
+        ]))),
+        CAN_ID_BRAKE if frame.len >= 1 => Some(CanSignal::Brake(frame.data[0] & 0x01 != 0)),
+        CAN_ID_IGNITION if frame.len >= 1 => Some(CanSignal::Ignition(frame.data[0] & 0x01 != 0)),
+        _ => None,
+    }
+}
+
+// ==========
+// MCP2518FD receive objects, without a time stamp and with an 8 byte payload (the FIFO is
+// set up that way, see `lib_can_bus`). Two little endian words of header, then the data.
+
+pub const RX_OBJECT_SIZE: usize = 16;
+
+const RXOBJ_SID_MASK: u32 = 0x7FF; // Word 0, bits 10:0.
+const RXOBJ_EID_SHIFT: u32 = 11; // Word 0, bits 28:11.
+const RXOBJ_EID_MASK: u32 = 0x3FFFF;
+const RXOBJ_DLC_MASK: u32 = 0x0F; // Word 1, bits 3:0.
+const RXOBJ_IDE: u32 = 1 << 4; // Word 1, extended ID.
+const RXOBJ_RTR: u32 = 1 << 5; // Word 1, remote frame.
+
+pub fn parse_rx_object(obj: &[u8; RX_OBJECT_SIZE]) -> CanFrame {
+    let word0 = u32::from_le_bytes([obj[0], obj[1], obj[2], obj[3]]);
+    let word1 = u32::from_le_bytes([obj[4], obj[5], obj[6], obj[7]]);
+
+    let sid = word0 & RXOBJ_SID_MASK;
+    let id = if word1 & RXOBJ_IDE != 0 {
+        // The base ID is the top 11 bits of an extended one.
+        (sid << 18) | ((word0 >> RXOBJ_EID_SHIFT) & RXOBJ_EID_MASK)
+    } else {
+        sid
+    };
+
+    // A remote frame asks for data, it doesn't have any. Anything over 8 is CAN FD, and
+    // wouldn't fit in the object anyway - the car doesn't send any of those.
+    let len = if word1 & RXOBJ_RTR != 0 {
+        0
+    } else {
+        ((word1 & RXOBJ_DLC_MASK) as u8).min(8)
+    };
+
+    let mut data = [0u8; 8];
+    data[..len as usize].copy_from_slice(&obj[8..8 + len as usize]);
+
+    CanFrame { id, len, data }
+}
+
+// The nominal bit time register (C1NBTCFG) for the bitrate. As few prescaler steps as
+// possible, with the sample point at 80% and the largest resync jump allowed.
+pub fn nominal_bit_timing(clock_hz: u32, bitrate: u32) -> u32 {
+    // At most 160 time quanta per bit, well within what TSEG1 (256) and TSEG2 (128) take.
+    let brp = (clock_hz / bitrate).div_ceil(160);
+    let tq = clock_hz / bitrate / brp;
+    let tseg2 = tq / 5;
+    let tseg1 = tq - 1 - tseg2; // Less the sync segment.
+
+    ((brp - 1) << 24) | ((tseg1 - 1) << 16) | ((tseg2 - 1) << 8) | (tseg2 - 1)
+}
@@ -1,10 +1,11 @@
-use defmt::{debug, error, trace, Format};
+use defmt::{debug, error, info, trace, Format};
 
 use embassy_rp::{
     flash::{Blocking, Error, Flash, ERASE_SIZE},
     peripherals::FLASH,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_time::Timer;
 
 // External "defines".
 use crate::lib_resources::{PeriFlash, ADDR_OFFSET, FLASH_SIZE};
@@ -16,16 +17,241 @@ pub type FlashMutex = Mutex<CriticalSectionRawMutex, FlashType>;
 use static_cell::StaticCell;
 pub static FLASH: StaticCell<FlashMutex> = StaticCell::new();
 
+// Marks the calibration as written, by the actuator in use. Both an erased (0xFF) and a
+// `prepare-flash`'ed (0x00) flash reads back as "not calibrated". A calibration done with
+// one actuator is useless with another, so each have their own.
+#[cfg(feature = "actuator-linear")]
+const CALIBRATION_MAGIC: u8 = 0xCA;
+#[cfg(feature = "actuator-dc-encoder")]
+const CALIBRATION_MAGIC: u8 = 0xCB;
+#[cfg(feature = "actuator-stepper")]
+const CALIBRATION_MAGIC: u8 = 0xCC;
+
+// How often to check if the flash writes can go ahead.
+const FLASH_HOLD_MS: u64 = 50;
+
+// Size of the config record in flash.
+const CONFIG_SIZE: usize = 39;
+
+// Marks the test plan as written. Without it, `test-actuator` runs the default plan.
+const TEST_PLAN_MAGIC: u8 = 0x7E;
+
+// Shutting down without a CAN-bus to tell that the ignition is off is opt-in. Neither an
+// erased nor a `prepare-flash`'ed flash can turn it on by accident.
+const SHUTDOWN_WITHOUT_CAN_MAGIC: u8 = 0xA5;
+
+// How many sequences a test plan can have.
+pub const TEST_PLAN_STEPS: usize = 4;
+
+// Learned actuator positions, in the actuators own unit (Ω of the linear actuator pot).
+#[derive(Copy, Clone, Format)]
+pub struct Calibration {
+    pub valid: bool,
+    pub gears: [u16; 4], // Indexed by `Button::from()`.
+    pub end_min: u16,
+    pub end_max: u16,
+    pub tolerance: u16,
+}
+
+impl Calibration {
+    // Nothing calibrated. What to do instead is up to the actuator, see `uncalibrated()`.
+    pub const NONE: Self = Self {
+        valid: false,
+        gears: [0; 4],
+        end_min: 0,
+        end_max: 0,
+        tolerance: 0,
+    };
+
+    // Spread the gears out evenly between the end stops, two `one_mm` either side allowed.
+    pub fn spread(end_min: u16, end_max: u16, one_mm: u16) -> Self {
+        let step = (end_max - end_min) / 5;
+
+        Self {
+            valid: false,
+            gears: [
+                end_min + step,
+                end_min + step * 2,
+                end_min + step * 3,
+                end_min + step * 4,
+            ],
+            end_min,
+            end_max,
+            tolerance: one_mm * 2,
+        }
+    }
+
+    pub fn position(&self, button: Button) -> u16 {
+        self.gears[Button::from(button) as usize]
+    }
+
+    // Is the `position` within the tolerance window of the `button` gear?
+    pub fn in_window(&self, button: Button, position: u16) -> bool {
+        self.position(button).abs_diff(position) <= self.tolerance
+    }
+
+    // The gear whose window the `position` is in, if any.
+    pub fn gear_at(&self, position: u16) -> Option<Button> {
+        Button::iterator().find(|button| self.in_window(*button, position))
+    }
+}
+
+// What gear to go to when we start.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum BootGear {
+    RestoreLast = 0,        // The gear we were in when we stopped (the flash).
+    Park = 1,               // Always (P)ark.
+    FollowTransmission = 2, // Whatever the transmission is in, if it says.
+}
+
+impl BootGear {
+    // Anything unknown, including an erased flash, is the old behaviour.
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            1 => Self::Park,
+            2 => Self::FollowTransmission,
+            _ => Self::RestoreLast,
+        }
+    }
+}
+
+// What to do when the actuator (or the CAN-bus) fails.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum FailurePolicy {
+    LimpHome = 0, // Keep going, with only (P)ark and (N)eutral.
+    Reset = 1,    // Reset, and hope it comes back.
+}
+
+impl FailurePolicy {
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            1 => Self::Reset,
+            _ => Self::LimpHome,
+        }
+    }
+}
+
+// A sequence of moves for `test-actuator`. Don't renumber these, they're stored in the flash.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum TestSequence {
+    Skip = 0,      // Nothing, an unused step of the plan.
+    Sweep = 1,     // End to end (3mm from the end stops), and back.
+    GearCycle = 2, // P, R, N, D.
+    Random = 3,    // Anywhere in the throw.
+}
+
+impl TestSequence {
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            1 => Self::Sweep,
+            2 => Self::GearCycle,
+            3 => Self::Random,
+            _ => Self::Skip,
+        }
+    }
+}
+
+// What `test-actuator` runs, in order, and how many iterations of each. The random targets
+// are always the same for the same seed, so a failing run can be repeated.
+#[derive(Copy, Clone, Format)]
+pub struct TestPlan {
+    pub steps: [(TestSequence, u16); TEST_PLAN_STEPS],
+    pub seed: u32,
+}
+
+pub const DEFAULT_TEST_PLAN: TestPlan = TestPlan {
+    steps: [
+        (TestSequence::Sweep, 10),
+        (TestSequence::GearCycle, 25),
+        (TestSequence::Random, 100),
+        (TestSequence::Skip, 0),
+    ],
+    seed: 0x2010_0171,
+};
+
+// How long to keep going on the UPS battery, before shutting down, when nothing is set.
+pub const DEFAULT_SHUTDOWN_HOLD_SECS: u8 = 60;
+
 // What we store in flash.
 #[derive(Format)]
 pub struct DbwConfig {
     pub active_button: Button,
     pub valet_mode: bool,
+    pub calibration: Calibration,
+    pub boot_gear: BootGear,
+    pub failure_policy: FailurePolicy,
+    pub shutdown_hold_secs: u8,
+    pub park_on_shutdown: bool,
+    pub test_plan: TestPlan,
+    pub shutdown_without_can: bool,
 }
 
 impl DbwConfig {
-    fn as_array(&self) -> [u8; 2] {
-        [self.active_button as u8, self.valet_mode as u8]
+    fn as_array(&self) -> [u8; CONFIG_SIZE] {
+        let mut buf = [0u8; CONFIG_SIZE];
+        let cal = &self.calibration;
+
+        buf[0] = self.active_button as u8;
+        buf[1] = self.valet_mode as u8;
+        buf[2] = if cal.valid { CALIBRATION_MAGIC } else { 0x00 };
+        for (i, v) in cal
+            .gears
+            .iter()
+            .chain([cal.end_min, cal.end_max, cal.tolerance].iter())
+            .enumerate()
+        {
+            buf[3 + i * 2..5 + i * 2].copy_from_slice(&v.to_le_bytes());
+        }
+        buf[17] = self.boot_gear as u8;
+        buf[18] = self.failure_policy as u8;
+        buf[19] = self.shutdown_hold_secs;
+        buf[20] = if self.park_on_shutdown { 0 } else { 1 }; // Erased/prepared => park.
+        buf[21] = TEST_PLAN_MAGIC;
+        for (i, (sequence, iterations)) in self.test_plan.steps.iter().enumerate() {
+            buf[22 + i * 3] = *sequence as u8;
+            buf[23 + i * 3..25 + i * 3].copy_from_slice(&iterations.to_le_bytes());
+        }
+        buf[34..38].copy_from_slice(&self.test_plan.seed.to_le_bytes());
+        buf[38] = if self.shutdown_without_can {
+            SHUTDOWN_WITHOUT_CAN_MAGIC
+        } else {
+            0
+        };
+
+        buf
+    }
+
+    fn test_plan_from_array(buf: &[u8]) -> TestPlan {
+        if buf[21] != TEST_PLAN_MAGIC {
+            return DEFAULT_TEST_PLAN;
+        }
+
+        let mut plan = DEFAULT_TEST_PLAN;
+        for (i, step) in plan.steps.iter_mut().enumerate() {
+            *step = (
+                TestSequence::from_integer(buf[22 + i * 3]),
+                u16::from_le_bytes([buf[23 + i * 3], buf[24 + i * 3]]),
+            );
+        }
+        plan.seed = u32::from_le_bytes([buf[34], buf[35], buf[36], buf[37]]);
+
+        plan
+    }
+
+    fn calibration_from_array(buf: &[u8]) -> Calibration {
+        let word = |i: usize| u16::from_le_bytes([buf[3 + i * 2], buf[4 + i * 2]]);
+
+        if buf[2] != CALIBRATION_MAGIC {
+            return Calibration::NONE;
+        }
+
+        Calibration {
+            valid: true,
+            gears: [word(0), word(1), word(2), word(3)],
+            end_min: word(4),
+            end_max: word(5),
+            tolerance: word(6),
+        }
     }
 
     pub fn read(flash: &mut FlashType) -> Result<DbwConfig, Error> {
@@ -44,6 +270,16 @@ impl DbwConfig {
                 Ok(DbwConfig {
                     active_button,
                     valet_mode,
+                    calibration: Self::calibration_from_array(&read_buf),
+                    boot_gear: BootGear::from_integer(read_buf[17]),
+                    failure_policy: FailurePolicy::from_integer(read_buf[18]),
+                    shutdown_hold_secs: match read_buf[19] {
+                        0 | 0xFF => DEFAULT_SHUTDOWN_HOLD_SECS,
+                        secs => secs,
+                    },
+                    park_on_shutdown: read_buf[20] != 1,
+                    test_plan: Self::test_plan_from_array(&read_buf),
+                    shutdown_without_can: read_buf[38] == SHUTDOWN_WITHOUT_CAN_MAGIC,
                 })
             }
             Err(e) => {
@@ -57,7 +293,7 @@ impl DbwConfig {
 
     pub fn write(flash: &mut FlashType, config: Self) -> Result<(), Error> {
         // Convert our struct to an array, so we can loop through it easier.
-        let buf: [u8; 2] = config.as_array();
+        let buf: [u8; CONFIG_SIZE] = config.as_array();
 
         for (j, b) in buf.into_iter().enumerate() {
             match flash.blocking_write(ADDR_OFFSET + ERASE_SIZE as u32 + j as u32, &[b] as &[u8]) {
@@ -73,9 +309,33 @@ impl DbwConfig {
     }
 }
 
+// Whatever the flash writes have to wait for, set by the firmware. The flash tools have
+// nothing else going on, so nothing by default.
+static mut FLASH_HOLD: fn() -> bool = nothing_pending;
+
+fn nothing_pending() -> bool {
+    false
+}
+
+pub fn set_flash_hold(hold: fn() -> bool) {
+    unsafe {
+        FLASH_HOLD = hold;
+    }
+}
+
+// Wait until the flash can be erased or written, see `set_flash_hold`.
+pub async fn flash_ready() {
+    while unsafe { FLASH_HOLD() } {
+        Timer::after_millis(FLASH_HOLD_MS).await;
+    }
+}
+
 pub async fn write_flash(flash: &mut FlashType, buf: DbwConfig) {
     trace!("write_flash({:?})", buf);
 
+    // Not in the middle of a move.
+    flash_ready().await;
+
     match DbwConfig::read(flash) {
         Ok(v) => debug!("Config (before write): {:?}", v),
         Err(e) => error!("Failed to read (before write): {:?}", e),
@@ -100,10 +360,57 @@ pub async fn write_flash(flash: &mut FlashType, buf: DbwConfig) {
     }
 }
 
+// A change to the config, for `config_writer` to write to the flash.
+#[derive(Copy, Clone, Format)]
+pub enum ConfigUpdate {
+    ActiveButton(Button),
+    Calibration(Calibration),
+}
+
+pub static CHANNEL_CONFIG: Channel<CriticalSectionRawMutex, ConfigUpdate, 8> = Channel::new();
+
+// Write the config changes to the flash, so whoever made the change doesn't have to wait for
+// it. Erasing and writing the flash stalls both cores for tens of milliseconds, no matter
+// what task does it, so the writes wait for the actuator to stand still - see `set_flash_hold`.
+#[embassy_executor::task]
+pub async fn config_writer(flash: &'static FlashMutex) {
+    info!("Started config writer task");
+
+    loop {
+        let update = CHANNEL_CONFIG.receive().await; // Block waiting for data.
+        debug!("Config update: {:?}", update);
+
+        // Read the existing values from the flash, update them and write them back.
+        // The flash lock is released when it goes out of scope.
+        let mut flash = flash.lock().await;
+        let mut config = match DbwConfig::read(&mut flash) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to read flash: {:?}", e);
+                resonable_defaults()
+            }
+        };
+
+        match update {
+            ConfigUpdate::ActiveButton(button) => config.active_button = button,
+            ConfigUpdate::Calibration(calibration) => config.calibration = calibration,
+        }
+
+        write_flash(&mut flash, config).await;
+    }
+}
+
 pub fn resonable_defaults() -> DbwConfig {
     DbwConfig {
         active_button: Button::P,
         valet_mode: false,
+        calibration: Calibration::NONE,
+        boot_gear: BootGear::RestoreLast,
+        failure_policy: FailurePolicy::LimpHome,
+        shutdown_hold_secs: DEFAULT_SHUTDOWN_HOLD_SECS,
+        park_on_shutdown: true,
+        test_plan: DEFAULT_TEST_PLAN,
+        shutdown_without_can: false,
     }
 }
 
@@ -3,7 +3,8 @@ use defmt::{info, unwrap};
 use embassy_executor::Spawner;
 
 use crate::lib_can_bus::can_manager;
-use crate::lib_resources::{PeriCan, PeriPowerMonitor, PeriWatchdog};
+use crate::lib_i2c::I2cBus;
+use crate::lib_resources::{PeriCan, PeriWatchdog};
 use crate::lib_ups::ups_monitor;
 use crate::lib_watchdog::feed_watchdog;
 
@@ -11,7 +12,7 @@ use crate::lib_watchdog::feed_watchdog;
 pub async fn core1_tasks(
     spawner: Spawner,
     watchdog: PeriWatchdog,
-    ups: PeriPowerMonitor,
+    i2c: &'static I2cBus,
     can: PeriCan,
 ) {
     info!("Spawning tasks on CORE1");
@@ -20,6 +21,6 @@ pub async fn core1_tasks(
     {
         spawner.spawn(unwrap!(feed_watchdog(watchdog)));	// Spawn Watchdog.
         spawner.spawn(unwrap!(can_manager(spawner, can)));	// Spawn the CAN manager.
-        spawner.spawn(unwrap!(ups_monitor(ups)));		// Spawn the UPS monitor.
+        spawner.spawn(unwrap!(ups_monitor(i2c)));		// Spawn the UPS monitor.
     }
 }
@@ -0,0 +1,276 @@
+use defmt::{debug, error, info};
+
+use embassy_executor::SendSpawner;
+use embassy_rp::{
+    adc::InterruptHandler,
+    gpio::Pull,
+    interrupt::typelevel::{Binding, ADC_IRQ_FIFO},
+    pwm::{Config as PwmConfig, InputMode, Pwm},
+};
+use embassy_time::{Duration, Instant, Ticker};
+
+// External "defines".
+use crate::lib_gear_actuator::{
+    closed_loop_move, Blind, ClosedLoop, GearActuator, HBridge, MoveError, MoveReport, Moving,
+    PositionSample, Protection,
+};
+use crate::lib_motion::MotionConfig;
+use crate::lib_motor_current::motor_current;
+use crate::lib_resources::PeriActuator;
+
+// Geared DC motor with a single channel (hall) encoder on the motor shaft, driving the lever
+// through a lead screw. 11 pulses/rev x 30:1 gearbox, 16.5mm/rev => 20 pulses/mm.
+const PULSES_PER_MM: u16 = 20;
+const TRAVEL_MM: u16 = 60;
+
+// The encoder only counts from where we started, so the position is zeroed against the
+// retracted end stop. Keep clear of it afterwards.
+const HOME_DUTY: f32 = -0.3;
+const HOME_STILL_MS: u64 = 200;
+const HOME_TIMEOUT_MS: u64 = 8_000;
+
+// Open loop, at this duty, the actuator moves at least this fast. Tune it on the bench, it's
+// better to stop a little short of (N)eutral than to go past it into (D)rive.
+const BLIND_DUTY: f32 = 0.5;
+const BLIND_MM_PER_S: u64 = 10;
+
+// A starting point, scaled from the linear actuator. Tune it on the bench.
+pub const DC_MOTION: MotionConfig = MotionConfig {
+    kp: 0.032,
+    ki: 0.0,
+    kd: 0.0012,
+    full_speed: 500.0,
+    max_velocity: 400.0,
+    acceleration: 1_000.0,
+    max_duty: 1.0,
+    min_duty: 0.2,
+    slew: 15.0,
+    deadband: 2,
+    period_ms: 5,
+    timeout_ms: 1_500,
+};
+
+pub const DC_PROTECTION: Protection = Protection {
+    stall_duty: 0.3,
+    stall_distance: 3,
+    stall_window_ms: 200,
+    max_current_ma: 4_000,
+    inrush_ms: 50,
+};
+
+// DC motor, driven by PWM through the same H-bridge as the linear actuator. The encoder is
+// on `encoder_pin` (GP5, PWM2/B), where the PWM slice counts the pulses in hardware - only
+// the B channel of a slice can be an input. There's only
+// one channel, so the direction is taken from the way we're driving the motor.
+pub struct DcActuator<'d> {
+    motor: HBridge<'d>,
+    encoder: Pwm<'d>,
+    count: u16,     // Last encoder counter value.
+    direction: i32, // Direction of the last drive.
+    position: i32,  // Pulses from the retracted end stop.
+    homed: bool,
+    pub motion: MotionConfig,
+    pub protection: Protection,
+}
+
+impl<'d> DcActuator<'d> {
+    // The position comes from the encoder, there's no sampling task to spawn (or ADC to use).
+    pub fn new(
+        r: PeriActuator,
+        _irqs: impl Binding<ADC_IRQ_FIFO, InterruptHandler>,
+        _spawner: SendSpawner,
+    ) -> Self {
+        let mut encoder_config = PwmConfig::default();
+        encoder_config.top = u16::MAX;
+
+        Self {
+            motor: HBridge::new(r.pwm, r.mplus, r.mminus),
+            encoder: Pwm::new_input(
+                r.encoder,
+                r.encoder_pin,
+                Pull::Up,
+                InputMode::RisingEdge,
+                encoder_config,
+            ),
+            count: 0,
+            direction: 0,
+            position: 0,
+            homed: false,
+            motion: DC_MOTION,
+            protection: DC_PROTECTION,
+        }
+    }
+
+    // Add up the pulses since the last time. The counter wraps, but we read it far more often
+    // than that.
+    fn update_position(&mut self) {
+        let count = self.encoder.counter();
+        let pulses = count.wrapping_sub(self.count) as i32;
+        self.count = count;
+        self.position += pulses * self.direction;
+    }
+
+    fn drive(&mut self, duty: f32) {
+        // Update with the old direction first, the pulses so far are from that.
+        self.update_position();
+        if duty > 0.0 {
+            self.direction = 1;
+        } else if duty < 0.0 {
+            self.direction = -1;
+        }
+        self.motor.drive(duty);
+    }
+}
+
+impl ClosedLoop for DcActuator<'_> {
+    const UNIT: &'static str = "";
+
+    // The counter is read right there, so it's as fresh as it gets. The pulses don't have
+    // any noise to speak of.
+    async fn sample(&mut self) -> PositionSample {
+        PositionSample {
+            position: self.read_position().await,
+            noise: None,
+            at: Instant::now(),
+        }
+    }
+
+    fn drive_motor(&mut self, duty: f32) {
+        self.drive(duty);
+    }
+
+    fn stop_motor(&mut self) {
+        self.motor.stop();
+    }
+}
+
+impl GearActuator for DcActuator<'_> {
+    const NAME: &'static str = "DC motor";
+
+    const POSITION_MIN: u16 = PULSES_PER_MM * 2;
+    const POSITION_MAX: u16 = PULSES_PER_MM * (TRAVEL_MM - 2);
+    const POSITION_1MM: u16 = PULSES_PER_MM;
+
+    // Run into the retracted end stop (the (P)ark end), and call that zero.
+    async fn home(&mut self) -> Result<(), MoveError> {
+        if self.homed {
+            return Ok(());
+        }
+        info!("Homing the DC actuator");
+        let _moving = Moving::start();
+
+        let started = Instant::now();
+        let mut still_since = Instant::now();
+        let mut last = self.encoder.counter();
+
+        self.drive(HOME_DUTY);
+        let mut ticker = Ticker::every(Duration::from_millis(self.motion.period_ms));
+        let result = loop {
+            ticker.next().await;
+
+            // Against the end stop, the motor draws a lot but doesn't go anywhere. That's fine
+            // for a short while, just don't cook it.
+            if let Some(current) = motor_current() {
+                if started.elapsed().as_millis() > self.protection.inrush_ms
+                    && current > self.protection.max_current_ma
+                {
+                    break Ok(());
+                }
+            }
+
+            let count = self.encoder.counter();
+            if count != last {
+                last = count;
+                still_since = Instant::now();
+            } else if still_since.elapsed().as_millis() > HOME_STILL_MS {
+                break Ok(());
+            }
+
+            if started.elapsed().as_millis() > HOME_TIMEOUT_MS {
+                error!("DC actuator never reached the end stop");
+                break Err(MoveError::Timeout { position: 0 });
+            }
+        };
+        self.motor.stop();
+        result?;
+
+        self.update_position();
+        self.position = 0;
+        self.homed = true;
+        debug!("DC actuator homed");
+
+        Ok(())
+    }
+
+    // Read the actuator position, in encoder pulses from the retracted end stop.
+    async fn read_position(&mut self) -> u16 {
+        self.update_position();
+        self.position.clamp(0, u16::MAX as i32) as u16
+    }
+
+    async fn move_to(&mut self, target: u16) -> Result<MoveReport, MoveError> {
+        self.home().await?;
+
+        let target = target.clamp(Self::POSITION_MIN, Self::POSITION_MAX);
+        let result = closed_loop_move(self, self.motion, self.protection, target).await;
+
+        // A stall or over-current might just as well be a skipped pulse or two, find the end
+        // stop again before the next move. Being pre-empted is just an early stop.
+        if result.is_err() && !matches!(result, Err(MoveError::Preempted { .. })) {
+            self.homed = false;
+        }
+
+        result
+    }
+
+    // Timed, at `BLIND_DUTY`. Running into the retracted end stop is an over-current, same as
+    // when homing.
+    async fn drive_blind(&mut self, extend: bool, mm: u16) -> Result<Blind, MoveError> {
+        let ms = mm as u64 * 1_000 / BLIND_MM_PER_S;
+        let duty = if extend { BLIND_DUTY } else { -BLIND_DUTY };
+        debug!("Driving actuator blind, duty {} for {}ms", duty, ms);
+        let _moving = Moving::start();
+
+        let started = Instant::now();
+        let mut ticker = Ticker::every(Duration::from_millis(self.motion.period_ms));
+        self.drive(duty);
+        let result = loop {
+            ticker.next().await;
+            if started.elapsed().as_millis() > ms {
+                break Ok(Blind::Done);
+            }
+
+            if let Some(current) = motor_current() {
+                if started.elapsed().as_millis() > self.protection.inrush_ms
+                    && current > self.protection.max_current_ma
+                {
+                    if !extend {
+                        break Ok(Blind::EndStop);
+                    }
+                    break Err(MoveError::OverCurrent {
+                        position: self.read_position().await,
+                        current_ma: current,
+                    });
+                }
+            }
+        };
+        self.motor.stop();
+
+        // Whatever the encoder says now, it's not to be trusted.
+        self.homed = false;
+
+        result
+    }
+
+    fn stop(&mut self) {
+        self.motor.stop();
+    }
+
+    fn deadband(&self) -> u16 {
+        self.motion.deadband
+    }
+
+    fn set_deadband(&mut self, deadband: u16) {
+        self.motion.deadband = deadband;
+    }
+}
@@ -0,0 +1,289 @@
+use defmt::{debug, error, info, trace, warn, Format};
+
+use embassy_rp::flash::{Error, ERASE_SIZE};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+
+// External "defines".
+use crate::lib_config::{flash_ready, FlashMutex, FlashType};
+use crate::lib_resources::ADDR_OFFSET;
+use crate::Button;
+
+// The events are stored in their own flash sectors, right after the config. They're used in
+// turn, when one is full the oldest is erased and the log carries on there. That way there's
+// always at least a full sector of history left.
+pub const EVENTLOG_ADDR: u32 = ADDR_OFFSET + 2 * ERASE_SIZE as u32;
+pub const EVENTLOG_SIZE: u32 = (EVENTLOG_SECTORS * ERASE_SIZE) as u32;
+const EVENTLOG_SECTORS: usize = 2;
+const EVENT_SIZE: usize = 8;
+
+// The first slot of each sector says which turn it was started on, the rest are events.
+const EVENT_SLOTS: usize = ERASE_SIZE / EVENT_SIZE - 1;
+const SECTOR_MAGIC: u8 = 0xEC;
+
+// Don't renumber these, they're stored in the flash.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum EventKind {
+    Boot = 1,
+    GearChanged = 2,
+    GearChangeFailed = 3,
+    ActuatorFailed = 4,
+    PositionDrift = 5,
+    DriftCorrected = 6,
+    UnexpectedMovement = 7,
+    LimpHome = 8,
+    BootGearMismatch = 9,
+    PowerLost = 10,
+    PowerRestored = 11,
+    Shutdown = 12,
+    SupplyFault = 13,
+    SupplyMin = 14,         // mV, during an actuator move where the supply sagged.
+    SupplyMax = 15,         // mV, during an actuator move where the supply sagged.
+    SupplyPeakCurrent = 16, // mA, during an actuator move where the supply sagged.
+    ResetReason = 17,       // `ResetReason`, right after `Boot`.
+    TaskHung = 18,          // `Task`, the one the watchdog reset us for.
+    Panic = 19,             // Line number, the file and message are only in the log.
+    BootStage = 20,         // `BootStage`, the last one before the reset.
+}
+
+impl EventKind {
+    pub fn from_integer(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Boot),
+            2 => Some(Self::GearChanged),
+            3 => Some(Self::GearChangeFailed),
+            4 => Some(Self::ActuatorFailed),
+            5 => Some(Self::PositionDrift),
+            6 => Some(Self::DriftCorrected),
+            7 => Some(Self::UnexpectedMovement),
+            8 => Some(Self::LimpHome),
+            9 => Some(Self::BootGearMismatch),
+            10 => Some(Self::PowerLost),
+            11 => Some(Self::PowerRestored),
+            12 => Some(Self::Shutdown),
+            13 => Some(Self::SupplyFault),
+            14 => Some(Self::SupplyMin),
+            15 => Some(Self::SupplyMax),
+            16 => Some(Self::SupplyPeakCurrent),
+            17 => Some(Self::ResetReason),
+            18 => Some(Self::TaskHung),
+            19 => Some(Self::Panic),
+            20 => Some(Self::BootStage),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct Event {
+    pub uptime_ms: u32, // Since the last `Boot` event.
+    pub kind: EventKind,
+    pub gear: Button,
+    pub value: u16, // Depends on the event, mostly the actuator position in Ω.
+}
+
+impl Event {
+    fn as_array(&self) -> [u8; EVENT_SIZE] {
+        let mut buf = [0u8; EVENT_SIZE];
+
+        buf[0] = self.kind as u8;
+        buf[1] = self.gear as u8;
+        buf[2..4].copy_from_slice(&self.value.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.uptime_ms.to_le_bytes());
+
+        buf
+    }
+
+    fn from_array(buf: &[u8]) -> Option<Self> {
+        Some(Event {
+            kind: EventKind::from_integer(buf[0])?,
+            gear: Button::from_integer(buf[1] & 0x03),
+            value: u16::from_le_bytes([buf[2], buf[3]]),
+            uptime_ms: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        })
+    }
+}
+
+pub static CHANNEL_EVENTS: Channel<CriticalSectionRawMutex, Event, 16> = Channel::new();
+
+// Record an event. This never blocks, if the logger can't keep up the event is dropped.
+pub fn log_event(kind: EventKind, gear: Button, value: u16) {
+    let event = Event {
+        uptime_ms: Instant::now().as_millis() as u32,
+        kind,
+        gear,
+        value,
+    };
+    debug!("Event: {:?}", event);
+
+    if CHANNEL_EVENTS.try_send(event).is_err() {
+        warn!("Event log queue full, dropped {:?}", event);
+    }
+}
+
+// Where in the log the next event goes.
+#[derive(Copy, Clone, Format)]
+struct LogPosition {
+    sector: usize,
+    turn: u32,
+    slot: usize,
+}
+
+// As if the last sector was full, so that the first event starts the first sector.
+const NOTHING_LOGGED: LogPosition = LogPosition {
+    sector: EVENTLOG_SECTORS - 1,
+    turn: 0,
+    slot: EVENT_SLOTS,
+};
+
+fn sector_addr(sector: usize) -> u32 {
+    EVENTLOG_ADDR + (sector * ERASE_SIZE) as u32
+}
+
+fn slot_addr(sector: usize, slot: usize) -> u32 {
+    sector_addr(sector) + ((slot + 1) * EVENT_SIZE) as u32
+}
+
+// The turn the sector was started on, if it have been started since it was erased.
+fn sector_turn(flash: &mut FlashType, sector: usize) -> Result<Option<u32>, Error> {
+    let mut buf = [0u8; EVENT_SIZE];
+    flash.blocking_read(sector_addr(sector), &mut buf)?;
+
+    Ok((buf[0] == SECTOR_MAGIC).then(|| u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])))
+}
+
+// The sectors that are in use, oldest first.
+fn sectors_in_order(
+    flash: &mut FlashType,
+) -> Result<[Option<(usize, u32)>; EVENTLOG_SECTORS], Error> {
+    let mut sectors = [None; EVENTLOG_SECTORS];
+    for (sector, entry) in sectors.iter_mut().enumerate() {
+        *entry = sector_turn(flash, sector)?.map(|turn| (sector, turn));
+    }
+
+    // Unused ones last.
+    sectors.sort_unstable_by_key(|entry| entry.map_or(u32::MAX, |(_, turn)| turn));
+    Ok(sectors)
+}
+
+// Find the first unused slot in the newest sector.
+fn next_slot(flash: &mut FlashType) -> Result<LogPosition, Error> {
+    let Some((sector, turn)) = sectors_in_order(flash)?
+        .iter()
+        .rev()
+        .find_map(|entry| *entry)
+    else {
+        return Ok(NOTHING_LOGGED);
+    };
+
+    let mut buf = [0u8; ERASE_SIZE];
+    flash.blocking_read(sector_addr(sector), &mut buf)?;
+
+    let slot = buf
+        .chunks(EVENT_SIZE)
+        .skip(1)
+        .position(|slot| slot[0] == 0xFF)
+        .unwrap_or(EVENT_SLOTS);
+
+    Ok(LogPosition { sector, turn, slot })
+}
+
+// Erase the oldest sector, and carry on there.
+fn start_sector(flash: &mut FlashType, position: &LogPosition) -> Result<LogPosition, Error> {
+    let next = LogPosition {
+        sector: (position.sector + 1) % EVENTLOG_SECTORS,
+        turn: position.turn + 1,
+        slot: 0,
+    };
+    info!("Event log sector full, erasing sector {}", next.sector);
+
+    let addr = sector_addr(next.sector);
+    flash.blocking_erase(addr, addr + ERASE_SIZE as u32)?;
+
+    let mut header = [0xFFu8; EVENT_SIZE];
+    header[0] = SECTOR_MAGIC;
+    header[4..8].copy_from_slice(&next.turn.to_le_bytes());
+    flash.blocking_write(addr, &header)?;
+
+    Ok(next)
+}
+
+// Print all the events stored in the flash, oldest first.
+pub fn dump_events(flash: &mut FlashType) {
+    let sectors = match sectors_in_order(flash) {
+        Ok(sectors) => sectors,
+        Err(e) => {
+            error!("Failed to read the event log: {:?}", e);
+            return;
+        }
+    };
+
+    let mut buf = [0u8; ERASE_SIZE];
+    for (sector, turn) in sectors.iter().flatten() {
+        if let Err(e) = flash.blocking_read(sector_addr(*sector), &mut buf) {
+            error!("Failed to read the event log: {:?}", e);
+            return;
+        }
+        debug!("Event log sector {}, turn {}", sector, turn);
+
+        for slot in buf.chunks(EVENT_SIZE).skip(1) {
+            if slot[0] == 0xFF {
+                break;
+            }
+            match Event::from_array(slot) {
+                Some(event) => info!("{:?}", event),
+                None => warn!("Unknown event: {=[u8]}", slot),
+            }
+        }
+    }
+}
+
+// Write the events to the flash, as they come in.
+#[embassy_executor::task]
+pub async fn event_logger(flash: &'static FlashMutex) {
+    info!("Started event logger task");
+
+    let mut position = {
+        // The flash lock is released when it goes out of scope.
+        let mut flash = flash.lock().await;
+        match next_slot(&mut flash) {
+            Ok(position) => position,
+            Err(e) => {
+                error!("Failed to read the event log: {:?}", e);
+                NOTHING_LOGGED
+            }
+        }
+    };
+    debug!(
+        "Event log: {:?}, {} slots per sector",
+        position, EVENT_SLOTS
+    );
+
+    loop {
+        let event = CHANNEL_EVENTS.receive().await; // Block waiting for data.
+
+        // The flash lock is released when it goes out of scope.
+        let mut flash = flash.lock().await;
+
+        // Not in the middle of a move. The events queue up in the meantime.
+        flash_ready().await;
+
+        // When the sector is full, move on to the next. Only the oldest events are lost.
+        if position.slot >= EVENT_SLOTS {
+            match start_sector(&mut flash, &position) {
+                Ok(next) => position = next,
+                Err(e) => {
+                    error!("Event log erase failed: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        match flash.blocking_write(slot_addr(position.sector, position.slot), &event.as_array()) {
+            Ok(_) => trace!("Event written to {:?}", position),
+            Err(e) => error!("Event write failed: {}", e),
+        }
+        position.slot += 1;
+    }
+}
@@ -0,0 +1,220 @@
+use defmt::{debug, error, info, Format};
+
+use embassy_futures::select::{select, Either};
+use embassy_rp::gpio::{Input, Level, Pull};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    signal::Signal,
+    watch::{Receiver as WatchReceiver, Watch},
+};
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c as _;
+use portable_atomic::{AtomicU16, Ordering};
+
+// External "defines".
+use crate::lib_i2c::{BusDevice, BusError, I2cBus, SharedI2c};
+use crate::lib_resources::{PeriExpander, EXPANDER_ADDRESS, EXPANDER_BUTTONS, EXPANDER_LEDS};
+
+// 16 pin I²C GPIO expander, for the buttons and LEDs that don't fit on the Pico. Pins 0-7 are
+// GPA0-7 (MCP23017) or IO0_0-7 (PCA9555), 8-15 are GPB0-7 or IO1_0-7. The inputs are read
+// when the chip pulls its INT pin low, the outputs are written when they change.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum ExpanderChip {
+    Mcp23017,
+    Pca9555,
+}
+
+// The one that's fitted.
+const CHIP: ExpanderChip = ExpanderChip::Mcp23017;
+
+// MCP23017 registers, with IOCON.BANK=0 (the default) so that A and B are next to each other.
+const MCP_IODIRA: u8 = 0x00; // 1 = input.
+const MCP_GPINTENA: u8 = 0x04; // Interrupt on change.
+const MCP_IOCON: u8 = 0x0A;
+const MCP_GPPUA: u8 = 0x0C; // 100kΩ pull-up.
+const MCP_GPIOA: u8 = 0x12;
+const MCP_OLATA: u8 = 0x14;
+
+// One INT pin for both ports (MIRROR), open drain (ODR) - the Pico pin have the pull-up.
+const MCP_IOCON_MIRROR: u8 = 0x40;
+const MCP_IOCON_ODR: u8 = 0x04;
+
+// PCA9555 registers. The inputs always have a pull-up, and always interrupt on change.
+const PCA_INPUT0: u8 = 0x00;
+const PCA_OUTPUT0: u8 = 0x02;
+const PCA_CONFIG0: u8 = 0x06; // 1 = input.
+
+// If the expander stops answering, try again (and set it up again) after this long.
+const RETRY_MS: u64 = 100;
+
+// The input pins, as last read. Every button has a receiver.
+static WATCH_INPUTS: Watch<CriticalSectionRawMutex, u16, 4> = Watch::new();
+
+// The output pins, as they should be. Wake up the expander task when they change.
+static OUTPUTS: AtomicU16 = AtomicU16::new(0);
+static SIGNAL_OUTPUTS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn mask(pins: &[Option<u8>]) -> u16 {
+    pins.iter().flatten().fold(0, |mask, pin| mask | 1 << pin)
+}
+
+// Is anything on the expander? If not, there's no need for it to be there at all.
+pub fn expander_in_use() -> bool {
+    mask(&EXPANDER_BUTTONS) | mask(&EXPANDER_LEDS) != 0
+}
+
+// A button on the expander. Active low, like the native ones.
+pub struct ExpanderInput {
+    pin: u8,
+    receiver: WatchReceiver<'static, CriticalSectionRawMutex, u16, 4>,
+}
+
+impl ExpanderInput {
+    pub fn new(pin: u8) -> Self {
+        Self {
+            pin,
+            receiver: WATCH_INPUTS
+                .receiver()
+                .expect("One expander input per button"),
+        }
+    }
+
+    fn level_of(&self, inputs: u16) -> Level {
+        if inputs & (1 << self.pin) != 0 {
+            Level::High
+        } else {
+            Level::Low
+        }
+    }
+
+    // Not read yet is the same as not pressed.
+    pub fn get_level(&mut self) -> Level {
+        self.level_of(self.receiver.try_get().unwrap_or(u16::MAX))
+    }
+
+    pub async fn wait_for_any_edge(&mut self) {
+        let level = self.get_level();
+        while self.level_of(self.receiver.changed().await) == level {}
+    }
+}
+
+// A LED on the expander.
+pub struct ExpanderOutput {
+    pin: u8,
+}
+
+impl ExpanderOutput {
+    pub fn new(pin: u8) -> Self {
+        Self { pin }
+    }
+
+    pub fn set_level(&mut self, level: Level) {
+        let bit = 1 << self.pin;
+        match level {
+            Level::High => OUTPUTS.fetch_or(bit, Ordering::SeqCst),
+            Level::Low => OUTPUTS.fetch_and(!bit, Ordering::SeqCst),
+        };
+        SIGNAL_OUTPUTS.signal(());
+    }
+}
+
+async fn write16(i2c: &mut SharedI2c, register: u8, value: u16) -> Result<(), BusError> {
+    let [low, high] = value.to_le_bytes();
+    i2c.write(EXPANDER_ADDRESS, &[register, low, high]).await
+}
+
+async fn read16(i2c: &mut SharedI2c, register: u8) -> Result<u16, BusError> {
+    let mut buf = [0u8; 2];
+    i2c.write_read(EXPANDER_ADDRESS, &[register], &mut buf)
+        .await?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+// Everything that isn't a LED is an input, that's the safe state for a pin.
+async fn configure(i2c: &mut SharedI2c) -> Result<(), BusError> {
+    let buttons = mask(&EXPANDER_BUTTONS);
+    let leds = mask(&EXPANDER_LEDS);
+    let outputs = OUTPUTS.load(Ordering::SeqCst);
+
+    match CHIP {
+        ExpanderChip::Mcp23017 => {
+            i2c.write(
+                EXPANDER_ADDRESS,
+                &[MCP_IOCON, MCP_IOCON_MIRROR | MCP_IOCON_ODR],
+            )
+            .await?;
+            write16(i2c, MCP_OLATA, outputs).await?;
+            write16(i2c, MCP_IODIRA, !leds).await?;
+            write16(i2c, MCP_GPPUA, buttons).await?;
+            write16(i2c, MCP_GPINTENA, buttons).await?;
+        }
+        ExpanderChip::Pca9555 => {
+            write16(i2c, PCA_OUTPUT0, outputs).await?;
+            write16(i2c, PCA_CONFIG0, !leds).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Reading the inputs also clears the interrupt.
+async fn read_inputs(i2c: &mut SharedI2c) -> Result<u16, BusError> {
+    match CHIP {
+        ExpanderChip::Mcp23017 => read16(i2c, MCP_GPIOA).await,
+        ExpanderChip::Pca9555 => read16(i2c, PCA_INPUT0).await,
+    }
+}
+
+async fn write_outputs(i2c: &mut SharedI2c) -> Result<(), BusError> {
+    let outputs = OUTPUTS.load(Ordering::SeqCst);
+    match CHIP {
+        ExpanderChip::Mcp23017 => write16(i2c, MCP_OLATA, outputs).await,
+        ExpanderChip::Pca9555 => write16(i2c, PCA_OUTPUT0, outputs).await,
+    }
+}
+
+#[embassy_executor::task]
+pub async fn expander(bus: &'static I2cBus, r: PeriExpander) {
+    info!("Started GPIO expander task ({})", CHIP);
+
+    let mut i2c = SharedI2c::new(bus, BusDevice::Expander);
+    let mut int = Input::new(r.int, Pull::Up);
+    let sender = WATCH_INPUTS.sender();
+
+    loop {
+        // Set it up, and get the inputs as they are now.
+        if let Err(e) = configure(&mut i2c).await {
+            error!("Failed to set up the GPIO expander: {:?}", e);
+            Timer::after_millis(RETRY_MS).await;
+            continue;
+        }
+        debug!("GPIO expander set up");
+
+        // Until it stops answering.
+        loop {
+            match read_inputs(&mut i2c).await {
+                Ok(inputs) => sender.send_if_modified(|old| {
+                    let changed = *old != Some(inputs);
+                    *old = Some(inputs);
+                    changed
+                }),
+                Err(e) => {
+                    error!("Failed to read the GPIO expander: {:?}", e);
+                    break;
+                }
+            };
+
+            match select(int.wait_for_low(), SIGNAL_OUTPUTS.wait()).await {
+                Either::First(_) => {}
+                Either::Second(_) => {
+                    if let Err(e) = write_outputs(&mut i2c).await {
+                        error!("Failed to write the GPIO expander: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Timer::after_millis(RETRY_MS).await;
+    }
+}
@@ -0,0 +1,38 @@
+use defmt::Format;
+
+// The gears, as the buttons (and the flash, the event log and the CAN-bus) know them. Kept on
+// its own, so the flash tools only need this and the config.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum Button {
+    P,
+    R,
+    N,
+    D,
+}
+
+// https://medium.com/@mikecode/rust-conversion-between-enum-and-integer-0e10e613573c
+impl Button {
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            0 => Self::P,
+            1 => Self::R,
+            2 => Self::N,
+            3 => Self::D,
+            _ => panic!("Unknown value: {}", v),
+        }
+    }
+
+    pub fn from(v: Self) -> u8 {
+        match v {
+            Self::P => 0,
+            Self::R => 1,
+            Self::N => 2,
+            Self::D => 3,
+        }
+    }
+
+    pub fn iterator() -> impl Iterator<Item = Button> {
+        [Self::P, Self::R, Self::N, Self::D].iter().copied()
+    }
+}
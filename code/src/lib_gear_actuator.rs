@@ -0,0 +1,614 @@
+use defmt::{debug, error, info, warn, Format};
+
+use embassy_rp::{
+    peripherals::{PIN_10, PIN_11, PWM_SLICE5},
+    pwm::{Config as PwmConfig, Pwm},
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use portable_atomic::{AtomicU8, Ordering};
+
+// External "defines".
+use crate::lib_buttons::Button;
+use crate::lib_config::Calibration;
+use crate::lib_motion::{MotionConfig, MotionController, Step};
+use crate::lib_motor_current::{motor_current, motor_running};
+
+#[cfg(feature = "actuator-dc-encoder")]
+use crate::lib_dc_actuator::DcActuator;
+#[cfg(feature = "actuator-linear")]
+use crate::lib_linear_actuator::LinearActuator;
+#[cfg(feature = "actuator-stepper")]
+use crate::lib_stepper_actuator::StepperActuator;
+
+// The actuator that moves the gear lever is selected at build time. All the tasks that
+// use it take the concrete type, so there can only be one.
+#[cfg(not(any(
+    feature = "actuator-linear",
+    feature = "actuator-dc-encoder",
+    feature = "actuator-stepper"
+)))]
+compile_error!(
+    "Select an actuator: `actuator-linear`, `actuator-dc-encoder` or `actuator-stepper`"
+);
+
+#[cfg(any(
+    all(feature = "actuator-linear", feature = "actuator-dc-encoder"),
+    all(feature = "actuator-linear", feature = "actuator-stepper"),
+    all(feature = "actuator-dc-encoder", feature = "actuator-stepper")
+))]
+compile_error!("Only one actuator can be selected, use `--no-default-features`");
+
+#[cfg(feature = "actuator-linear")]
+pub type Actuator<'d> = LinearActuator<'d>;
+#[cfg(feature = "actuator-dc-encoder")]
+pub type Actuator<'d> = DcActuator<'d>;
+#[cfg(feature = "actuator-stepper")]
+pub type Actuator<'d> = StepperActuator<'d>;
+
+// Without a calibration, spread the gears out evenly over the actuator throw. Run the
+// calibration to get the real positions for the car.
+pub fn uncalibrated() -> Calibration {
+    Calibration::spread(
+        Actuator::POSITION_MIN,
+        Actuator::POSITION_MAX,
+        Actuator::POSITION_1MM,
+    )
+}
+
+// 125MHz / (6249 + 1) => 20kHz, so we can't hear the motor whine.
+const PWM_TOP: u16 = 6_249;
+
+pub const ADC_MAX: u32 = 4_095;
+
+// Number of current samples kept for each move.
+const CAPTURE_SAMPLES: usize = 32;
+
+// Something that can move the gear lever. Positions are in the actuators own unit (Ω of the
+// feedback pot, encoder pulses or motor steps), and so is the calibration.
+#[allow(async_fn_in_trait)]
+pub trait GearActuator {
+    const NAME: &'static str;
+
+    // The usable throw, and how far 1mm is.
+    const POSITION_MIN: u16;
+    const POSITION_MAX: u16;
+    const POSITION_1MM: u16;
+
+    async fn read_position(&mut self) -> u16;
+
+    // Find a known position, for actuators that don't know where they are after power up.
+    // Done before the first move, if not before.
+    async fn home(&mut self) -> Result<(), MoveError> {
+        Ok(())
+    }
+
+    // Move the actuator to the `target` position.
+    async fn move_to(&mut self, target: u16) -> Result<MoveReport, MoveError>;
+
+    // Move `mm` out (or in, if not `extend`) without looking at the position, for when it
+    // can't be trusted. Retracting, it stops early, with `Blind::EndStop`, against the end stop.
+    async fn drive_blind(&mut self, extend: bool, mm: u16) -> Result<Blind, MoveError>;
+
+    fn stop(&mut self);
+
+    // How close to the target is close enough.
+    fn deadband(&self) -> u16;
+    fn set_deadband(&mut self, deadband: u16);
+
+    // Move the actuator to the calibrated position of the gear.
+    async fn move_to_gear(
+        &mut self,
+        calibration: &Calibration,
+        button: Button,
+    ) -> Result<MoveReport, MoveError> {
+        self.move_to(calibration.position(button)).await
+    }
+
+    // Move the actuator 5mm away from where it is, then back again. Make sure it moved,
+    // and that it came back to where it started.
+    async fn self_test(&mut self) -> bool {
+        if let Err(e) = self.home().await {
+            error!("Actuator test, homing failed: {:?}", e);
+            return false;
+        }
+
+        // Homing leaves it against the end stop, outside the throw.
+        let before = self
+            .read_position()
+            .await
+            .clamp(Self::POSITION_MIN, Self::POSITION_MAX);
+        let away = if before > Self::POSITION_MIN + Self::POSITION_1MM * 10 {
+            before - Self::POSITION_1MM * 5
+        } else {
+            before + Self::POSITION_1MM * 5
+        };
+        info!(
+            "Testing {} actuator: {} => {} => {}",
+            Self::NAME,
+            before,
+            away,
+            before
+        );
+
+        if let Err(e) = self.move_to(away).await {
+            error!("Actuator test, move away failed: {:?}", e);
+            return false;
+        }
+        let moved = self.read_position().await;
+        if moved.abs_diff(before) < Self::POSITION_1MM * 3 {
+            error!("Actuator test, haven't moved ({} => {})", before, moved);
+            return false;
+        }
+
+        Timer::after_millis(100).await;
+
+        if let Err(e) = self.move_to(before).await {
+            error!("Actuator test, move back failed: {:?}", e);
+            return false;
+        }
+        let after = self.read_position().await;
+        if after.abs_diff(before) > self.deadband() * 2 {
+            error!("Actuator test, didn't return ({} => {})", before, after);
+            return false;
+        }
+
+        true
+    }
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum MoveError {
+    Timeout { position: u16 },
+    Stall { position: u16 },
+    OverCurrent { position: u16, current_ma: u16 },
+    Preempted { position: u16 }, // Stopped for a more urgent target, not a fault.
+}
+
+// How an open loop move ended.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum Blind {
+    Done,    // Went the whole way, as far as we know.
+    EndStop, // Ran into the retracted end stop.
+}
+
+// Signalled when a more urgent target (P or N) comes in while moving. The moves check it once
+// every loop, where it's safe to stop, and give up on the target they had.
+pub static SIGNAL_PREEMPT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn preempted() -> bool {
+    SIGNAL_PREEMPT.signaled()
+}
+
+// How many moves are running right now. A homing can drive blind on the way, so it's a count.
+static MOVES_RUNNING: AtomicU8 = AtomicU8::new(0);
+
+// Held for as long as the motor might be running, whatever the kind of move. Flash writes
+// wait for the actuator to be done, see `actuator_busy`.
+pub struct Moving;
+
+impl Moving {
+    pub fn start() -> Self {
+        MOVES_RUNNING.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for Moving {
+    fn drop(&mut self) {
+        MOVES_RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn moving() -> bool {
+    MOVES_RUNNING.load(Ordering::SeqCst) > 0
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct MoveReport {
+    pub start: u16,
+    pub end: u16,
+    pub target: u16,
+    pub duration_ms: u64,
+    pub planned_ms: u64,
+    pub overshoot: u16, // Past the target.
+    pub peak_current_ma: u16,
+    pub avg_current_ma: u16,
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct Protection {
+    pub stall_duty: f32,      // Only look for a stall when driving harder than this.
+    pub stall_distance: u16,  // Must have moved at least this far ..
+    pub stall_window_ms: u64, // .. within this long.
+    pub max_current_ma: u16,
+    pub inrush_ms: u64, // Ignore the current for this long after the motor starts.
+}
+
+// Keeps an eye on a closed loop move, for a stuck or overloaded motor.
+pub struct MoveGuard {
+    protection: Protection,
+    moved_at: (u16, Instant), // Where and when we last saw the actuator moving.
+    running_since: Option<Instant>, // When the motor started (again) after standing still.
+}
+
+impl MoveGuard {
+    pub fn new(protection: Protection, start: u16) -> Self {
+        Self {
+            protection,
+            moved_at: (start, Instant::now()),
+            running_since: None,
+        }
+    }
+
+    // Check this before anything else, so the drive can be cut right away.
+    pub fn over_current(&self, current_ma: u16) -> bool {
+        match self.running_since {
+            Some(since) => {
+                since.elapsed().as_millis() > self.protection.inrush_ms
+                    && current_ma > self.protection.max_current_ma
+            }
+            None => false,
+        }
+    }
+
+    // Driving hard, but not going anywhere - we're stuck against something.
+    pub fn stalled(&mut self, position: u16, duty: f32) -> bool {
+        if duty == 0.0 {
+            self.running_since = None;
+        } else if self.running_since.is_none() {
+            self.running_since = Some(Instant::now());
+        }
+
+        if duty.abs() < self.protection.stall_duty
+            || position.abs_diff(self.moved_at.0) >= self.protection.stall_distance
+        {
+            self.moved_at = (position, Instant::now());
+            return false;
+        }
+
+        self.moved_at.1.elapsed().as_millis() > self.protection.stall_window_ms
+    }
+}
+
+// A position for the closed loop, when it was measured and how noisy it is (if known).
+#[derive(Copy, Clone, Format)]
+pub struct PositionSample {
+    pub position: u16,
+    pub noise: Option<u16>,
+    pub at: Instant,
+}
+
+// An actuator with position feedback, driven through an H-bridge. Where the position comes
+// from is all that differs, the move itself is the same - see `closed_loop_move`.
+#[allow(async_fn_in_trait)]
+pub trait ClosedLoop {
+    // The unit of the position, for the log.
+    const UNIT: &'static str;
+
+    async fn sample(&mut self) -> PositionSample;
+    fn drive_motor(&mut self, duty: f32);
+    fn stop_motor(&mut self);
+}
+
+// Move to the `target` (already clamped to the throw) with the motion controller. Cut the
+// drive on an over-current, a stall or a timeout, and stop early if pre-empted.
+pub async fn closed_loop_move<A: ClosedLoop>(
+    actuator: &mut A,
+    motion: MotionConfig,
+    protection: Protection,
+    target: u16,
+) -> Result<MoveReport, MoveError> {
+    let _moving = Moving::start();
+    let start = actuator.sample().await.position;
+    let extending = target >= start;
+
+    let mut controller = MotionController::new(motion, start, target);
+    let timeout = controller.timeout_ms();
+    debug!(
+        "Moving actuator {}{} => {}{} (planned {}ms)",
+        start,
+        A::UNIT,
+        target,
+        A::UNIT,
+        controller.planned_ms()
+    );
+
+    let started = Instant::now();
+    let mut overshoot: u16 = 0;
+    let mut capture = CurrentCapture::new();
+    let mut guard = MoveGuard::new(protection, start);
+    let mut timing = LoopTiming::new(motion.period_ms);
+
+    let mut ticker = Ticker::every(Duration::from_millis(motion.period_ms));
+    let result = loop {
+        let sample = actuator.sample().await;
+        let position = sample.position;
+        timing.tick(sample.at);
+        if extending && position > target {
+            overshoot = overshoot.max(position - target);
+        } else if !extending && position < target {
+            overshoot = overshoot.max(target - position);
+        }
+
+        // Cut the drive right away, before doing anything else.
+        if let Some(current) = motor_current() {
+            capture.add(current);
+            if guard.over_current(current) {
+                actuator.stop_motor();
+                error!(
+                    "Actuator over-current at {}{}: {}mA (max {}mA)",
+                    position,
+                    A::UNIT,
+                    current,
+                    protection.max_current_ma
+                );
+                break Err(MoveError::OverCurrent {
+                    position,
+                    current_ma: current,
+                });
+            }
+        }
+
+        // Something more important came up, stop here and let that have the actuator.
+        if preempted() {
+            actuator.stop_motor();
+            info!(
+                "Actuator move to {}{} pre-empted at {}{}",
+                target,
+                A::UNIT,
+                position,
+                A::UNIT
+            );
+            break Err(MoveError::Preempted { position });
+        }
+
+        if let Some(noise) = sample.noise {
+            controller.set_noise(noise);
+        }
+        let duty = match controller.step(position) {
+            Step::Drive(duty) => {
+                actuator.drive_motor(duty);
+                duty
+            }
+            Step::Done => break Ok(()),
+        };
+
+        if guard.stalled(position, duty) {
+            actuator.stop_motor();
+            error!(
+                "Actuator stalled at {}{} (target {}{}, duty {})",
+                position,
+                A::UNIT,
+                target,
+                A::UNIT,
+                duty
+            );
+            break Err(MoveError::Stall { position });
+        }
+
+        if started.elapsed().as_millis() > timeout {
+            actuator.stop_motor();
+            error!(
+                "Actuator move timed out at {}{} (target {}{})",
+                position,
+                A::UNIT,
+                target,
+                A::UNIT
+            );
+            break Err(MoveError::Timeout { position });
+        }
+
+        ticker.next().await;
+    };
+    actuator.stop_motor();
+    capture.log(motion.period_ms);
+    timing.log();
+    result?;
+
+    let report = MoveReport {
+        start,
+        end: actuator.sample().await.position,
+        target,
+        duration_ms: started.elapsed().as_millis(),
+        planned_ms: controller.planned_ms(),
+        overshoot,
+        peak_current_ma: capture.peak,
+        avg_current_ma: capture.average(),
+    };
+    debug!("Actuator move done: {:?}", report);
+
+    Ok(report)
+}
+
+// How regular the control loop really is, and how old the position was when it was used.
+// The control task runs on a high priority executor, so this should stay within a few µs of
+// the period no matter what else is going on.
+pub struct LoopTiming {
+    period_us: u64,
+    last: Option<Instant>,
+    periods: u32,
+    min_us: u64,
+    max_us: u64,
+    sum_us: u64,
+    late: u32, // Periods more than 50% over.
+    max_age_us: u64,
+}
+
+impl LoopTiming {
+    pub fn new(period_ms: u64) -> Self {
+        Self {
+            period_us: period_ms * 1_000,
+            last: None,
+            periods: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+            sum_us: 0,
+            late: 0,
+            max_age_us: 0,
+        }
+    }
+
+    // Call once every period, with when the position used in it was measured.
+    pub fn tick(&mut self, measured: Instant) {
+        let now = Instant::now();
+        self.max_age_us = self
+            .max_age_us
+            .max(now.saturating_duration_since(measured).as_micros());
+
+        if let Some(last) = self.last {
+            let period = (now - last).as_micros();
+            self.periods += 1;
+            self.min_us = self.min_us.min(period);
+            self.max_us = self.max_us.max(period);
+            self.sum_us += period;
+            if period > self.period_us * 3 / 2 {
+                self.late += 1;
+            }
+        }
+        self.last = Some(now);
+    }
+
+    pub fn log(&self) {
+        if self.periods == 0 {
+            return;
+        }
+
+        if self.late > 0 {
+            warn!(
+                "Control loop: {} of {} periods late (min {}µs, avg {}µs, max {}µs, period {}µs)",
+                self.late,
+                self.periods,
+                self.min_us,
+                self.sum_us / self.periods as u64,
+                self.max_us,
+                self.period_us
+            );
+        } else {
+            debug!(
+                "Control loop: {} periods (min {}µs, avg {}µs, max {}µs), position age max {}µs",
+                self.periods,
+                self.min_us,
+                self.sum_us / self.periods as u64,
+                self.max_us,
+                self.max_age_us
+            );
+        }
+    }
+}
+
+// The motor current during a move. When the buffer is full, every other sample is dropped
+// and the interval doubled, so it always covers the whole move.
+pub struct CurrentCapture {
+    samples: [u16; CAPTURE_SAMPLES],
+    len: usize,
+    every: u32, // Periods between each sample.
+    count: u32,
+    pub peak: u16,
+    sum: u32,
+    total: u32,
+}
+
+impl CurrentCapture {
+    pub fn new() -> Self {
+        Self {
+            samples: [0; CAPTURE_SAMPLES],
+            len: 0,
+            every: 1,
+            count: 0,
+            peak: 0,
+            sum: 0,
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, current_ma: u16) {
+        self.peak = self.peak.max(current_ma);
+        self.sum += current_ma as u32;
+        self.total += 1;
+
+        self.count += 1;
+        if self.count < self.every {
+            return;
+        }
+        self.count = 0;
+
+        if self.len == CAPTURE_SAMPLES {
+            for i in 0..CAPTURE_SAMPLES / 2 {
+                self.samples[i] = self.samples[i * 2];
+            }
+            self.len = CAPTURE_SAMPLES / 2;
+            self.every *= 2;
+        }
+        self.samples[self.len] = current_ma;
+        self.len += 1;
+    }
+
+    pub fn average(&self) -> u16 {
+        if self.total == 0 {
+            return 0;
+        }
+        (self.sum / self.total) as u16
+    }
+
+    pub fn log(&self, period_ms: u64) {
+        info!(
+            "Actuator current, every {}ms (mA): {}",
+            self.every as u64 * period_ms,
+            &self.samples[..self.len]
+        );
+    }
+}
+
+impl Default for CurrentCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// PWM driven H-bridge, on `mplus`/`mminus` of the actuator resources. The motor current is
+// measured while it's driven, see `lib_motor_current.rs`.
+pub struct HBridge<'d> {
+    pwm: Pwm<'d>,
+    config: PwmConfig,
+}
+
+impl<'d> HBridge<'d> {
+    pub fn new(
+        slice: Peri<'d, PWM_SLICE5>,
+        mplus: Peri<'d, PIN_10>,
+        mminus: Peri<'d, PIN_11>,
+    ) -> Self {
+        let mut config = PwmConfig::default();
+        config.top = PWM_TOP;
+        config.compare_a = 0;
+        config.compare_b = 0;
+
+        Self {
+            pwm: Pwm::new_output_ab(slice, mplus, mminus, config.clone()),
+            config,
+        }
+    }
+
+    // Positive duty drives `mplus`, negative `mminus`. Only ever drive one side.
+    pub fn drive(&mut self, duty: f32) {
+        let compare = (duty.abs().min(1.0) * PWM_TOP as f32) as u16;
+        if duty > 0.0 {
+            self.config.compare_a = compare;
+            self.config.compare_b = 0;
+        } else {
+            self.config.compare_a = 0;
+            self.config.compare_b = compare;
+        }
+        self.pwm.set_config(&self.config);
+        motor_running(true);
+    }
+
+    pub fn stop(&mut self) {
+        self.config.compare_a = 0;
+        self.config.compare_b = 0;
+        self.pwm.set_config(&self.config);
+        motor_running(false);
+    }
+}
@@ -0,0 +1,280 @@
+use defmt::{info, warn, Format};
+
+use core::future::Future;
+
+use embassy_embedded_hal::shared_bus::{asynch::i2c::I2cDevice, I2cDeviceError};
+use embassy_rp::{
+    bind_interrupts,
+    gpio::Pin,
+    i2c::{Async, Config, Error as RpI2cError, I2c, InterruptHandler},
+    pac,
+    peripherals::I2C1,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal_async::i2c::{
+    Error as I2cErrorTrait, ErrorKind, ErrorType, I2c as I2cTrait, Operation, SevenBitAddress,
+};
+use portable_atomic::{AtomicU32, AtomicU8, Ordering};
+
+use static_cell::StaticCell;
+
+// External "defines".
+use crate::lib_resources::{
+    PeriI2c, EXPANDER_ADDRESS, MOTOR_CURRENT_ADDRESS, SPI_BRIDGE_ADDRESS, UPS_ADDRESS,
+};
+
+bind_interrupts!(struct Irqs {
+    I2C1_IRQ => InterruptHandler<I2C1>;
+});
+
+// Everything on I2C1 shares the bus, one transfer at a time.
+pub type I2cBus = Mutex<CriticalSectionRawMutex, I2c<'static, I2C1, Async>>;
+
+pub static I2C_BUS: StaticCell<I2cBus> = StaticCell::new();
+
+// The bus pins, for the recovery. Whatever `PeriI2c` says, set by `init_i2c()`.
+static SDA_PIN: AtomicU8 = AtomicU8::new(0);
+static SCL_PIN: AtomicU8 = AtomicU8::new(0);
+
+// GPIO function (datasheet, 2.19.2), for driving the pins by hand.
+const FUNCSEL_SIO: u8 = 5;
+
+// A slave stuck in the middle of a byte lets go of SDA after at most 9 clocks. 100kHz.
+const RECOVERY_CLOCKS: u8 = 9;
+const RECOVERY_HALF_PERIOD_US: u64 = 5;
+
+// The devices that (might) be on the bus.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum BusDevice {
+    Ups,          // INA219 on the UPS module.
+    SpiBridge,    // SC18IS606, for the new CAN design.
+    Expander,     // MCP23017 or PCA9555, for the buttons and LEDs.
+    MotorCurrent, // INA219 on the actuator supply.
+}
+
+pub struct DeviceInfo {
+    pub device: BusDevice,
+    pub name: &'static str,
+    pub address: u8,
+    pub timeout_ms: u64, // For a whole transfer.
+}
+
+// Everything we expect to find. Indexed by `BusDevice`.
+pub const DEVICES: [DeviceInfo; 4] = [
+    DeviceInfo {
+        device: BusDevice::Ups,
+        name: "UPS (INA219)",
+        address: UPS_ADDRESS,
+        timeout_ms: 10,
+    },
+    DeviceInfo {
+        device: BusDevice::SpiBridge,
+        name: "SPI bridge (SC18IS606)",
+        address: SPI_BRIDGE_ADDRESS,
+        timeout_ms: 50, // Up to 1024 bytes of buffer.
+    },
+    DeviceInfo {
+        device: BusDevice::Expander,
+        name: "GPIO expander",
+        address: EXPANDER_ADDRESS,
+        timeout_ms: 10,
+    },
+    DeviceInfo {
+        device: BusDevice::MotorCurrent,
+        name: "Motor current (INA219)",
+        address: MOTOR_CURRENT_ADDRESS,
+        timeout_ms: 10,
+    },
+];
+
+// One bit per `BusDevice`, set if it answered the scan at boot.
+static DEVICES_PRESENT: AtomicU32 = AtomicU32::new(0);
+
+pub fn device_present(device: BusDevice) -> bool {
+    DEVICES_PRESENT.load(Ordering::SeqCst) & (1 << device as u8) != 0
+}
+
+pub fn init_i2c(r: PeriI2c) -> &'static I2cBus {
+    SDA_PIN.store(r.sda.pin(), Ordering::SeqCst);
+    SCL_PIN.store(r.scl.pin(), Ordering::SeqCst);
+
+    let i2c = I2c::new_async(r.i2c, r.scl, r.sda, Irqs, Config::default());
+    let bus: &'static I2cBus = I2C_BUS.init(Mutex::new(i2c));
+
+    return bus;
+}
+
+#[derive(Copy, Clone, Format, Debug)]
+pub enum BusError {
+    I2c(RpI2cError),
+    Config,  // Only for devices with their own bus config, which we don't have.
+    Timeout, // The device (or someone else on the bus) didn't finish in time.
+}
+
+impl I2cErrorTrait for BusError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BusError::I2c(e) => e.kind(),
+            BusError::Config | BusError::Timeout => ErrorKind::Other,
+        }
+    }
+}
+
+// A device on the shared bus. Takes the bus for each transfer, and gives up on it if it takes
+// longer than the device should.
+pub struct SharedI2c {
+    device: I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, I2C1, Async>>,
+    timeout: Duration,
+}
+
+impl SharedI2c {
+    pub fn new(bus: &'static I2cBus, device: BusDevice) -> Self {
+        Self {
+            device: I2cDevice::new(bus),
+            timeout: Duration::from_millis(DEVICES[device as usize].timeout_ms),
+        }
+    }
+}
+
+// Time the transfer out, and unwrap the shared bus error.
+async fn timed<T>(
+    timeout: Duration,
+    transfer: impl Future<Output = Result<T, I2cDeviceError<RpI2cError>>>,
+) -> Result<T, BusError> {
+    match with_timeout(timeout, transfer).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(I2cDeviceError::I2c(e))) => Err(BusError::I2c(e)),
+        Ok(Err(I2cDeviceError::Config)) => Err(BusError::Config),
+        Err(_) => Err(BusError::Timeout),
+    }
+}
+
+impl ErrorType for SharedI2c {
+    type Error = BusError;
+}
+
+impl I2cTrait for SharedI2c {
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), BusError> {
+        timed(self.timeout, self.device.read(address, read)).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), BusError> {
+        timed(self.timeout, self.device.write(address, write)).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), BusError> {
+        timed(self.timeout, self.device.write_read(address, write, read)).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), BusError> {
+        timed(self.timeout, self.device.transaction(address, operations)).await
+    }
+}
+
+// Is anyone answering on the address?
+async fn probe(bus: &'static I2cBus, info: &DeviceInfo) -> bool {
+    let mut device = SharedI2c::new(bus, info.device);
+    let mut buf = [0u8; 1];
+    device.read(info.address, &mut buf).await.is_ok()
+}
+
+// See which of the devices we know about are there. Missing ones aren't necessarily a
+// problem, the UPS and the motor current monitor are optional and the others are for the next
+// version of the board.
+pub async fn scan_i2c(bus: &'static I2cBus) {
+    info!("Scanning the I²C bus");
+
+    let mut present = 0;
+    for info in DEVICES.iter() {
+        if probe(bus, info).await {
+            info!("  0x{:02x}: {} - found", info.address, info.name);
+            present |= 1 << info.device as u8;
+        } else {
+            info!("  0x{:02x}: {} - not found", info.address, info.name);
+        }
+    }
+
+    DEVICES_PRESENT.store(present, Ordering::SeqCst);
+}
+
+fn function(pin: usize) -> u8 {
+    pac::IO_BANK0.gpio(pin).ctrl().read().funcsel()
+}
+
+fn set_function(pin: usize, function: u8) {
+    pac::IO_BANK0
+        .gpio(pin)
+        .ctrl()
+        .write(|w| w.set_funcsel(function));
+}
+
+// Open drain - drive it low, or let the pull-up have it.
+fn release(pin: usize, release: bool) {
+    if release {
+        pac::SIO.gpio_oe(0).value_clr().write_value(1 << pin);
+    } else {
+        pac::SIO.gpio_oe(0).value_set().write_value(1 << pin);
+    }
+}
+
+fn is_high(pin: usize) -> bool {
+    pac::SIO.gpio_in(0).read() & (1 << pin) != 0
+}
+
+// A glitch in the middle of a transfer can leave a device holding SDA low, waiting for the
+// rest of a byte that never comes. Take the pins away from the I²C controller, clock SCL until
+// it lets go, finish with a STOP and give the pins back.
+// Only after a transfer failed - a device that doesn't answer (NAK) isn't a stuck bus.
+pub async fn recover_bus(bus: &'static I2cBus) {
+    // Nobody else can start a transfer while we're at it.
+    let _bus = bus.lock().await;
+
+    let sda = SDA_PIN.load(Ordering::SeqCst) as usize;
+    let scl = SCL_PIN.load(Ordering::SeqCst) as usize;
+    let (sda_function, scl_function) = (function(sda), function(scl));
+
+    pac::SIO
+        .gpio_out(0)
+        .value_clr()
+        .write_value(1 << sda | 1 << scl);
+    release(sda, true);
+    release(scl, true);
+    set_function(sda, FUNCSEL_SIO);
+    set_function(scl, FUNCSEL_SIO);
+
+    let mut clocks = 0;
+    while !is_high(sda) && clocks < RECOVERY_CLOCKS {
+        release(scl, false);
+        Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+        release(scl, true);
+        Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+        clocks += 1;
+    }
+    if clocks > 0 {
+        warn!(
+            "I²C bus recovery: {} clocks, SDA released: {}",
+            clocks,
+            is_high(sda)
+        );
+    }
+
+    // STOP - SDA going high while SCL is high.
+    release(sda, false);
+    Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+    release(sda, true);
+    Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+
+    set_function(sda, sda_function);
+    set_function(scl, scl_function);
+}
@@ -0,0 +1,447 @@
+use defmt::{debug, trace, Format};
+
+use embassy_rp::peripherals::PIO0;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, once_lock::OnceLock,
+};
+use embassy_time::{Duration, Instant, Ticker};
+
+use ws2812::{Colour, Ws2812};
+
+// External "defines".
+use crate::lib_buttons::{
+    Button, LedStatus, ScannerMutex, CHANNEL_D, CHANNEL_N, CHANNEL_P, CHANNEL_R,
+};
+
+// How often the animation task wakes up to update the LEDs. Fast enough for the "breathe"
+// PWM, the LEDs are only written when they change so it's cheap the rest of the time.
+const TICK_MS: u64 = 4;
+
+// Number of ticks in one "breathe" PWM frame. The LEDs we drive are either plain GPIOs or
+// a single NeoPixel, so "dimming" is done by lighting the LED for a part of each frame.
+// 5 * 4ms = 20ms, a 50Hz frame - any slower and it flickers.
+const BREATHE_STEPS: u64 = 5;
+
+// One slot per priority level, per LED.
+const PRIORITIES: usize = 4;
+const TARGETS: usize = 6;
+
+// The LEDs the animation task controls.
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum LedTarget {
+    NeoPixel,
+    Button(Button),
+    Aura,
+}
+
+impl LedTarget {
+    fn index(self) -> usize {
+        match self {
+            Self::NeoPixel => 0,
+            Self::Button(button) => 1 + Button::from(button) as usize,
+            Self::Aura => 5,
+        }
+    }
+
+    fn from_index(i: usize) -> Self {
+        match i {
+            0 => Self::NeoPixel,
+            1..=4 => Self::Button(Button::from_integer(i as u8 - 1)),
+            _ => Self::Aura,
+        }
+    }
+}
+
+// A higher priority pattern pre-empts all lower ones on the same LED. When it's done (or
+// cleared), the LED falls back to the highest priority pattern that's still active.
+#[derive(Copy, Clone, Format, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum Priority {
+    Background = 0, // Steady state, such as the selected gear or the system status.
+    Normal = 1,     // Short feedback, such as "button already selected".
+    Alert = 2,      // Blocking conditions, such as "on battery".
+    Fatal = 3,      // Nothing else matters.
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum LedColour {
+    Black,
+    Red,
+    Green,
+    Blue,
+    Orange,
+    Yellow,
+    Purple,
+}
+
+impl LedColour {
+    fn to_colour(self) -> Colour {
+        match self {
+            Self::Black => Colour::BLACK,
+            Self::Red => Colour::RED,
+            Self::Green => Colour::GREEN,
+            Self::Blue => Colour::BLUE,
+            Self::Orange => Colour::ORANGE,
+            Self::Yellow => Colour::YELLOW,
+            Self::Purple => Colour::PURPLE,
+        }
+    }
+
+    // Colour index of the R503 aura LED (see the `AuraLedConfig` command in the R503 manual).
+    fn to_aura(self) -> u8 {
+        match self {
+            Self::Black => 0,
+            Self::Red => 1,
+            Self::Blue => 2,
+            Self::Purple => 3,
+            Self::Green => 4,
+            Self::Yellow | Self::Orange => 5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum Shape {
+    Off,
+    Solid,
+    Blink { on_ms: u16, off_ms: u16, count: u8 }, // A `count` of zero blinks forever.
+    Breathe { period_ms: u16 },
+    Alternate { period_ms: u16 }, // Switch between `colour` and `alt`.
+}
+
+#[derive(Copy, Clone, Format, PartialEq)]
+pub struct Pattern {
+    pub shape: Shape,
+    pub colour: LedColour,
+    pub alt: LedColour,
+    pub priority: Priority,
+    pub expiry: Option<Duration>, // `None` => until replaced or cleared.
+}
+
+impl Pattern {
+    pub const fn solid(colour: LedColour, priority: Priority) -> Self {
+        Self {
+            shape: Shape::Solid,
+            colour,
+            alt: LedColour::Black,
+            priority,
+            expiry: None,
+        }
+    }
+
+    pub const fn off(priority: Priority) -> Self {
+        Self {
+            shape: Shape::Off,
+            colour: LedColour::Black,
+            alt: LedColour::Black,
+            priority,
+            expiry: None,
+        }
+    }
+
+    pub const fn blink(colour: LedColour, priority: Priority, count: u8, on_ms: u16) -> Self {
+        Self {
+            shape: Shape::Blink {
+                on_ms,
+                off_ms: on_ms,
+                count,
+            },
+            colour,
+            alt: LedColour::Black,
+            priority,
+            expiry: None,
+        }
+    }
+
+    pub const fn breathe(colour: LedColour, priority: Priority, period_ms: u16) -> Self {
+        Self {
+            shape: Shape::Breathe { period_ms },
+            colour,
+            alt: LedColour::Black,
+            priority,
+            expiry: None,
+        }
+    }
+
+    pub const fn alternate(
+        colour: LedColour,
+        alt: LedColour,
+        priority: Priority,
+        period_ms: u16,
+    ) -> Self {
+        Self {
+            shape: Shape::Alternate { period_ms },
+            colour,
+            alt,
+            priority,
+            expiry: None,
+        }
+    }
+
+    pub const fn expires_after(mut self, expiry: Duration) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+}
+
+// Patterns used by more than one task.
+// The colour is ignored for the button LEDs, they're either on or off.
+pub const GEAR_SELECTED: Pattern = Pattern::solid(LedColour::Green, Priority::Background);
+pub const GEAR_UNSELECTED: Pattern = Pattern::off(Priority::Background);
+pub const GEAR_ALREADY_SELECTED: Pattern =
+    Pattern::blink(LedColour::Green, Priority::Normal, 3, 500);
+pub const GEAR_GESTURE: Pattern = Pattern::solid(LedColour::Green, Priority::Normal);
+pub const BUTTONS_DISABLED: Pattern = Pattern::off(Priority::Alert);
+pub const ACTUATOR_FAILED: Pattern = Pattern::blink(LedColour::Red, Priority::Fatal, 0, 250);
+
+pub enum LedRequest {
+    Show(LedTarget, Pattern),
+    Clear(LedTarget, Priority),
+}
+
+pub static CHANNEL_LEDS: Channel<CriticalSectionRawMutex, LedRequest, 32> = Channel::new();
+
+// The fingerprint scanner is initialized after the animation task is started, so it's
+// handed over when it's available.
+static AURA_SCANNER: OnceLock<&'static ScannerMutex> = OnceLock::new();
+
+// Post a pattern to a LED.
+pub async fn show(target: LedTarget, pattern: Pattern) {
+    CHANNEL_LEDS.send(LedRequest::Show(target, pattern)).await;
+}
+
+// Remove the pattern with `priority` from a LED.
+pub async fn clear(target: LedTarget, priority: Priority) {
+    CHANNEL_LEDS.send(LedRequest::Clear(target, priority)).await;
+}
+
+// Light the LED for the `active` button and turn off all the others.
+pub async fn show_gear(active: Button) {
+    for button in Button::iterator() {
+        if button == active {
+            show(LedTarget::Button(button), GEAR_SELECTED).await;
+        } else {
+            show(LedTarget::Button(button), GEAR_UNSELECTED).await;
+        }
+    }
+}
+
+// Post the same pattern to all the button LEDs.
+pub async fn show_buttons(pattern: Pattern) {
+    for button in Button::iterator() {
+        show(LedTarget::Button(button), pattern).await;
+    }
+}
+
+pub async fn clear_buttons(priority: Priority) {
+    for button in Button::iterator() {
+        clear(LedTarget::Button(button), priority).await;
+    }
+}
+
+pub fn attach_scanner(fp_scanner: &'static ScannerMutex) {
+    let _ = AURA_SCANNER.init(fp_scanner);
+}
+
+// What a LED should show at a given point in time.
+#[derive(Copy, Clone, PartialEq)]
+enum Frame {
+    Dark,
+    Primary,
+    Secondary,
+}
+
+#[derive(Copy, Clone)]
+struct Active {
+    pattern: Pattern,
+    started: Instant,
+}
+
+// Returns `None` when the pattern have run its course.
+fn frame(active: &Active, now: Instant, tick: u64) -> Option<Frame> {
+    let elapsed = (now - active.started).as_millis();
+
+    if let Some(expiry) = active.pattern.expiry {
+        if elapsed >= expiry.as_millis() {
+            return None;
+        }
+    }
+
+    match active.pattern.shape {
+        Shape::Off => Some(Frame::Dark),
+        Shape::Solid => Some(Frame::Primary),
+        Shape::Blink {
+            on_ms,
+            off_ms,
+            count,
+        } => {
+            let period = (on_ms as u64 + off_ms as u64).max(1);
+            if count > 0 && elapsed / period >= count as u64 {
+                return None;
+            }
+
+            // Start with the LED off, so that blinking an already lit LED is visible.
+            if elapsed % period < off_ms as u64 {
+                Some(Frame::Dark)
+            } else {
+                Some(Frame::Primary)
+            }
+        }
+        Shape::Breathe { period_ms } => {
+            let period = (period_ms as u64).max(1);
+            let phase = elapsed % period;
+
+            // Triangle wave, 0 => BREATHE_STEPS => 0 over one period.
+            let level = if phase < period / 2 {
+                phase * 2 * BREATHE_STEPS / period
+            } else {
+                (period - phase) * 2 * BREATHE_STEPS / period
+            };
+
+            if tick % BREATHE_STEPS < level {
+                Some(Frame::Primary)
+            } else {
+                Some(Frame::Dark)
+            }
+        }
+        Shape::Alternate { period_ms } => {
+            if (elapsed / (period_ms as u64).max(1)) % 2 == 0 {
+                Some(Frame::Primary)
+            } else {
+                Some(Frame::Secondary)
+            }
+        }
+    }
+}
+
+// Convert a pattern to the R503 `AuraLedConfig` parameters: (control, speed, colour, times).
+fn aura_config(pattern: Option<Pattern>) -> (u8, u8, u8, u8) {
+    match pattern {
+        None => (4, 0, 0, 0), // Always off.
+        Some(pattern) => {
+            let colour = pattern.colour.to_aura();
+            match pattern.shape {
+                Shape::Off => (4, 0, 0, 0),
+                Shape::Solid => (3, 0, colour, 0),
+                Shape::Blink { on_ms, count, .. } => {
+                    (2, (on_ms / 10).min(255) as u8, colour, count)
+                }
+                Shape::Breathe { period_ms } => (1, (period_ms / 20).min(255) as u8, colour, 0),
+                Shape::Alternate { period_ms } => (2, (period_ms / 10).min(255) as u8, colour, 0),
+            }
+        }
+    }
+}
+
+async fn set_button_led(button: Button, lit: bool) {
+    let status = if lit { LedStatus::On } else { LedStatus::Off };
+    match button {
+        Button::P => CHANNEL_P.send(status).await,
+        Button::N => CHANNEL_N.send(status).await,
+        Button::R => CHANNEL_R.send(status).await,
+        Button::D => CHANNEL_D.send(status).await,
+    }
+}
+
+// Run all the LED patterns - NeoPixel, button LEDs and fingerprint scanner aura.
+#[embassy_executor::task]
+pub async fn led_animator(mut neopixel: Ws2812<'static, PIO0, 0>) {
+    debug!("Started LED animation task");
+
+    let mut slots: [[Option<Active>; PRIORITIES]; TARGETS] = [[None; PRIORITIES]; TARGETS];
+    let mut output: [Option<Frame>; TARGETS] = [None; TARGETS];
+    let mut neopixel_colour: Option<LedColour> = None;
+    let mut aura_shown: Option<Option<Pattern>> = None;
+    let mut aura_dirty = false;
+
+    let mut tick: u64 = 0;
+    let mut ticker = Ticker::every(Duration::from_millis(TICK_MS));
+    loop {
+        // Deal with all the requests that have queued up since last tick.
+        while let Ok(request) = CHANNEL_LEDS.try_receive() {
+            match request {
+                LedRequest::Show(target, pattern) => {
+                    trace!("LED {}: show {:?}", target, pattern);
+                    slots[target.index()][pattern.priority as usize] = Some(Active {
+                        pattern,
+                        started: Instant::now(),
+                    });
+                    if target == LedTarget::Aura {
+                        aura_dirty = true;
+                    }
+                }
+                LedRequest::Clear(target, priority) => {
+                    trace!("LED {}: clear {}", target, priority);
+                    slots[target.index()][priority as usize] = None;
+                    if target == LedTarget::Aura {
+                        aura_dirty = true;
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        for (i, target_slots) in slots.iter_mut().enumerate() {
+            // Find the highest priority pattern that's still running.
+            let mut current: Option<(Pattern, Frame)> = None;
+            for slot in target_slots.iter_mut().rev() {
+                if let Some(active) = slot {
+                    match frame(active, now, tick) {
+                        Some(f) => {
+                            current = Some((active.pattern, f));
+                            break;
+                        }
+                        None => {
+                            // Done - fall back to the next one down.
+                            *slot = None;
+                            if i == LedTarget::Aura.index() {
+                                aura_dirty = true;
+                            }
+                        }
+                    }
+                }
+            }
+
+            match LedTarget::from_index(i) {
+                LedTarget::NeoPixel => {
+                    let colour = match current {
+                        Some((pattern, Frame::Primary)) => pattern.colour,
+                        Some((pattern, Frame::Secondary)) => pattern.alt,
+                        _ => LedColour::Black,
+                    };
+                    if neopixel_colour != Some(colour) {
+                        neopixel.set_colour(colour.to_colour()).await;
+                        neopixel_colour = Some(colour);
+                    }
+                }
+                LedTarget::Button(button) => {
+                    let f = current.map(|(_, f)| f).unwrap_or(Frame::Dark);
+                    if output[i] != Some(f) {
+                        set_button_led(button, f == Frame::Primary).await;
+                        output[i] = Some(f);
+                    }
+                }
+                LedTarget::Aura => {
+                    // The aura animates itself, so only tell it when the pattern changes.
+                    let pattern = current.map(|(p, _)| p);
+                    if aura_dirty && aura_shown != Some(pattern) {
+                        if let Some(fp_scanner) = AURA_SCANNER.try_get() {
+                            // Don't wait for the scanner, if it's busy, try again next tick.
+                            if let Ok(mut fp_scanner) = fp_scanner.try_lock() {
+                                let (ctrl, speed, colour, times) = aura_config(pattern);
+                                let _ = fp_scanner.AuraLedConfig(ctrl, speed, colour, times).await;
+                                aura_shown = Some(pattern);
+                                aura_dirty = false;
+                            }
+                        }
+                    } else {
+                        aura_dirty = false;
+                    }
+                }
+            }
+        }
+
+        tick = tick.wrapping_add(1);
+        ticker.next().await;
+    }
+}
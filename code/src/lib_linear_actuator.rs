@@ -0,0 +1,185 @@
+use defmt::{debug, error, unwrap};
+
+use embassy_executor::SendSpawner;
+use embassy_rp::{
+    adc::{Adc, Channel as AdcChannel, Config as AdcConfig, InterruptHandler},
+    gpio::Pull,
+    interrupt::typelevel::{Binding, ADC_IRQ_FIFO},
+};
+use embassy_time::{with_timeout, Duration, Instant, Ticker};
+
+// External "defines".
+use crate::lib_gear_actuator::{
+    closed_loop_move, Blind, ClosedLoop, GearActuator, HBridge, MoveError, MoveReport, Moving,
+    PositionSample, Protection,
+};
+use crate::lib_motion::{MotionConfig, DEFAULT_MOTION};
+use crate::lib_motor_current::motor_current;
+use crate::lib_pot::{pot_sampler, PotSample, PotSampler, SIGNAL_POT};
+use crate::lib_resources::PeriActuator;
+
+use actuator::{RESISTANCE_THROW_1MM, RESISTANCE_THROW_MAX, RESISTANCE_THROW_MIN};
+
+// The sampling task delivers a new position every millisecond. If it haven't for this long,
+// it's stuck.
+const SAMPLE_TIMEOUT_MS: u64 = 10;
+
+// At 30% duty, the actuator moves ~35Ω in 300ms, so 10Ω is well clear of the pot noise.
+// The motor is rated at 5A stall current.
+pub const DEFAULT_PROTECTION: Protection = Protection {
+    stall_duty: 0.3,
+    stall_distance: 10,
+    stall_window_ms: 300,
+    max_current_ma: 4_000,
+    inrush_ms: 50,
+};
+
+// Open loop, at this duty, the actuator moves at least this fast. Tune it on the bench, it's
+// better to stop a little short of (N)eutral than to go past it into (D)rive.
+const BLIND_DUTY: f32 = 0.5;
+const BLIND_MM_PER_S: u64 = 10;
+
+// Linear actuator, driven by PWM through an H-bridge on `mplus`/`mminus` with the position
+// read from the feedback potentiometer. The pot is sampled by its own task, see `lib_pot.rs`.
+pub struct LinearActuator<'d> {
+    motor: HBridge<'d>,
+    last: Option<PotSample>,
+    pub motion: MotionConfig,
+    pub protection: Protection,
+}
+
+impl<'d> LinearActuator<'d> {
+    pub fn new(
+        r: PeriActuator,
+        irqs: impl Binding<ADC_IRQ_FIFO, InterruptHandler>,
+        spawner: SendSpawner,
+    ) -> Self {
+        spawner.spawn(unwrap!(pot_sampler(PotSampler::new(
+            Adc::new(r.adc, irqs, AdcConfig::default()),
+            AdcChannel::new_pin(r.pot, Pull::None),
+            r.dma,
+        ))));
+
+        Self {
+            motor: HBridge::new(r.pwm, r.mplus, r.mminus),
+            last: None,
+            motion: DEFAULT_MOTION,
+            protection: DEFAULT_PROTECTION,
+        }
+    }
+
+    // Wait for the next sample of the position.
+    pub async fn read_sample(&mut self) -> PotSample {
+        match with_timeout(Duration::from_millis(SAMPLE_TIMEOUT_MS), SIGNAL_POT.wait()).await {
+            Ok(sample) => {
+                self.last = Some(sample);
+                sample
+            }
+            Err(_) => match self.last {
+                Some(sample) => {
+                    // Use the last known position, the control loop will catch up.
+                    error!(
+                        "No new actuator potentiometer sample since {}ms",
+                        sample.at.elapsed().as_millis()
+                    );
+                    sample
+                }
+                // Don't ever guess where it is.
+                None => SIGNAL_POT.wait().await,
+            },
+        }
+    }
+
+    // Read the actuator position, in Ω.
+    pub async fn read_pot(&mut self) -> u16 {
+        self.read_sample().await.position
+    }
+}
+
+impl ClosedLoop for LinearActuator<'_> {
+    const UNIT: &'static str = "Ω";
+
+    async fn sample(&mut self) -> PositionSample {
+        let sample = self.read_sample().await;
+        PositionSample {
+            position: sample.position,
+            noise: Some(sample.noise),
+            at: sample.at,
+        }
+    }
+
+    fn drive_motor(&mut self, duty: f32) {
+        self.motor.drive(duty);
+    }
+
+    fn stop_motor(&mut self) {
+        self.motor.stop();
+    }
+}
+
+impl GearActuator for LinearActuator<'_> {
+    const NAME: &'static str = "linear";
+
+    const POSITION_MIN: u16 = RESISTANCE_THROW_MIN;
+    const POSITION_MAX: u16 = RESISTANCE_THROW_MAX;
+    const POSITION_1MM: u16 = RESISTANCE_THROW_1MM;
+
+    async fn read_position(&mut self) -> u16 {
+        self.read_pot().await
+    }
+
+    // Move the actuator to the `target` position (in Ω).
+    async fn move_to(&mut self, target: u16) -> Result<MoveReport, MoveError> {
+        let target = target.clamp(RESISTANCE_THROW_MIN, RESISTANCE_THROW_MAX);
+        closed_loop_move(self, self.motion, self.protection, target).await
+    }
+
+    // Timed, at `BLIND_DUTY`. Running into the retracted end stop is an over-current.
+    async fn drive_blind(&mut self, extend: bool, mm: u16) -> Result<Blind, MoveError> {
+        let ms = mm as u64 * 1_000 / BLIND_MM_PER_S;
+        let duty = if extend { BLIND_DUTY } else { -BLIND_DUTY };
+        debug!("Driving actuator blind, duty {} for {}ms", duty, ms);
+        let _moving = Moving::start();
+
+        let started = Instant::now();
+        let mut ticker = Ticker::every(Duration::from_millis(self.motion.period_ms));
+        self.motor.drive(duty);
+        let result = loop {
+            ticker.next().await;
+            if started.elapsed().as_millis() > ms {
+                break Ok(Blind::Done);
+            }
+
+            // The position might be garbage, but the current is still good.
+            if let Some(current) = motor_current() {
+                if started.elapsed().as_millis() > self.protection.inrush_ms
+                    && current > self.protection.max_current_ma
+                {
+                    if !extend {
+                        break Ok(Blind::EndStop);
+                    }
+                    let sample = SIGNAL_POT.try_take().or(self.last);
+                    break Err(MoveError::OverCurrent {
+                        position: sample.map(|sample| sample.position).unwrap_or(0),
+                        current_ma: current,
+                    });
+                }
+            }
+        };
+        self.motor.stop();
+
+        result
+    }
+
+    fn stop(&mut self) {
+        self.motor.stop();
+    }
+
+    fn deadband(&self) -> u16 {
+        self.motion.deadband
+    }
+
+    fn set_deadband(&mut self, deadband: u16) {
+        self.motion.deadband = deadband;
+    }
+}
@@ -0,0 +1,235 @@
+use defmt::Format;
+
+use num_traits::Float;
+
+// Closed loop position control of the actuator.
+//
+// A trapezoidal velocity profile moves a setpoint from the start to the target position,
+// and a PID controller makes the actuator follow that setpoint. The profile gives us the
+// soft start and soft stop, the duty cycle slew limit takes care of the current spikes
+// when the motor starts and reverses.
+//
+// All positions are in Ω, as read from the actuator potentiometer (or encoder pulses, for the
+// DC motor). The controller doesn't know anything about the hardware, so it can be run
+// against a plant model on the host (see `motion-sim`).
+
+#[derive(Copy, Clone, Format)]
+pub struct MotionConfig {
+    pub kp: f32,           // Duty per Ω of position error.
+    pub ki: f32,           // Duty per Ω·s of accumulated error.
+    pub kd: f32,           // Duty per Ω/s of actuator velocity.
+    pub full_speed: f32,   // Ω/s at `max_duty`, for the feed forward.
+    pub max_velocity: f32, // Ω/s
+    pub acceleration: f32, // Ω/s²
+    pub max_duty: f32,     // 0.0 - 1.0
+    pub min_duty: f32,     // Below this, the motor doesn't move (static friction).
+    pub slew: f32,         // Max duty change per second.
+    pub deadband: u16,     // Ω - close enough, stop here.
+    pub period_ms: u64,    // Control loop period.
+    pub timeout_ms: u64,   // Give up if we haven't settled this long after the planned time.
+}
+
+// Tuned against the plant model, see `motion-sim/`. The integral term isn't needed with the
+// velocity feed forward, and only adds overshoot on the short moves.
+pub const DEFAULT_MOTION: MotionConfig = MotionConfig {
+    kp: 0.008,
+    ki: 0.0,
+    kd: 0.0006,
+    full_speed: 400.0,
+    max_velocity: 300.0,
+    acceleration: 800.0,
+    max_duty: 1.0,
+    min_duty: 0.15,
+    slew: 15.0,
+    deadband: 8,
+    period_ms: 5,
+    timeout_ms: 1_500,
+};
+
+// Number of periods the position have to stay inside the deadband to be done.
+const SETTLE_PERIODS: u8 = 10;
+
+// The measured velocity is the difference of two noisy readings, smooth it before it goes into
+// the D term (a time constant of about 10 periods).
+const VELOCITY_FILTER: f32 = 0.1;
+
+// Trapezoidal (or triangular, for short moves) velocity profile.
+struct Profile {
+    start: f32,
+    direction: f32,
+    distance: f32,
+    velocity: f32, // Peak velocity actually reached.
+    acceleration: f32,
+    t_accel: f32,
+    t_cruise: f32,
+}
+
+impl Profile {
+    fn new(start: f32, target: f32, max_velocity: f32, acceleration: f32) -> Self {
+        let distance = (target - start).abs();
+        let direction = if target >= start { 1.0 } else { -1.0 };
+
+        let mut t_accel = max_velocity / acceleration;
+        let mut velocity = max_velocity;
+        let mut t_cruise = 0.0;
+        if acceleration * t_accel * t_accel > distance {
+            // Never reaches full speed.
+            t_accel = (distance / acceleration).sqrt();
+            velocity = acceleration * t_accel;
+        } else {
+            t_cruise = (distance - acceleration * t_accel * t_accel) / max_velocity;
+        }
+
+        Self {
+            start,
+            direction,
+            distance,
+            velocity,
+            acceleration,
+            t_accel,
+            t_cruise,
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        2.0 * self.t_accel + self.t_cruise
+    }
+
+    // Returns (position, velocity) at time `t`.
+    fn at(&self, t: f32) -> (f32, f32) {
+        let a = self.acceleration;
+        let (travelled, velocity) = if t <= 0.0 {
+            (0.0, 0.0)
+        } else if t < self.t_accel {
+            (0.5 * a * t * t, a * t)
+        } else if t < self.t_accel + self.t_cruise {
+            let t = t - self.t_accel;
+            (
+                0.5 * a * self.t_accel * self.t_accel + self.velocity * t,
+                self.velocity,
+            )
+        } else if t < self.duration() {
+            let t = self.duration() - t;
+            (self.distance - 0.5 * a * t * t, a * t)
+        } else {
+            (self.distance, 0.0)
+        };
+
+        (
+            self.start + self.direction * travelled,
+            self.direction * velocity,
+        )
+    }
+}
+
+pub enum Step {
+    Drive(f32), // Duty cycle, -1.0 (retract) to 1.0 (extend).
+    Done,
+}
+
+pub struct MotionController {
+    config: MotionConfig,
+    profile: Profile,
+    target: f32,
+    elapsed: f32,
+    integral: f32,
+    last_position: f32,
+    velocity: f32, // Measured, filtered.
+    duty: f32,
+    settled: u8,
+    deadband: f32,
+}
+
+impl MotionController {
+    pub fn new(config: MotionConfig, start: u16, target: u16) -> Self {
+        Self {
+            config,
+            profile: Profile::new(
+                start as f32,
+                target as f32,
+                config.max_velocity,
+                config.acceleration,
+            ),
+            target: target as f32,
+            elapsed: 0.0,
+            integral: 0.0,
+            last_position: start as f32,
+            velocity: 0.0,
+            duty: 0.0,
+            settled: 0,
+            deadband: config.deadband as f32,
+        }
+    }
+
+    // How noisy the position measurement is right now. There's no point in trying to get
+    // closer to the target than that, so widen the deadband when it's noisier than expected.
+    pub fn set_noise(&mut self, noise: u16) {
+        self.deadband = self.config.deadband.max(noise) as f32;
+    }
+
+    // How long the profile says the move should take.
+    pub fn planned_ms(&self) -> u64 {
+        (self.profile.duration() * 1_000.0) as u64
+    }
+
+    // Give up if we haven't settled by then.
+    pub fn timeout_ms(&self) -> u64 {
+        self.planned_ms() + self.config.timeout_ms
+    }
+
+    // Run one control period with the latest position measurement.
+    pub fn step(&mut self, position: u16) -> Step {
+        let dt = self.config.period_ms as f32 / 1_000.0;
+        let position = position as f32;
+
+        self.elapsed += dt;
+        let (setpoint, velocity) = self.profile.at(self.elapsed);
+
+        let error = setpoint - position;
+        let measured_velocity = (position - self.last_position) / dt;
+        self.last_position = position;
+        self.velocity += (measured_velocity - self.velocity) * VELOCITY_FILTER;
+
+        // When the profile have finished and we're close enough, ramp down and stay put.
+        let profile_done = self.elapsed >= self.profile.duration();
+        let mut wanted = if profile_done && (self.target - position).abs() <= self.deadband {
+            // Inside the deadband, don't chase the noise.
+            self.integral = 0.0;
+            0.0
+        } else {
+            // Anti-windup: only integrate when we're not saturated.
+            if self.duty.abs() < self.config.max_duty {
+                self.integral += error * dt;
+            }
+
+            // What it takes to go at the profile velocity, above what it takes to move at all.
+            let feed_forward =
+                velocity / self.config.full_speed * (self.config.max_duty - self.config.min_duty);
+            let mut wanted = feed_forward + self.config.kp * error + self.config.ki * self.integral
+                - self.config.kd * self.velocity;
+
+            // Compensate for the static friction, the motor won't move below `min_duty`.
+            if wanted.abs() > 0.01 {
+                wanted += wanted.signum() * self.config.min_duty;
+            }
+            wanted
+        };
+        wanted = wanted.clamp(-self.config.max_duty, self.config.max_duty);
+
+        // Slew rate limit, for a soft start, a soft stop and no hard reversals.
+        let max_change = self.config.slew * dt;
+        self.duty += (wanted - self.duty).clamp(-max_change, max_change);
+
+        // Done when we've stayed close enough, with the motor stopped, for a while.
+        if wanted == 0.0 && self.duty == 0.0 {
+            self.settled += 1;
+            if self.settled >= SETTLE_PERIODS {
+                return Step::Done;
+            }
+        } else {
+            self.settled = 0;
+        }
+
+        Step::Drive(self.duty)
+    }
+}
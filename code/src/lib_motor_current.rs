@@ -0,0 +1,144 @@
+use defmt::{error, info, warn};
+
+use embassy_rp::i2c::{AbortReason::NoAcknowledge, Error::Abort};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicU64, Ordering};
+
+use ina219::{
+    address::Address,
+    calibration::{IntCalibration, MicroAmpere},
+    errors::{InitializationError, InitializationErrorReason::I2cError},
+    AsyncIna219,
+};
+
+// External "defines".
+use crate::lib_i2c::{recover_bus, BusDevice, BusError, I2cBus, SharedI2c};
+use crate::lib_resources::MOTOR_CURRENT_ADDRESS;
+
+// The actuator motor current is measured with an INA219 on the I²C bus, high side on the +12V
+// lead to the H-bridge. That way it doesn't need any pins of its own. With a 10mΩ shunt, that's
+// 100µA/µV and up to 32A (the motor is rated at 5A stall current).
+const SHUNT_UA_PER_UV: i32 = 100;
+
+// While the motor is driven, read it as fast as it can convert (532µs for each of the bus and
+// the shunt, at 12 bits).
+const SAMPLE_PERIOD_US: u64 = 1_100;
+
+// A reading older than this is no good for cutting the drive. Two control loop periods.
+const MAX_AGE_MS: u64 = 10;
+
+// A read that fails is tried again this many times, before giving up on the chip and starting
+// over. If it doesn't answer at all, look for it again this often.
+const READ_RETRIES: u8 = 3;
+const REDETECT_SECS: u64 = 10;
+
+// The latest reading - when it was taken (ms since boot) in the top 48 bits, and the current
+// (mA) in the bottom 16. All in one, so the time and the current always go together.
+static MOTOR_CURRENT: AtomicU64 = AtomicU64::new(0);
+
+// The motor is being driven (`true`), or have stopped (`false`). Only sampled in between, to
+// leave the bus alone the rest of the time.
+static SIGNAL_MOTOR_RUNNING: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+// The H-bridge calls this every time it's driven, and when it stops.
+pub fn motor_running(running: bool) {
+    SIGNAL_MOTOR_RUNNING.signal(running);
+}
+
+// The motor current in mA, if there's a recent enough reading. `None` if there's no monitor,
+// or it haven't caught up yet - then only the stall detection protects the motor.
+pub fn motor_current() -> Option<u16> {
+    let reading = MOTOR_CURRENT.load(Ordering::SeqCst);
+    let at_ms = reading >> 16;
+    if at_ms == 0 || Instant::now().as_millis().saturating_sub(at_ms) > MAX_AGE_MS {
+        return None;
+    }
+
+    Some(reading as u16)
+}
+
+fn store_current(current_ma: i32) {
+    let current_ma = current_ma.unsigned_abs().min(u16::MAX as u32) as u64;
+    let at_ms = Instant::now().as_millis().max(1);
+    MOTOR_CURRENT.store(at_ms << 16 | current_ma, Ordering::SeqCst);
+}
+
+#[embassy_executor::task]
+pub async fn motor_current_monitor(bus: &'static I2cBus) {
+    info!("Started motor current monitor task");
+
+    let mut found = false;
+    loop {
+        let i2c = SharedI2c::new(bus, BusDevice::MotorCurrent);
+
+        // Resolution of 1mA, and a shunt of 10mΩ.
+        let calib = IntCalibration::new(MicroAmpere(1_000), 10_000).unwrap();
+        match AsyncIna219::new_calibrated(
+            i2c,
+            Address::from_byte(MOTOR_CURRENT_ADDRESS).unwrap(),
+            calib,
+        )
+        .await
+        {
+            // Not there (any more).
+            Err(InitializationError {
+                reason: I2cError(BusError::I2c(Abort(NoAcknowledge))),
+                ..
+            }) => {
+                // It's optional, so only complain once it have been there.
+                if found {
+                    warn!(
+                        "Can't find the motor current monitor, retrying in {}s",
+                        REDETECT_SECS
+                    );
+                }
+            }
+            Err(_) => {
+                recover_bus(bus).await;
+                if found {
+                    warn!(
+                        "Can't initialize the motor current monitor, retrying in {}s",
+                        REDETECT_SECS
+                    );
+                }
+            }
+            Ok(mut ina) => {
+                info!("Motor current monitor running");
+                found = true;
+
+                let mut failures: u8 = 0;
+                'running: loop {
+                    // Nothing to do until the motor starts.
+                    while !SIGNAL_MOTOR_RUNNING.wait().await {}
+
+                    while SIGNAL_MOTOR_RUNNING.try_take() != Some(false) {
+                        match ina.next_measurement().await {
+                            Ok(Some(measurement)) => {
+                                failures = 0;
+                                store_current(
+                                    measurement.shunt_voltage.shunt_voltage_uv() * SHUNT_UA_PER_UV
+                                        / 1_000,
+                                );
+                            }
+                            // Not converted yet, try again next time.
+                            Ok(None) => {}
+                            Err(_) => {
+                                failures += 1;
+                                if failures > READ_RETRIES {
+                                    break 'running;
+                                }
+                            }
+                        }
+                        Timer::after_micros(SAMPLE_PERIOD_US).await;
+                    }
+                }
+
+                error!("Motor current monitor stopped answering, starting over");
+                recover_bus(bus).await;
+            }
+        }
+
+        Timer::after_secs(REDETECT_SECS).await;
+    }
+}
@@ -0,0 +1,127 @@
+use defmt::{error, info, trace, Format};
+
+use embassy_rp::{
+    adc::{Adc, Async, Channel as AdcChannel},
+    peripherals::DMA_CH7,
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker};
+
+// External "defines".
+use crate::lib_gear_actuator::ADC_MAX;
+
+// Sample the actuator potentiometer in the background, so the control loop always have a
+// clean, recent position to work with.
+//
+// A single ADC read of the pot is only good to ~±5Ω, and the RP2040 ADC have a few codes
+// with a large DNL error (512, 1536, 2560 and 3584 - erratum RP2040-E11). So every
+// millisecond, a burst of samples is taken in free-running mode, straight to memory with
+// DMA. The median of the burst throws away the spikes, and an IIR filter smooths what's
+// left. The spread of the burst gives an estimate of the noise.
+
+// How often to take a burst, and how many samples in it.
+const SAMPLE_PERIOD_US: u64 = 1_000;
+const BURST_SAMPLES: usize = 32;
+
+// 48MHz / (479 + 1) => 100k samples/s, the burst takes ~0.32ms.
+const ADC_CLOCK_DIV: u16 = 479;
+
+// IIR filter, 1/2^n of the new value. At 1kHz, n=2 is a time constant of ~4ms, that's
+// still fast compared to the 5ms control loop.
+const IIR_SHIFT: u32 = 2;
+const NOISE_SHIFT: u32 = 4;
+
+// NOTE: There's no compensation for the pot supply. The reading is only a ratio of it if
+//       the pot is fed from the same ADC_VREF the ADC measures against, and nothing here
+//       checks that it is - no reference is sampled. A pot fed from anything else (3V3, or
+//       the actuator supply) moves with that supply instead, and the gear positions with it.
+//       The 10kΩ pot also pulls ADC_VREF down over the 200Ω filter resistor (R7), by ~2%.
+//       That's the same at every position, so the calibration takes care of it.
+//
+// Full scale of the actuator feedback potentiometer.
+pub const POT_RESISTANCE: u32 = 10_000;
+
+#[derive(Copy, Clone, Format)]
+pub struct PotSample {
+    pub position: u16, // Ω, filtered.
+    pub noise: u16,    // Ω, estimated standard deviation of a single reading.
+    pub at: Instant,   // When the burst was taken.
+}
+
+// The latest sample. Only the actuator reads it, so a signal is enough.
+pub static SIGNAL_POT: Signal<CriticalSectionRawMutex, PotSample> = Signal::new();
+
+pub struct PotSampler {
+    adc: Adc<'static, Async>,
+    pot: AdcChannel<'static>,
+    dma: Peri<'static, DMA_CH7>,
+}
+
+impl PotSampler {
+    pub fn new(
+        adc: Adc<'static, Async>,
+        pot: AdcChannel<'static>,
+        dma: Peri<'static, DMA_CH7>,
+    ) -> Self {
+        Self { adc, pot, dma }
+    }
+}
+
+// Raw pot reading (0 - ADC_MAX, scaled up by 2^IIR_SHIFT) to Ω.
+fn to_ohm(filtered: u32) -> u16 {
+    ((filtered >> IIR_SHIFT) * POT_RESISTANCE / ADC_MAX) as u16
+}
+
+#[embassy_executor::task]
+pub async fn pot_sampler(mut sampler: PotSampler) {
+    info!("Started actuator potentiometer sampling task");
+
+    let mut buf = [0u16; BURST_SAMPLES];
+
+    let mut filtered: Option<u32> = None; // Scaled up by 2^IIR_SHIFT, to keep the precision.
+    let mut noise: u32 = 0; // Scaled up by 2^NOISE_SHIFT.
+
+    let mut ticker = Ticker::every(Duration::from_micros(SAMPLE_PERIOD_US));
+    loop {
+        ticker.next().await;
+
+        let at = Instant::now();
+        if let Err(e) = sampler
+            .adc
+            .read_many(
+                &mut sampler.pot,
+                &mut buf,
+                ADC_CLOCK_DIV,
+                sampler.dma.reborrow(),
+            )
+            .await
+        {
+            error!("Failed to sample actuator potentiometer: {:?}", e);
+            continue;
+        }
+
+        // Median of the burst, then the spread between the quartiles. For a normal
+        // distribution, the interquartile range is ~1.35 standard deviations.
+        buf.sort_unstable();
+        let median = buf[BURST_SAMPLES / 2] as u32;
+        let spread = (buf[BURST_SAMPLES * 3 / 4] - buf[BURST_SAMPLES / 4]) as u32;
+        let sigma = spread * 100 / 135;
+
+        let value = match filtered {
+            // Start from the first reading, not from zero.
+            None => median << IIR_SHIFT,
+            Some(f) => f - (f >> IIR_SHIFT) + median,
+        };
+        filtered = Some(value);
+        noise = noise - (noise >> NOISE_SHIFT) + sigma;
+
+        let sample = PotSample {
+            position: to_ohm(value),
+            noise: ((noise >> NOISE_SHIFT) * POT_RESISTANCE / ADC_MAX) as u16,
+            at,
+        };
+        trace!("Pot: {:?}", sample);
+        SIGNAL_POT.signal(sample);
+    }
+}
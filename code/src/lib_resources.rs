@@ -5,15 +5,24 @@ use embassy_rp::{peripherals, Peri};
 pub const ADDR_OFFSET: u32 = 0x100000;
 pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
 
+// Devices on the I²C bus (I2C1).
 pub const UPS_ADDRESS: u8 = 0x43;
+pub const SPI_BRIDGE_ADDRESS: u8 = 0x28; // SC18IS606, A2-A0 tied to GND.
+pub const EXPANDER_ADDRESS: u8 = 0x20; // MCP23017 or PCA9555, A2-A0 tied to GND.
+pub const MOTOR_CURRENT_ADDRESS: u8 = 0x41; // INA219 on the actuator supply, A0 tied to VS+.
+
+// Any of the buttons and LEDs can be moved to the GPIO expander instead (pin 0-15, see
+// `lib_expander.rs`), in the order P, R, N, D. `None` is the pin in `PeriButtons`, which is
+// otherwise left alone.
+pub const EXPANDER_BUTTONS: [Option<u8>; 4] = [None, None, None, None];
+pub const EXPANDER_LEDS: [Option<u8>; 4] = [None, None, None, None];
 
 #[cfg_attr(any(), rustfmt::skip)]
 assign_resources! {
     serial: PeriSerial {
         uart:		UART1,
         dma:		DMA_CH4,
-        tx:		PIN_4,
-        rx:		PIN_5
+        tx:		PIN_4
     },
     builtin: PeriBuiltin {
         pin:		PIN_25
@@ -34,12 +43,20 @@ assign_resources! {
         peri:   	FLASH,
         dma:    	DMA_CH3
     },
+    // Which pins are used, and how, depends on the actuator feature:
+    //   * actuator-linear     - H-bridge on mplus/mminus, position pot on pot.
+    //   * actuator-dc-encoder - H-bridge on mplus/mminus, encoder on encoder_pin (counted by
+    //                           encoder, it must be a B channel).
+    //   * actuator-stepper    - STEP on mplus, DIR on mminus, home switch on pot.
     actuator: PeriActuator {
         adc:		ADC,
-        mplus:		PIN_10,
-        mminus:		PIN_11,
-        vsel:		PIN_12,
-        pot:		PIN_28
+        dma:		DMA_CH7,	// ADC free-running samples
+        pwm:		PWM_SLICE5,
+        encoder:	PWM_SLICE2,
+        mplus:		PIN_10,		// PWM5/A
+        mminus:		PIN_11,		// PWM5/B
+        pot:		PIN_28,		// ADC2
+        encoder_pin:	PIN_5		// UART1 RX (unused), PWM2/B
     },
     fpscan: PeriFPScanner {
         uart:		UART0,
@@ -71,7 +88,11 @@ assign_resources! {
         sck_pin:	PIN_18,
         spi:		SPI0		// Serial Peripheral Interface
     },
-    ups: PeriPowerMonitor {
+    expander: PeriExpander {
+        int:		PIN_12		// GPIO expander INT (open drain)
+    },
+    // Shared by the UPS (power monitor) and the other I²C devices, see `lib_i2c.rs`.
+    i2c: PeriI2c {
         sda:		PIN_6,
         scl:		PIN_7,
         i2c:		I2C1
@@ -84,14 +105,14 @@ assign_resources! {
 // * PIN_2	PeriButtons:p_but
 // * PIN_3	PeriButtons:r_but
 // * PIN_4	PeriSerial:tx
-// * PIN_5	PeriSerial:rx		Unused
-// * PIN_6	PeriPowerMonitor:sda
-// * PIN_7	PeriPowerMonitor:scl
+// * PIN_5	PeriActuator:encoder_pin	Only actuator-dc-encoder
+// * PIN_6	PeriI2c:sda
+// * PIN_7	PeriI2c:scl
 // * PIN_8	PeriButtons:n_led
 // * PIN_9	PeriButtons:d_led
 // * PIN_10	PeriActuator:mplus
 // * PIN_11	PeriActuator:mminus
-// * PIN_12	PeriActuator:vsel	Unused
+// * PIN_12	PeriExpander:int
 // * PIN_13	PeriFPScanner:wakeup
 // * PIN_14	PeriButtons:p_led
 // * PIN_15	PeriNeopixel:pin
@@ -117,6 +138,7 @@ assign_resources! {
 // * DMA_CH4	PeriSerial:dma
 // * DMA_CH5	PeriCan:send_dma
 // * DMA_CH6	PeriCan:recv_dma
+// * DMA_CH7	PeriActuator:dma
 //
 // # UART
 // * UART0	PeriFPScanner:uart
@@ -124,8 +146,10 @@ assign_resources! {
 //
 // # Other
 // * PIO0	PeriNeopixel:pio
+// * PWM2	PeriActuator:encoder
+// * PWM5	PeriActuator:pwm
 // * SPI0	PeriCan:spi
 // * ADC	PeriActuator:adc
-// * I2C1	PeriPowerMonitor:i2c
+// * I2C1	PeriI2c:i2c
 // * FLASH	PeriFlash:peri
 // * WATCHDOG	PeriWatchdog:peri
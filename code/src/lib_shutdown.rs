@@ -0,0 +1,314 @@
+use defmt::{debug, error, info, warn, Format};
+
+use embassy_rp::gpio::Output;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::MutexGuard, pubsub::Publisher,
+};
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+// External "defines".
+use crate::lib_actuator::{request_gear, ACTUATOR_TARGET};
+use crate::lib_buttons::{hold_buttons, Button, ButtonState, BUTTON_ENABLED, CHANNEL_BUTTON_STATE};
+use crate::lib_can_bus::{can_alive, can_lost, vehicle_state, CANMessage, CHANNEL_CANWRITE};
+use crate::lib_config::{ConfigUpdate, FlashMutex, FlashType, CHANNEL_CONFIG};
+use crate::lib_eventlog::{log_event, EventKind, CHANNEL_EVENTS};
+use crate::lib_leds::{show_buttons, BUTTONS_DISABLED};
+use crate::lib_status::{get_state, raise_fault, request_reset, set_state, FaultCode, SystemState};
+use crate::lib_ups::{ups_status, SIGNAL_ON_BATTERY};
+
+// How long to give the actuator to get to (P)ark.
+const PARK_TIMEOUT_MS: u64 = 10_000;
+
+// How long to wait for the config and the event log to be written.
+const COMMIT_TIMEOUT_MS: u64 = 2_000;
+
+// Let the EIS see the start relay open before the lock relay does.
+const RELAY_GAP_MS: u64 = 100;
+
+// How often to look at the car while on battery.
+const WATCH_MS: u64 = 250;
+
+pub struct EisRelays {
+    pub lock: Output<'static>,
+    pub start: Output<'static>,
+}
+
+type ButtonPublisher = Publisher<'static, CriticalSectionRawMutex, ButtonState, 4, 4, 4>;
+
+// Why the power went.
+#[derive(Copy, Clone, Format, PartialEq)]
+enum PowerLoss {
+    IgnitionOff,  // The driver turned the car off. Shut down.
+    WhileDriving, // A fuse or a wire, under way (or no telling). Keep everything as-is, and warn.
+    SupplyFault,  // A fuse or a wire, standing still with the ignition on. Record it.
+}
+
+// The last speed the CAN-bus gave was zero. It goes quiet a while after the car is turned
+// off, and then the car is still where it was.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+// The speed, if the CAN-bus have told us lately.
+fn speed() -> Option<u16> {
+    let speed = vehicle_state().speed;
+    if let Some(speed) = speed {
+        STOPPED.store(speed == 0, Ordering::SeqCst);
+    }
+
+    speed
+}
+
+// Put the UPS transition together with what the CAN-bus says. Only the EIS saying the
+// ignition is off shuts down, anything else keeps everything as-is. Without a CAN-bus,
+// only if `without_can` is set.
+fn classify(last: Option<PowerLoss>, without_can: bool) -> PowerLoss {
+    if speed().is_some_and(|speed| speed > 0) {
+        return PowerLoss::WhileDriving;
+    }
+
+    if can_alive() {
+        return match vehicle_state().ignition {
+            Some(false) => PowerLoss::IgnitionOff,
+            // On, or not on the bus we're listening to. Either way, the car is awake.
+            _ => PowerLoss::SupplyFault,
+        };
+    }
+
+    // The CAN-bus going quiet after the ignition was turned off, is just the car going to
+    // sleep. So is it going quiet standing still - the ignition status (from the EIS) might
+    // not make it to the bus we're listening to, and the car only goes to sleep with the
+    // ignition off. The controller runs off the UPS, so it's not us losing the bus.
+    if last == Some(PowerLoss::IgnitionOff) || (can_lost() && STOPPED.load(Ordering::SeqCst)) {
+        return PowerLoss::IgnitionOff;
+    }
+
+    // Nothing to go on, assume the worst.
+    if without_can {
+        PowerLoss::IgnitionOff
+    } else {
+        PowerLoss::WhileDriving
+    }
+}
+
+fn on_battery() -> bool {
+    ups_status().is_some_and(|status| status.on_battery)
+}
+
+// Wait for the power to come back.
+async fn mains_restored() {
+    while SIGNAL_ON_BATTERY.wait().await {}
+}
+
+// Do what the (new) kind of power loss calls for.
+async fn react(publisher: &ButtonPublisher, old: Option<PowerLoss>, new: PowerLoss, hold_secs: u8) {
+    let button = unsafe { BUTTON_ENABLED };
+    let charge = ups_status().map(|status| status.soc).unwrap_or(0);
+
+    // The ignition is back on, carry on as if nothing happened.
+    if old == Some(PowerLoss::IgnitionOff) {
+        info!("Ignition on again, shutdown aborted");
+        publisher.publish_immediate(ButtonState::Start);
+    }
+
+    match new {
+        PowerLoss::IgnitionOff => {
+            warn!("Ignition off, shutting down in {}s", hold_secs);
+            publisher.publish_immediate(ButtonState::Stop);
+            CHANNEL_CANWRITE.send(CANMessage::PowerLost).await;
+        }
+        PowerLoss::WhileDriving => {
+            error!(
+                "Power lost while driving, carrying on on the battery ({}%)",
+                charge
+            );
+            raise_fault(FaultCode::SupplyFault);
+            CHANNEL_CANWRITE.send(CANMessage::SupplyLost).await;
+        }
+        PowerLoss::SupplyFault => {
+            error!("Power lost with the ignition on ({}%)", charge);
+            raise_fault(FaultCode::SupplyFault);
+            log_event(EventKind::SupplyFault, button, charge as u16);
+            if old != Some(PowerLoss::WhileDriving) {
+                CHANNEL_CANWRITE.send(CANMessage::SupplyLost).await;
+            }
+        }
+    }
+}
+
+// Keep an eye on the car while on the battery, and react when it changes. Returns `false`
+// when the power comes back, and `true` when the ignition have been off for `hold_secs`.
+async fn watch(publisher: &ButtonPublisher, hold_secs: u8, without_can: bool) -> bool {
+    let mut class: Option<PowerLoss> = None;
+    let mut since = Instant::now();
+    loop {
+        match ups_status() {
+            Some(status) if !status.on_battery => {
+                if class == Some(PowerLoss::IgnitionOff) {
+                    publisher.publish_immediate(ButtonState::Start);
+                }
+                return false;
+            }
+            Some(_) => {}
+            // The UPS monitor lost the UPS, so we can't tell if the power is back. Hold on to
+            // where we are until it finds it again.
+            None => {
+                since = Instant::now();
+                Timer::after_millis(WATCH_MS).await;
+                continue;
+            }
+        }
+
+        let new = classify(class, without_can);
+        if class != Some(new) {
+            react(publisher, class, new, hold_secs).await;
+            class = Some(new);
+            since = Instant::now();
+        }
+
+        if new == PowerLoss::IgnitionOff && since.elapsed().as_secs() >= hold_secs as u64 {
+            return true;
+        }
+
+        Timer::after_millis(WATCH_MS).await;
+    }
+}
+
+// The car is known to be standing still. The CAN-bus goes quiet a while after the car is
+// turned off, then go by the last speed it gave. Without a CAN-bus there's no knowing, only
+// `without_can` says so.
+fn stationary(without_can: bool) -> bool {
+    match speed() {
+        Some(speed) => speed == 0,
+        None if can_lost() => STOPPED.load(Ordering::SeqCst),
+        None => without_can,
+    }
+}
+
+// Go to (P)ark, and wait for the actuator to get there.
+async fn park() -> bool {
+    info!("Moving to (P)ark before shutting down");
+    request_gear(Button::P);
+
+    let started = Instant::now();
+    while started.elapsed().as_millis() < PARK_TIMEOUT_MS {
+        Timer::after_millis(100).await;
+        if unsafe { ACTUATOR_TARGET.is_none() && BUTTON_ENABLED == Button::P } {
+            return true;
+        }
+    }
+
+    warn!("Actuator didn't get to (P)ark");
+    false
+}
+
+// Make sure the config and the event log are written, and take the flash so nothing else
+// can start a write while the power goes. Hold on to the lock until the end.
+async fn commit(
+    flash: &'static FlashMutex,
+) -> MutexGuard<'static, CriticalSectionRawMutex, FlashType> {
+    CHANNEL_CONFIG
+        .send(ConfigUpdate::ActiveButton(unsafe { BUTTON_ENABLED }))
+        .await;
+
+    let started = Instant::now();
+    while !(CHANNEL_CONFIG.is_empty() && CHANNEL_EVENTS.is_empty())
+        && started.elapsed().as_millis() < COMMIT_TIMEOUT_MS
+    {
+        Timer::after_millis(10).await;
+    }
+
+    // Whoever have it, is writing the last of it.
+    let flash = flash.lock().await;
+    debug!("Config and event log committed");
+
+    flash
+}
+
+// On the UPS battery, find out why. If the driver turned the car off, keep going for a
+// while - it might just be a glitch, or the ignition turned off and on again. Then put
+// everything to rest, and halt. If the power comes back before the relays are released,
+// carry on as if nothing happened. After that, reset and start over.
+// With the ignition on, a fuse or a wire have gone. Never shut down under the driver, and
+// when in doubt, assume that's where we are.
+#[embassy_executor::task]
+pub async fn shutdown_manager(
+    flash: &'static FlashMutex,
+    mut relays: EisRelays,
+    hold_secs: u8,
+    park_on_shutdown: bool,
+    without_can: bool,
+) {
+    info!("Started shutdown manager task");
+
+    // Become a publisher on the button state channel.
+    let publisher = CHANNEL_BUTTON_STATE.publisher().unwrap();
+
+    loop {
+        // Wait for the power to go.
+        while !SIGNAL_ON_BATTERY.wait().await {}
+
+        let button = unsafe { BUTTON_ENABLED };
+        let charge = ups_status().map(|status| status.soc).unwrap_or(0);
+        warn!("On battery ({}%)", charge);
+        log_event(EventKind::PowerLost, button, charge as u16);
+        STOPPED.store(false, Ordering::SeqCst);
+
+        if !watch(&publisher, hold_secs, without_can).await {
+            info!("Power restored, shutdown aborted");
+            log_event(EventKind::PowerRestored, button, 0);
+            continue;
+        }
+
+        // Never put anything to rest under way. Hold on to everything until we know the car
+        // is standing still, or the power comes back.
+        if !stationary(without_can) {
+            warn!("Car not known to be standing still, staying in {}", button);
+            while on_battery() && !stationary(without_can) {
+                Timer::after_millis(WATCH_MS).await;
+            }
+        }
+
+        // The actuator might still be needed, so only when we know the power isn't coming
+        // back. Nothing but the shutdown moves it from here on, the buttons stay blocked
+        // until the power comes back.
+        if on_battery() && park_on_shutdown && button != Button::P {
+            hold_buttons(true);
+            park().await;
+        }
+
+        if !on_battery() {
+            info!("Power restored, shutdown aborted");
+            log_event(EventKind::PowerRestored, button, 0);
+            hold_buttons(false);
+            publisher.publish_immediate(ButtonState::Start);
+            continue;
+        }
+
+        break;
+    }
+
+    // No way back from here.
+    info!("Shutting down");
+    CHANNEL_CANWRITE.send(CANMessage::ShuttingDown).await;
+    log_event(EventKind::Shutdown, unsafe { BUTTON_ENABLED }, 0);
+    let _flash = commit(flash).await;
+
+    // Ignore the buttons from now on, and release the relays - the start relay first, so
+    // the EIS never sees it without the lock.
+    hold_buttons(true);
+    show_buttons(BUTTONS_DISABLED).await;
+    relays.start.set_low();
+    Timer::after_millis(RELAY_GAP_MS).await;
+    relays.lock.set_low();
+
+    // The UPS module can't turn itself off, so just sit here until the battery is flat, or
+    // the power comes back. Then start over.
+    if get_state() != SystemState::Fatal {
+        set_state(SystemState::Shutdown);
+    }
+    info!("Shut down, waiting for the power to come back");
+    mains_restored().await;
+
+    info!("Power restored, restarting");
+    request_reset();
+}
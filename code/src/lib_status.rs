@@ -0,0 +1,250 @@
+use defmt::{debug, info, warn, Format};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+// External "defines".
+use crate::lib_leds::{clear, show, LedColour, LedTarget, Pattern, Priority};
+
+// The meaning of the status LED, from the README:
+// * RED			Bootup started.
+// * YELLOW		Bootup done, login started.
+// * RED (short)		Failed login.
+// * GREEN		Login done, main loop started.
+// * BLUE			Valet mode, main loop started.
+// * RED (blinking)	Fatal error, program execution stopped.
+// * OFF			Shut down, on the UPS battery.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum SystemState {
+    BootStarted,
+    BootDone,
+    LoginFailed,
+    LoginDone,
+    Valet,
+    Fatal,
+    Shutdown,
+}
+
+impl SystemState {
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            0 => Self::BootStarted,
+            1 => Self::BootDone,
+            2 => Self::LoginFailed,
+            3 => Self::LoginDone,
+            4 => Self::Valet,
+            6 => Self::Shutdown,
+            _ => Self::Fatal,
+        }
+    }
+
+    fn pattern(self) -> Pattern {
+        match self {
+            Self::BootStarted => Pattern::solid(LedColour::Red, Priority::Background),
+            Self::BootDone => Pattern::solid(LedColour::Yellow, Priority::Background),
+            Self::LoginFailed => Pattern::solid(LedColour::Red, Priority::Background),
+            Self::LoginDone => Pattern::solid(LedColour::Green, Priority::Background),
+            Self::Valet => Pattern::solid(LedColour::Blue, Priority::Background),
+            Self::Fatal => Pattern::blink(LedColour::Red, Priority::Fatal, 0, 250),
+            Self::Shutdown => Pattern::off(Priority::Background),
+        }
+    }
+}
+
+// Fault codes. The value is the number of blinks shown on the status LED, so keep them
+// low and don't renumber them - the README tells the driver what they mean.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum FaultCode {
+    ActuatorTest = 1,
+    ActuatorMove = 2,
+    Flash = 3,
+    Fingerprint = 4,
+    ActuatorStall = 5,
+    ActuatorOverCurrent = 6,
+    GearMismatch = 7,
+    ActuatorFailed = 8,
+    LimpHome = 9,
+    CanBusLost = 10,
+    SupplyFault = 11,
+    PowerMonitor = 12,
+}
+
+impl FaultCode {
+    pub fn from_integer(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::ActuatorTest),
+            2 => Some(Self::ActuatorMove),
+            3 => Some(Self::Flash),
+            4 => Some(Self::Fingerprint),
+            5 => Some(Self::ActuatorStall),
+            6 => Some(Self::ActuatorOverCurrent),
+            7 => Some(Self::GearMismatch),
+            8 => Some(Self::ActuatorFailed),
+            9 => Some(Self::LimpHome),
+            10 => Some(Self::CanBusLost),
+            11 => Some(Self::SupplyFault),
+            12 => Some(Self::PowerMonitor),
+            _ => None,
+        }
+    }
+
+    pub fn iterator() -> impl Iterator<Item = FaultCode> {
+        (1..32u8).filter_map(Self::from_integer)
+    }
+}
+
+// Why we're in limp-home mode. Only (P)ark and (N)eutral can be selected in either, but with
+// the actuator gone, the moves are made open loop.
+#[derive(Copy, Clone, Format, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum LimpReason {
+    CanBus = 1,   // The transmission and the ESP have gone quiet.
+    Actuator = 2, // The actuator position can't be trusted.
+}
+
+// Limp-home mode overrides the normal (non-fatal) state colours.
+const LIMP_HOME: Pattern = Pattern::solid(LedColour::Orange, Priority::Background);
+
+// Blink code timing.
+const FAULT_BLINK_MS: u16 = 300;
+const FAULT_PAUSE_MS: u64 = 2_000;
+
+static SYSTEM_STATE: AtomicU8 = AtomicU8::new(SystemState::BootStarted as u8);
+static ACTIVE_FAULTS: AtomicU32 = AtomicU32::new(0);
+static LIMP_HOME_MODE: AtomicU8 = AtomicU8::new(0); // `LimpReason`, 0 when not limping.
+static RESET_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Wake up the status task when something changed.
+static SIGNAL_STATUS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn set_state(state: SystemState) {
+    let old = SystemState::from_integer(SYSTEM_STATE.swap(state as u8, Ordering::SeqCst));
+    if old != state {
+        debug!("System state: {} => {}", old, state);
+        SIGNAL_STATUS.signal(());
+    }
+}
+
+pub fn get_state() -> SystemState {
+    SystemState::from_integer(SYSTEM_STATE.load(Ordering::SeqCst))
+}
+
+// Something can't be trusted, but the car should still be usable. Stays until restart, and
+// the worst reason wins.
+pub fn enter_limp_home(reason: LimpReason) {
+    let old = LIMP_HOME_MODE.fetch_max(reason as u8, Ordering::SeqCst);
+    if old < reason as u8 {
+        warn!("Entering limp-home mode: {}", reason);
+        SIGNAL_STATUS.signal(());
+    }
+}
+
+pub fn limp_home() -> bool {
+    LIMP_HOME_MODE.load(Ordering::SeqCst) != 0
+}
+
+pub fn limp_reason() -> Option<LimpReason> {
+    match LIMP_HOME_MODE.load(Ordering::SeqCst) {
+        1 => Some(LimpReason::CanBus),
+        2 => Some(LimpReason::Actuator),
+        _ => None,
+    }
+}
+
+// Give up and reset. The watchdog task stops feeding the watchdog when it sees this.
+pub fn request_reset() {
+    if !RESET_REQUESTED.swap(true, Ordering::SeqCst) {
+        warn!("Reset requested");
+    }
+}
+
+pub fn reset_requested() -> bool {
+    RESET_REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn raise_fault(code: FaultCode) {
+    let bit = 1 << (code as u8);
+    if ACTIVE_FAULTS.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+        warn!("Fault raised: {} ({} blinks)", code, code as u8);
+        SIGNAL_STATUS.signal(());
+    }
+}
+
+pub fn clear_fault(code: FaultCode) {
+    let bit = 1 << (code as u8);
+    if ACTIVE_FAULTS.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        info!("Fault cleared: {}", code);
+        SIGNAL_STATUS.signal(());
+    }
+}
+
+pub fn fault_active(code: FaultCode) -> bool {
+    ACTIVE_FAULTS.load(Ordering::SeqCst) & (1 << (code as u8)) != 0
+}
+
+// Derive the status LED from the system state, and blink out the active fault codes.
+#[embassy_executor::task]
+pub async fn status_indicator() {
+    debug!("Started status indicator task");
+
+    let mut shown: Option<(SystemState, bool)> = None;
+    loop {
+        let state = get_state();
+        let limp = limp_home();
+        if shown != Some((state, limp)) {
+            // The fatal pattern sits above everything else, so there's no need to remove it.
+            let pattern = if limp && state != SystemState::Fatal {
+                LIMP_HOME
+            } else {
+                state.pattern()
+            };
+            show(LedTarget::NeoPixel, pattern).await;
+            shown = Some((state, limp));
+        }
+
+        if ACTIVE_FAULTS.load(Ordering::SeqCst) == 0
+            || state == SystemState::Fatal
+            || state == SystemState::Shutdown
+        {
+            // Nothing to blink, sleep until something changes.
+            SIGNAL_STATUS.wait().await;
+            continue;
+        }
+
+        // Blink each fault code in turn: '<n> x RED', pause, '<m> x RED', pause, ...
+        for code in FaultCode::iterator() {
+            if !fault_active(code) {
+                continue;
+            }
+
+            let blinks = code as u8;
+            show(
+                LedTarget::NeoPixel,
+                Pattern::blink(LedColour::Red, Priority::Alert, blinks, FAULT_BLINK_MS),
+            )
+            .await;
+
+            // Let the sequence run to completion, then keep the LED dark for a while so the
+            // codes can be told apart.
+            let sequence = blinks as u64 * 2 * FAULT_BLINK_MS as u64;
+            Timer::after_millis(sequence).await;
+            show(
+                LedTarget::NeoPixel,
+                Pattern::off(Priority::Alert).expires_after(Duration::from_millis(FAULT_PAUSE_MS)),
+            )
+            .await;
+
+            if let Either::First(_) =
+                select(SIGNAL_STATUS.wait(), Timer::after_millis(FAULT_PAUSE_MS)).await
+            {
+                // Something changed, start over.
+                clear(LedTarget::NeoPixel, Priority::Alert).await;
+                break;
+            }
+        }
+    }
+}
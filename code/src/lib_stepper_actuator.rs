@@ -0,0 +1,238 @@
+use defmt::{debug, error, info};
+
+use embassy_executor::SendSpawner;
+use embassy_rp::{
+    adc::InterruptHandler,
+    gpio::{Input, Level, Output, Pull},
+    interrupt::typelevel::{Binding, ADC_IRQ_FIFO},
+};
+use embassy_time::{Instant, Timer};
+
+use num_traits::Float;
+
+// External "defines".
+use crate::lib_gear_actuator::{preempted, Blind, GearActuator, MoveError, MoveReport, Moving};
+use crate::lib_resources::PeriActuator;
+
+// Stepper motor on a T8 lead screw (8mm/rev), through a step/dir driver (A4988, DRV8825 or
+// similar) in full step mode. 200 steps/rev => 25 steps/mm.
+const STEPS_PER_MM: u16 = 25;
+const TRAVEL_MM: u16 = 60;
+
+// Steps per second, and steps per second². Much faster than this and it starts to skip
+// steps under load.
+const START_SPEED: f32 = 200.0;
+const MAX_SPEED: f32 = 1_000.0;
+const ACCELERATION: f32 = 2_000.0;
+const HOME_SPEED: f32 = 300.0;
+
+// The driver needs at least 1µs (A4988) or 2µs (DRV8825) high on STEP.
+const STEP_PULSE_US: u64 = 3;
+
+// Stepper motor, driven open loop. The driver STEP and DIR inputs are on the `mplus` and
+// `mminus` pins, and the home switch (active low, at the (P)ark end) on the `pot` pin.
+// There's no feedback, so the position is just the steps we've made since the home switch.
+pub struct StepperActuator<'d> {
+    step: Output<'d>,
+    dir: Output<'d>,
+    home_switch: Input<'d>,
+    position: u16,
+    homed: bool,
+    deadband: u16,
+}
+
+impl<'d> StepperActuator<'d> {
+    // The ADC isn't used, and there's nothing to spawn. It's only here so all the actuators are
+    // created the same way.
+    pub fn new(
+        r: PeriActuator,
+        _irqs: impl Binding<ADC_IRQ_FIFO, InterruptHandler>,
+        _spawner: SendSpawner,
+    ) -> Self {
+        Self {
+            step: Output::new(r.mplus, Level::Low),
+            dir: Output::new(r.mminus, Level::Low),
+            home_switch: Input::new(r.pot, Pull::Up),
+            position: 0,
+            homed: false,
+            deadband: 0,
+        }
+    }
+
+    // High DIR extends the actuator.
+    fn set_direction(&mut self, extending: bool) {
+        self.dir
+            .set_level(if extending { Level::High } else { Level::Low });
+    }
+
+    async fn step_once(&mut self, interval_us: u64) {
+        self.step.set_high();
+        Timer::after_micros(STEP_PULSE_US).await;
+        self.step.set_low();
+        Timer::after_micros(interval_us.saturating_sub(STEP_PULSE_US)).await;
+    }
+}
+
+impl GearActuator for StepperActuator<'_> {
+    const NAME: &'static str = "stepper";
+
+    const POSITION_MIN: u16 = STEPS_PER_MM;
+    const POSITION_MAX: u16 = STEPS_PER_MM * (TRAVEL_MM - 1);
+    const POSITION_1MM: u16 = STEPS_PER_MM;
+
+    // Step towards the home switch, slowly, until it closes.
+    async fn home(&mut self) -> Result<(), MoveError> {
+        if self.homed {
+            return Ok(());
+        }
+        info!("Homing the stepper actuator");
+        let _moving = Moving::start();
+
+        let interval_us = (1_000_000.0 / HOME_SPEED) as u64;
+        let max_steps = STEPS_PER_MM as u32 * (TRAVEL_MM as u32 + 10);
+
+        self.set_direction(false);
+        let mut steps = 0;
+        while self.home_switch.is_high() {
+            if steps > max_steps {
+                error!("Stepper actuator never reached the home switch");
+                return Err(MoveError::Timeout { position: 0 });
+            }
+            self.step_once(interval_us).await;
+            steps += 1;
+        }
+
+        self.position = 0;
+        self.homed = true;
+        debug!("Stepper actuator homed after {} steps", steps);
+
+        Ok(())
+    }
+
+    // Read the actuator position, in steps from the home switch.
+    async fn read_position(&mut self) -> u16 {
+        self.position
+    }
+
+    // Move to the target with a trapezoidal speed profile, start and stop slowly enough that
+    // the motor doesn't skip any steps.
+    async fn move_to(&mut self, target: u16) -> Result<MoveReport, MoveError> {
+        let _moving = Moving::start();
+        self.home().await?;
+
+        let target = target.clamp(Self::POSITION_MIN, Self::POSITION_MAX);
+        let start = self.position;
+        let distance = start.abs_diff(target);
+        let extending = target >= start;
+        debug!(
+            "Moving actuator {} => {} ({} steps)",
+            start, target, distance
+        );
+
+        let started = Instant::now();
+        let mut preempting = false;
+        if distance > self.deadband {
+            self.set_direction(extending);
+            let mut stop_at = distance;
+            let mut done = 0;
+            while done < stop_at {
+                // Something more important came up. Stopping dead at speed would skip steps,
+                // so ramp down and stop where the deceleration gets us.
+                if !preempting && preempted() {
+                    preempting = true;
+                    let speed = (START_SPEED * START_SPEED + 2.0 * ACCELERATION * done as f32)
+                        .sqrt()
+                        .min(MAX_SPEED);
+                    let ramp = (speed * speed - START_SPEED * START_SPEED) / (2.0 * ACCELERATION);
+                    stop_at = stop_at.min(done + ramp as u16 + 1);
+                    info!(
+                        "Actuator move to {} pre-empted at {}, stopping in {} steps",
+                        target,
+                        self.position,
+                        stop_at - done
+                    );
+                }
+
+                // Accelerate from the start, decelerate towards the end, whichever is slowest.
+                let left = (stop_at - done) as f32;
+                let speed = (START_SPEED * START_SPEED + 2.0 * ACCELERATION * done as f32)
+                    .sqrt()
+                    .min((START_SPEED * START_SPEED + 2.0 * ACCELERATION * left).sqrt())
+                    .min(MAX_SPEED);
+
+                self.step_once((1_000_000.0 / speed) as u64).await;
+                if extending {
+                    self.position += 1;
+                } else {
+                    self.position -= 1;
+                }
+                done += 1;
+            }
+        }
+
+        if preempting && self.position != target {
+            return Err(MoveError::Preempted {
+                position: self.position,
+            });
+        }
+
+        let report = MoveReport {
+            start,
+            end: self.position,
+            target,
+            duration_ms: started.elapsed().as_millis(),
+            planned_ms: started.elapsed().as_millis(),
+            overshoot: 0,
+            peak_current_ma: 0,
+            avg_current_ma: 0,
+        };
+        debug!("Actuator move done: {:?}", report);
+
+        Ok(report)
+    }
+
+    // The stepper is always driven blind, this just doesn't need it to be homed first. Counts
+    // the steps at the homing speed, and stops at the home switch.
+    async fn drive_blind(&mut self, extend: bool, mm: u16) -> Result<Blind, MoveError> {
+        let _moving = Moving::start();
+        let steps = mm as u32 * STEPS_PER_MM as u32;
+        debug!(
+            "Driving actuator blind, {} steps {}",
+            steps,
+            if extend { "out" } else { "in" }
+        );
+
+        let interval_us = (1_000_000.0 / HOME_SPEED) as u64;
+        self.set_direction(extend);
+        for _ in 0..steps {
+            // That's the end stop, for the others.
+            if !extend && self.home_switch.is_low() {
+                self.position = 0;
+                self.homed = true;
+                return Ok(Blind::EndStop);
+            }
+            self.step_once(interval_us).await;
+            if extend {
+                self.position = self.position.saturating_add(1);
+            } else {
+                self.position = self.position.saturating_sub(1);
+            }
+        }
+
+        Ok(Blind::Done)
+    }
+
+    // Steps are only made while moving, so there's nothing to stop. The driver keeps holding
+    // the position.
+    fn stop(&mut self) {
+        self.step.set_low();
+    }
+
+    fn deadband(&self) -> u16 {
+        self.deadband
+    }
+
+    fn set_deadband(&mut self, deadband: u16) {
+        self.deadband = deadband;
+    }
+}
@@ -1,104 +1,531 @@
-use defmt::{debug, info, warn};
+use defmt::{debug, error, info, warn, Format};
 
-use embassy_rp::{
-    bind_interrupts,
-    i2c::{AbortReason::NoAcknowledge, Config, Error::Abort, I2c, InterruptHandler},
-};
-use embassy_time::Timer;
+use core::mem::MaybeUninit;
+
+use embassy_futures::select::{select, Either};
+use embassy_rp::i2c::{AbortReason::NoAcknowledge, Error::Abort};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
 
 use ina219::{
     address::Address,
     calibration::{IntCalibration, MicroAmpere},
     errors::{InitializationError, InitializationErrorReason::I2cError},
-    SyncIna219,
+    AsyncIna219,
 };
 
-use crate::lib_buttons::{ButtonState, CHANNEL_BUTTON_STATE};
-use crate::lib_resources::{PeriPowerMonitor, UPS_ADDRESS};
+// External "defines".
+use crate::lib_buttons::{Button, BUTTON_ENABLED};
+use crate::lib_eventlog::{log_event, EventKind};
+use crate::lib_i2c::{recover_bus, BusDevice, BusError, I2cBus, SharedI2c};
+use crate::lib_resources::UPS_ADDRESS;
+use crate::lib_status::{clear_fault, raise_fault, FaultCode};
+use crate::lib_watchdog::{check_in, register, Task};
+
+// The UPS module comes with a single 600mAh LiPo cell. Cheap cells are often nowhere near
+// what they say on the label, lower this if the time left is always too optimistic.
+const BATTERY_CAPACITY_MAH: f32 = 600.0;
+
+// The shunt resistor in the Pico UPS Hat B: R1/0.01Ω => 100µA/µV.
+const SHUNT_UA_PER_UV: i32 = 100;
+
+// Open circuit voltage (mV) vs state of charge (%) for a LiPo cell, at room temperature.
+// Only good with (next to) no current flowing.
+const OCV_TABLE: [(u16, u8); 11] = [
+    (3_000, 0),
+    (3_450, 5),
+    (3_600, 10),
+    (3_680, 20),
+    (3_740, 30),
+    (3_790, 40),
+    (3_840, 50),
+    (3_900, 60),
+    (3_970, 70),
+    (4_050, 80),
+    (4_200, 100),
+];
+
+// With less than this going in or out, for this long, the cell voltage is close enough to the
+// open circuit voltage to correct the drift of the coulomb counter against it.
+const REST_CURRENT_MA: f32 = 20.0;
+const REST_SECS: u64 = 300;
+
+// How much of the difference to the open circuit voltage estimate to correct, every second.
+const DRIFT_CORRECTION: f32 = 0.01;
+
+// The current for the time-to-empty estimate, 1/n of the new value.
+const CURRENT_FILTER: f32 = 10.0;
+
+// A read that fails is tried again this many times, waiting twice as long each time, before
+// giving up on the chip and starting over.
+const READ_RETRIES: u8 = 3;
+const RETRY_MS: u64 = 50;
+
+// When it doesn't answer at all, look for it again this often. When it answers but fails
+// anyway, back off from 1s up to this.
+const REDETECT_SECS: u64 = 30;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+// How often the charge goes on the console.
+const REPORT_SECS: u64 = 60;
+
+// During an actuator move, the supply is read as fast as the INA219 can convert (532µs for
+// each of the bus and the shunt, at 12 bits). Stop by itself if nobody tells it to.
+const CAPTURE_PERIOD_US: u64 = 1_100;
+const CAPTURE_MAX_MS: u64 = 5_000;
+
+// Only a move where the supply sagged below this goes in the event log, the rest only on the
+// console. The UPS cuts off at around 3V, this leaves some room.
+const SUPPLY_SAG_MV: u16 = 3_500;
+
+// Tells the capture record apart from whatever was left in the RAM.
+const CAPTURE_MAGIC: u32 = 0x5CA9_7E01;
 
-bind_interrupts!(struct Irqs {
-    I2C1_IRQ => InterruptHandler<embassy_rp::peripherals::I2C1>;
-});
+// What the UPS monitor knows about the battery, for the other tasks.
+static POWER_MONITOR_STATUS: AtomicU8 = AtomicU8::new(PowerMonitorStatus::Unknown as u8);
+static UPS_ON_BATTERY: AtomicBool = AtomicBool::new(false);
+static UPS_SOC: AtomicU8 = AtomicU8::new(0);
+static UPS_TIME_TO_EMPTY: AtomicU16 = AtomicU16::new(TIME_TO_EMPTY_UNKNOWN);
+
+const TIME_TO_EMPTY_UNKNOWN: u16 = u16::MAX;
+
+// Signalled when we go on battery (`true`), and when the power comes back (`false`).
+pub static SIGNAL_ON_BATTERY: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum PowerMonitorStatus {
+    Unknown, // Not looked for it yet.
+    Present,
+    Absent, // Nothing answers on its address.
+    Faulty, // It answers, but the reads keep failing.
+}
+
+impl PowerMonitorStatus {
+    pub fn from_integer(v: u8) -> Self {
+        match v {
+            1 => Self::Present,
+            2 => Self::Absent,
+            3 => Self::Faulty,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+pub fn power_monitor_status() -> PowerMonitorStatus {
+    PowerMonitorStatus::from_integer(POWER_MONITOR_STATUS.load(Ordering::SeqCst))
+}
+
+// No UPS at all is fine, it's optional. But if it was there and went away, or it's there but
+// can't be read, the battery isn't being looked after.
+fn set_monitor_status(status: PowerMonitorStatus) {
+    let old =
+        PowerMonitorStatus::from_integer(POWER_MONITOR_STATUS.swap(status as u8, Ordering::SeqCst));
+    if old == status {
+        return;
+    }
+    info!("UPS monitor: {} => {}", old, status);
+
+    match status {
+        PowerMonitorStatus::Present => clear_fault(FaultCode::PowerMonitor),
+        PowerMonitorStatus::Faulty => raise_fault(FaultCode::PowerMonitor),
+        PowerMonitorStatus::Absent if old != PowerMonitorStatus::Unknown => {
+            raise_fault(FaultCode::PowerMonitor)
+        }
+        _ => {}
+    }
+}
+
+// Each move gets an ID, so a stop is never taken for the end of another move. A signal only
+// keeps the last value, a start and a stop close together would otherwise be lost.
+static MOVE_ID: AtomicU16 = AtomicU16::new(0);
+static MOVE_DONE: AtomicU16 = AtomicU16::new(0);
+
+// Signalled with the ID of the move that's about to start.
+static SIGNAL_SUPPLY_CAPTURE: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+
+// What the supply did during an actuator move.
+#[derive(Copy, Clone, Format)]
+#[repr(C)]
+pub struct SupplyCapture {
+    pub move_id: u16,
+    pub min_mv: u16,  // Bus voltage.
+    pub max_mv: u16,  // Bus voltage.
+    pub peak_ma: u16, // Battery current, either way.
+    pub samples: u16,
+}
+
+impl SupplyCapture {
+    fn new(move_id: u16) -> Self {
+        Self {
+            move_id,
+            min_mv: u16::MAX,
+            max_mv: 0,
+            peak_ma: 0,
+            samples: 0,
+        }
+    }
+
+    fn add(&mut self, voltage_mv: u16, current_ma: i32) {
+        self.min_mv = self.min_mv.min(voltage_mv);
+        self.max_mv = self.max_mv.max(voltage_mv);
+        self.peak_ma = self
+            .peak_ma
+            .max(current_ma.unsigned_abs().min(u16::MAX as u32) as u16);
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    fn sagged(&self) -> bool {
+        self.samples > 0 && self.min_mv < SUPPLY_SAG_MV
+    }
+
+    pub fn log_events(&self, button: Button) {
+        log_event(EventKind::SupplyMin, button, self.min_mv);
+        log_event(EventKind::SupplyMax, button, self.max_mv);
+        log_event(EventKind::SupplyPeakCurrent, button, self.peak_ma);
+    }
+}
+
+// The capture, kept in RAM that isn't cleared at boot. If the Pico resets in the middle of
+// a move, the next boot can still see what the supply did up to then. After a real power
+// cycle, there can be anything in there, hence the magic and the check.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct CaptureRecord {
+    magic: u32,
+    moving: u32, // Non-zero while the move is still going.
+    capture: SupplyCapture,
+    check: u32,
+}
+
+impl CaptureRecord {
+    fn checksum(&self) -> u32 {
+        let c = &self.capture;
+        self.magic
+            ^ self.moving
+            ^ (c.move_id as u32) << 8
+            ^ ((c.min_mv as u32) << 16 | c.max_mv as u32)
+            ^ ((c.peak_ma as u32) << 16 | c.samples as u32)
+    }
+}
+
+#[link_section = ".uninit.SUPPLY_CAPTURE"]
+static mut CAPTURE_RECORD: MaybeUninit<CaptureRecord> = MaybeUninit::uninit();
+
+fn save_capture(capture: &SupplyCapture, moving: bool) {
+    let mut record = CaptureRecord {
+        magic: CAPTURE_MAGIC,
+        moving: moving as u32,
+        capture: *capture,
+        check: 0,
+    };
+    record.check = record.checksum();
+    unsafe { (*core::ptr::addr_of_mut!(CAPTURE_RECORD)).write(record) };
+}
+
+// The capture from before the last reset, and if that reset came in the middle of the move.
+// Only once, it's forgotten after this.
+pub fn take_supply_capture() -> Option<(SupplyCapture, bool)> {
+    // All integers, so any bit pattern is a valid (if meaningless) record.
+    let record = unsafe { (*core::ptr::addr_of!(CAPTURE_RECORD)).assume_init_read() };
+    if record.magic != CAPTURE_MAGIC || record.check != record.checksum() {
+        return None;
+    }
+
+    unsafe {
+        (*core::ptr::addr_of_mut!(CAPTURE_RECORD))
+            .as_mut_ptr()
+            .write_bytes(0, 1)
+    };
+    Some((record.capture, record.moving != 0))
+}
+
+// The actuator calls these around each move.
+pub fn start_supply_capture() -> u16 {
+    let move_id = MOVE_ID.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+    SIGNAL_SUPPLY_CAPTURE.signal(move_id);
+    move_id
+}
+
+pub fn stop_supply_capture(move_id: u16) {
+    MOVE_DONE.store(move_id, Ordering::SeqCst);
+}
+
+#[derive(Copy, Clone, Format)]
+pub struct UpsStatus {
+    pub on_battery: bool,
+    pub soc: u8,                        // %
+    pub time_to_empty_min: Option<u16>, // Only while discharging.
+}
+
+// `None` if there's no UPS, or it can't be read (or it haven't been read yet).
+pub fn ups_status() -> Option<UpsStatus> {
+    if power_monitor_status() != PowerMonitorStatus::Present {
+        return None;
+    }
+
+    Some(UpsStatus {
+        on_battery: UPS_ON_BATTERY.load(Ordering::SeqCst),
+        soc: UPS_SOC.load(Ordering::SeqCst),
+        time_to_empty_min: match UPS_TIME_TO_EMPTY.load(Ordering::SeqCst) {
+            TIME_TO_EMPTY_UNKNOWN => None,
+            minutes => Some(minutes),
+        },
+    })
+}
+
+// State of charge (%) from the open circuit voltage, interpolated from the table.
+fn soc_from_ocv(voltage_mv: u16) -> f32 {
+    let (first_mv, first_soc) = OCV_TABLE[0];
+    if voltage_mv <= first_mv {
+        return first_soc as f32;
+    }
+
+    for pair in OCV_TABLE.windows(2) {
+        let ((low_mv, low_soc), (high_mv, high_soc)) = (pair[0], pair[1]);
+        if voltage_mv <= high_mv {
+            let fraction = (voltage_mv - low_mv) as f32 / (high_mv - low_mv) as f32;
+            return low_soc as f32 + fraction * (high_soc - low_soc) as f32;
+        }
+    }
+
+    100.0
+}
+
+// Count the charge going in and out of the battery. The counter drifts (the current is only
+// sampled now and then, and the capacity is a guess), so whenever the battery have been
+// resting for a while, it's pulled towards what the open circuit voltage says.
+// The readings don't come exactly once a second (not while the supply is captured during a
+// move, or after a retry), so everything goes by the time since the last one.
+struct SocEstimator {
+    soc: f32,        // %
+    current_ma: f32, // Filtered, positive is charging.
+    resting_since: Option<Instant>,
+    last: Instant,
+}
+
+impl SocEstimator {
+    // Nothing to count from yet, so start from the voltage.
+    fn new(voltage_mv: u16) -> Self {
+        Self {
+            soc: soc_from_ocv(voltage_mv),
+            current_ma: 0.0,
+            resting_since: None,
+            last: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, voltage_mv: u16, current_ma: f32) {
+        let now = Instant::now();
+        let elapsed_ms = (now - self.last).as_millis() as f32;
+        let hours = elapsed_ms / 3_600_000.0;
+        self.last = now;
+
+        self.soc += current_ma * hours / BATTERY_CAPACITY_MAH * 100.0;
+        self.current_ma += (current_ma - self.current_ma) / CURRENT_FILTER;
+
+        if current_ma > -REST_CURRENT_MA && current_ma < REST_CURRENT_MA {
+            let since = *self.resting_since.get_or_insert(now);
+            if (now - since).as_secs() >= REST_SECS {
+                let correction = (DRIFT_CORRECTION * elapsed_ms / 1_000.0).min(1.0);
+                self.soc += (soc_from_ocv(voltage_mv) - self.soc) * correction;
+            }
+        } else {
+            self.resting_since = None;
+        }
+
+        self.soc = self.soc.clamp(0.0, 100.0);
+    }
+
+    // Minutes until empty, at the current rate. Only when discharging.
+    fn time_to_empty_min(&self) -> Option<u16> {
+        if self.current_ma > -REST_CURRENT_MA {
+            return None;
+        }
+
+        let remaining_mah = self.soc / 100.0 * BATTERY_CAPACITY_MAH;
+        Some(
+            (remaining_mah / -self.current_ma * 60.0).min((TIME_TO_EMPTY_UNKNOWN - 1) as f32)
+                as u16,
+        )
+    }
+}
+
+// Don't hammer a chip that's playing up. Returns how long to wait this time.
+fn back_off(secs: &mut u64) -> u64 {
+    let wait = *secs;
+    *secs = (*secs * 2).min(MAX_BACKOFF_SECS);
+    wait
+}
 
 #[embassy_executor::task]
-pub async fn ups_monitor(ups: PeriPowerMonitor) {
+pub async fn ups_monitor(bus: &'static I2cBus) {
     let mut state_battery: bool = false;
     let mut state_power: bool = true;
 
-    let i2c = I2c::new_async(ups.i2c, ups.scl, ups.sda, Irqs, Config::default());
-
-    // Become a publisher on the button state channel.
-    let publisher = CHANNEL_BUTTON_STATE.publisher().unwrap();
-
-    // Resolution of 1A, and a shunt of 10mΩ.
-    // The shunt resistor in the Pico UPS Hat B: R1/0.01Ω (10,000µΩ/10mΩ).
-    //let calib = IntCalibration::new(MicroAmpere(1_000_000), 10_000).unwrap();
-    let calib = IntCalibration::new(MicroAmpere(100), 10_000).unwrap();
-    match SyncIna219::new_calibrated(i2c, Address::from_byte(UPS_ADDRESS).unwrap(), calib) {
-        Err(InitializationError {
-            reason: I2cError(Abort(NoAcknowledge)),
-            ..
-        }) => {
-            warn!("Can't initialize the UPS. Not connected?");
-            return ();
-        }
-        Ok(mut ina) => {
-            info!("UPS monitor running");
-
-            let mut cnt: u8 = 0;
-            loop {
-                let measurement = ina
-                    .next_measurement()
-                    .unwrap()
-                    .expect("A measurement is ready");
-                let shunt_voltage_uv = measurement.shunt_voltage.shunt_voltage_uv();
-
-                // Calculate how much charge is left.
-                let bus_voltage = measurement.bus_voltage.voltage_mv() / 1_000;
-                let mut charge: i16 = (((bus_voltage - 3) as f32) / 1.2 * 100.0) as i16;
-                if charge < 0 {
-                    charge = 0;
-                } else if charge > 100 {
-                    charge = 100;
+    let mut estimator: Option<SocEstimator> = None;
+    let mut backoff_secs: u64 = 1;
+    let mut last_report: Option<Instant> = None;
+    register(Task::Ups);
+    loop {
+        check_in(Task::Ups);
+
+        let i2c = SharedI2c::new(bus, BusDevice::Ups);
+
+        // Resolution of 1A, and a shunt of 10mΩ.
+        // The shunt resistor in the Pico UPS Hat B: R1/0.01Ω (10,000µΩ/10mΩ).
+        //let calib = IntCalibration::new(MicroAmpere(1_000_000), 10_000).unwrap();
+        let calib = IntCalibration::new(MicroAmpere(100), 10_000).unwrap();
+        let wait_secs =
+            match AsyncIna219::new_calibrated(i2c, Address::from_byte(UPS_ADDRESS).unwrap(), calib)
+                .await
+            {
+                Err(InitializationError {
+                    reason: I2cError(BusError::I2c(Abort(NoAcknowledge))),
+                    ..
+                }) => {
+                    if power_monitor_status() == PowerMonitorStatus::Unknown {
+                        warn!("Can't initialize the UPS. Not connected?");
+                    }
+                    set_monitor_status(PowerMonitorStatus::Absent);
+                    REDETECT_SECS
                 }
+                Err(_) => {
+                    error!(
+                        "Failed to initialize the UPS, retrying in {}s",
+                        backoff_secs
+                    );
+                    recover_bus(bus).await;
+                    set_monitor_status(PowerMonitorStatus::Faulty);
+                    back_off(&mut backoff_secs)
+                }
+                Ok(mut ina) => {
+                    info!("UPS monitor running");
 
-                // Every 60s, let's output some stats.
-                if (cnt % 60) == 0 {
-                    debug!("Power:           {}", measurement.power);
-                    debug!("Current:         {}", measurement.current);
-                    info!("Charge:          {}%", charge);
+                    let mut failures: u8 = 0;
+                    loop {
+                        check_in(Task::Ups);
 
-                    debug!("Voltage (Bus):   {=f32:#02}V", bus_voltage as f32);
+                        let measurement = match ina.next_measurement().await {
+                            Ok(Some(measurement)) => {
+                                failures = 0;
+                                measurement
+                            }
+                            // Not converted yet, nothing wrong with that. Try again shortly.
+                            Ok(None) => {
+                                Timer::after_millis(RETRY_MS).await;
+                                continue;
+                            }
+                            Err(_) => {
+                                failures += 1;
+                                if failures > READ_RETRIES {
+                                    error!("UPS stopped answering, starting over");
+                                    break;
+                                }
+                                warn!(
+                                    "Failed to read the UPS, retry {}/{}",
+                                    failures, READ_RETRIES
+                                );
+                                Timer::after_millis(RETRY_MS << failures).await;
+                                continue;
+                            }
+                        };
+                        set_monitor_status(PowerMonitorStatus::Present);
+                        backoff_secs = 1;
+                        let shunt_voltage_uv = measurement.shunt_voltage.shunt_voltage_uv();
 
-                    let shunt_voltage_mv = measurement.shunt_voltage.shunt_voltage_mv();
-                    debug!(
-                        "Voltage (Shunt): {=f32:#02}mV ({=f32:#02}µV)",
-                        shunt_voltage_mv as f32, shunt_voltage_uv as f32,
-                    );
+                        // Calculate how much charge is left. The shunt voltage is negative
+                        // when discharging.
+                        let bus_voltage_mv = measurement.bus_voltage.voltage_mv();
+                        let current_ma = (shunt_voltage_uv * SHUNT_UA_PER_UV) as f32 / 1_000.0;
+                        let soc =
+                            estimator.get_or_insert_with(|| SocEstimator::new(bus_voltage_mv));
+                        soc.update(bus_voltage_mv, current_ma);
 
-                    cnt = 0; // Reset counter
-                }
+                        let charge = soc.soc as u8;
+                        let time_to_empty = soc.time_to_empty_min();
+                        UPS_SOC.store(charge, Ordering::SeqCst);
+                        UPS_TIME_TO_EMPTY.store(
+                            time_to_empty.unwrap_or(TIME_TO_EMPTY_UNKNOWN),
+                            Ordering::SeqCst,
+                        );
 
-                if ((shunt_voltage_uv as i16) < -350) && !state_battery {
-                    info!("=> On battery ({=f32:#02}µV)", shunt_voltage_uv as f32);
+                        // Every minute, let's output some stats. What the other tasks see
+                        // goes on the console, the rest is for debugging.
+                        if last_report.is_none_or(|last| last.elapsed().as_secs() >= REPORT_SECS) {
+                            if let Some(status) = ups_status() {
+                                info!("UPS:             {}", status);
+                            }
+                            debug!("Power:           {}", measurement.power);
+                            debug!("Current:         {=f32:#02}mA", current_ma);
 
-                    state_battery = true;
-                    state_power = false;
+                            debug!("Voltage (Bus):   {}mV", bus_voltage_mv);
 
-                    publisher.publish_immediate(ButtonState::Stop);
-                } else if ((shunt_voltage_uv as i16) > -50) && !state_power {
-                    info!("=> On power ({=f32:#02}µV)", shunt_voltage_uv as f32);
+                            let shunt_voltage_mv = measurement.shunt_voltage.shunt_voltage_mv();
+                            debug!(
+                                "Voltage (Shunt): {=f32:#02}mV ({=f32:#02}µV)",
+                                shunt_voltage_mv as f32, shunt_voltage_uv as f32,
+                            );
 
-                    state_battery = false;
-                    state_power = true;
+                            last_report = Some(Instant::now());
+                        }
 
-                    publisher.publish_immediate(ButtonState::Start);
-                }
+                        if ((shunt_voltage_uv as i16) < -350) && !state_battery {
+                            info!("=> On battery ({=f32:#02}µV)", shunt_voltage_uv as f32);
 
-                cnt = cnt + 1;
-                Timer::after_secs(1).await;
-            }
-        }
-        _ => {}
+                            state_battery = true;
+                            state_power = false;
+                            UPS_ON_BATTERY.store(true, Ordering::SeqCst);
+                            SIGNAL_ON_BATTERY.signal(true);
+                        } else if ((shunt_voltage_uv as i16) > -50) && !state_power {
+                            info!("=> On power ({=f32:#02}µV)", shunt_voltage_uv as f32);
+
+                            state_battery = false;
+                            state_power = true;
+                            UPS_ON_BATTERY.store(false, Ordering::SeqCst);
+                            SIGNAL_ON_BATTERY.signal(false);
+                        }
+
+                        // Until the next reading, unless the actuator is about to move. Then watch
+                        // the supply closely until it's done.
+                        if let Either::Second(move_id) =
+                            select(Timer::after_secs(1), SIGNAL_SUPPLY_CAPTURE.wait()).await
+                        {
+                            let mut capture = SupplyCapture::new(move_id);
+                            let started = Instant::now();
+                            while MOVE_DONE.load(Ordering::SeqCst) != move_id
+                                && started.elapsed().as_millis() < CAPTURE_MAX_MS
+                            {
+                                // Not ready yet, or a glitch. Either way, try again next time.
+                                if let Ok(Some(measurement)) = ina.next_measurement().await {
+                                    capture.add(
+                                        measurement.bus_voltage.voltage_mv(),
+                                        measurement.shunt_voltage.shunt_voltage_uv()
+                                            * SHUNT_UA_PER_UV
+                                            / 1_000,
+                                    );
+                                    save_capture(&capture, true);
+                                }
+                                Timer::after_micros(CAPTURE_PERIOD_US).await;
+                            }
+
+                            save_capture(&capture, false);
+                            debug!("Supply during the move: {:?}", capture);
+                            if capture.sagged() {
+                                warn!("Supply sagged during move {}", move_id);
+                                capture.log_events(unsafe { BUTTON_ENABLED });
+                            }
+                        }
+                    }
+
+                    // Stopped answering in the middle of it, might have left the bus hanging.
+                    recover_bus(bus).await;
+                    set_monitor_status(PowerMonitorStatus::Faulty);
+                    back_off(&mut backoff_secs)
+                }
+            };
+        check_in(Task::Ups);
+        Timer::after_secs(wait_secs).await;
     }
 }
@@ -1,10 +1,385 @@
-use defmt::{error, info};
+use defmt::{debug, error, info, Format};
 
-use embassy_rp::watchdog::Watchdog;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
+use embassy_rp::{pac, watchdog::Watchdog};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 
+// External "defines".
 use crate::lib_resources::PeriWatchdog;
+use crate::lib_status::reset_requested;
+
+// The tasks the watchdog keeps an eye on. Don't renumber these, they're stored in the event log.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum Task {
+    Actuator = 0,
+    CanReader = 1,
+    CanWriter = 2,
+    CanDecoder = 3,
+    Ups = 4,
+    Scanner = 5, // Not the task, whoever have waited the longest for the fingerprint scanner.
+}
+
+pub struct TaskInfo {
+    pub task: Task,
+    pub name: &'static str,
+    pub deadline_ms: u32, // Longest time between two check-ins.
+}
+
+// Indexed by `Task`.
+pub const TASKS: [TaskInfo; 6] = [
+    TaskInfo {
+        task: Task::Actuator,
+        name: "actuator control",
+        deadline_ms: 30_000, // Checks in for every move, and every retry of it.
+    },
+    TaskInfo {
+        task: Task::CanReader,
+        name: "CAN reader",
+        deadline_ms: 10_000,
+    },
+    TaskInfo {
+        task: Task::CanWriter,
+        name: "CAN writer",
+        deadline_ms: 10_000,
+    },
+    TaskInfo {
+        task: Task::CanDecoder,
+        name: "CAN decoder",
+        deadline_ms: 10_000,
+    },
+    TaskInfo {
+        task: Task::Ups,
+        name: "UPS monitor",
+        deadline_ms: 90_000, // The longest back-off, and then some.
+    },
+    TaskInfo {
+        task: Task::Scanner,
+        name: "fingerprint scanner lock",
+        deadline_ms: 60_000,
+    },
+];
+
+impl Task {
+    pub fn from_integer(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Actuator),
+            1 => Some(Self::CanReader),
+            2 => Some(Self::CanWriter),
+            3 => Some(Self::CanDecoder),
+            4 => Some(Self::Ups),
+            5 => Some(Self::Scanner),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        TASKS[self as usize].name
+    }
+}
+
+// Tasks that only wake up when there's something to do, wake up this often just to check in.
+pub const CHECK_IN_SECS: u64 = 5;
+
+// One bit per `Task`, and when each of them last checked in (ms since boot).
+static TASKS_REGISTERED: AtomicU32 = AtomicU32::new(0);
+static TASKS_CHECKED_IN: [AtomicU32; 6] = [const { AtomicU32::new(0) }; 6];
+
+// Which task the watchdog gave up on, for the next boot. The scratch registers survive a
+// watchdog reset (but not a power on).
+const HUNG_TASK_SCRATCH: usize = 0;
+const HUNG_TASK_MAGIC: u32 = 0x4855_4E00; // "HUN\0"
+
+// How far the boot got, the same way. Left at `Running` once it's done.
+const BOOT_STAGE_SCRATCH: usize = 1;
+const BOOT_STAGE_MAGIC: u32 = 0x5354_4700; // "STG\0"
+
+// The steps in `main()`, numbered the same.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum BootStage {
+    Logging = 1,
+    Leds = 3,
+    I2c = 4,
+    Relays = 5,
+    Flash = 6,
+    Actuator = 7,
+    Login = 9,
+    Buttons = 10,
+    BootGear = 12,
+    Ignition = 13,
+    StartCar = 14,
+    Running = 15,
+}
+
+impl BootStage {
+    pub fn from_integer(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Logging),
+            3 => Some(Self::Leds),
+            4 => Some(Self::I2c),
+            5 => Some(Self::Relays),
+            6 => Some(Self::Flash),
+            7 => Some(Self::Actuator),
+            9 => Some(Self::Login),
+            10 => Some(Self::Buttons),
+            12 => Some(Self::BootGear),
+            13 => Some(Self::Ignition),
+            14 => Some(Self::StartCar),
+            15 => Some(Self::Running),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_boot_stage(stage: BootStage) {
+    debug!("Boot stage: {}", stage);
+    pac::WATCHDOG
+        .scratch(BOOT_STAGE_SCRATCH)
+        .write_value(BOOT_STAGE_MAGIC | stage as u32);
+}
+
+// Read a breadcrumb from a scratch register, and clear it for next time.
+fn take_scratch(index: usize, magic: u32) -> Option<u8> {
+    let scratch = pac::WATCHDOG.scratch(index);
+    let value = scratch.read();
+    scratch.write_value(0);
+
+    if value & 0xFFFF_FF00 != magic {
+        return None;
+    }
+    Some(value as u8)
+}
+
+// The last panic, kept in RAM that isn't cleared at boot (like the supply capture in
+// `lib_ups.rs`). Only the end of the file name, and the start of the message.
+const PANIC_MAGIC: u32 = 0x9A41_C001;
+const PANIC_FILE_LEN: usize = 32;
+const PANIC_MESSAGE_LEN: usize = 64;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    pub line: u32,
+    pub column: u32,
+    file_len: u32,
+    message_len: u32,
+    file: [u8; PANIC_FILE_LEN],
+    message: [u8; PANIC_MESSAGE_LEN],
+    check: u32,
+}
+
+impl PanicRecord {
+    fn checksum(&self) -> u32 {
+        let header = self.magic ^ self.line ^ self.column ^ self.file_len ^ self.message_len;
+        self.file
+            .iter()
+            .chain(self.message.iter())
+            .fold(header, |check, byte| check.rotate_left(5) ^ *byte as u32)
+    }
+
+    pub fn file(&self) -> &str {
+        text(&self.file, self.file_len)
+    }
+
+    pub fn message(&self) -> &str {
+        text(&self.message, self.message_len)
+    }
+}
+
+// Whatever is valid UTF-8 of it - the message might have been cut in the middle of a character.
+fn text(buf: &[u8], len: u32) -> &str {
+    let buf = &buf[..(len as usize).min(buf.len())];
+    match core::str::from_utf8(buf) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+// Formats the message into the record, and drops what doesn't fit.
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+// Stop feeding the watchdog, it resets us.
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+// For the panic handler. Write down what happened and where, and have the watchdog reset us.
+// Before the watchdog is running (step 4 in `main()`), we just stop.
+pub fn record_panic(info: &PanicInfo) {
+    let mut record = PanicRecord {
+        magic: PANIC_MAGIC,
+        line: 0,
+        column: 0,
+        file_len: 0,
+        message_len: 0,
+        file: [0; PANIC_FILE_LEN],
+        message: [0; PANIC_MESSAGE_LEN],
+        check: 0,
+    };
+
+    if let Some(location) = info.location() {
+        let file = location.file().as_bytes();
+        let file = &file[file.len().saturating_sub(PANIC_FILE_LEN)..];
+        record.file[..file.len()].copy_from_slice(file);
+        record.file_len = file.len() as u32;
+        record.line = location.line();
+        record.column = location.column();
+    }
+
+    let mut message = Truncate {
+        buf: &mut record.message,
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+    record.message_len = message.len as u32;
+
+    record.check = record.checksum();
+    unsafe { (*core::ptr::addr_of_mut!(PANIC_RECORD)).write(record) };
+
+    PANICKED.store(true, Ordering::SeqCst);
+}
+
+fn panic_record() -> Option<PanicRecord> {
+    // All integers, so any bit pattern is a valid (if meaningless) record.
+    let record = unsafe { (*core::ptr::addr_of!(PANIC_RECORD)).assume_init_read() };
+    if record.magic != PANIC_MAGIC || record.check != record.checksum() {
+        return None;
+    }
+    Some(record)
+}
+
+// Why the Pico (re)started. Don't renumber these, they're stored in the event log.
+#[derive(Copy, Clone, Format, PartialEq)]
+#[repr(u8)]
+pub enum ResetReason {
+    Unknown = 0,
+    PowerOn = 1,  // Power on, or a brown-out.
+    RunPin = 2,   // The RUN pin (the reset button).
+    Debugger = 3, // Through the SWD port.
+    Watchdog = 4, // Nobody fed the watchdog.
+    Forced = 5,   // The watchdog was told to reset (a soft reset).
+    Panic = 6,    // We panicked, and then the watchdog reset us.
+}
+
+// The watchdog logs its own resets, and is cleared by the others. The chip reset register
+// keeps what the last of the others was.
+fn reset_reason() -> ResetReason {
+    if panic_record().is_some() {
+        return ResetReason::Panic;
+    }
+
+    let watchdog = pac::WATCHDOG.reason().read();
+    if watchdog.timer() {
+        return ResetReason::Watchdog;
+    }
+    if watchdog.force() {
+        return ResetReason::Forced;
+    }
+
+    let chip = pac::VREG_AND_CHIP_RESET.chip_reset().read();
+    if chip.had_psm_restart() {
+        ResetReason::Debugger
+    } else if chip.had_run() {
+        ResetReason::RunPin
+    } else if chip.had_por() {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+// What the last run left behind. Only once, it's all forgotten after this. Must be called
+// before the first `set_boot_stage()`.
+pub struct Breadcrumbs {
+    pub reason: ResetReason,
+    pub stage: Option<BootStage>,
+    pub hung: Option<Task>,
+    pub panic: Option<PanicRecord>,
+}
+
+pub fn take_breadcrumbs() -> Breadcrumbs {
+    let breadcrumbs = Breadcrumbs {
+        reason: reset_reason(),
+        stage: take_scratch(BOOT_STAGE_SCRATCH, BOOT_STAGE_MAGIC).and_then(BootStage::from_integer),
+        hung: take_scratch(HUNG_TASK_SCRATCH, HUNG_TASK_MAGIC).and_then(Task::from_integer),
+        panic: panic_record(),
+    };
+
+    unsafe {
+        (*core::ptr::addr_of_mut!(PANIC_RECORD))
+            .as_mut_ptr()
+            .write_bytes(0, 1)
+    };
+    breadcrumbs
+}
+
+impl Breadcrumbs {
+    // Anything other than a power on or someone pressing reset.
+    pub fn unexpected(&self) -> bool {
+        matches!(self.reason, ResetReason::Watchdog | ResetReason::Panic)
+    }
+}
+
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+// Start keeping an eye on the task. It has to check in within its deadline from now on.
+pub fn register(task: Task) {
+    TASKS_CHECKED_IN[task as usize].store(now_ms(), Ordering::SeqCst);
+    TASKS_REGISTERED.fetch_or(1 << task as u8, Ordering::SeqCst);
+}
+
+// The same, but counting from `since`. For when there's several waiting for the same thing,
+// and the deadline is for the one that have waited the longest.
+pub fn register_since(task: Task, since: Instant) {
+    TASKS_CHECKED_IN[task as usize].store(since.as_millis() as u32, Ordering::SeqCst);
+    TASKS_REGISTERED.fetch_or(1 << task as u8, Ordering::SeqCst);
+}
+
+// For the things that takes as long as they take, like a calibration.
+pub fn unregister(task: Task) {
+    TASKS_REGISTERED.fetch_and(!(1 << task as u8), Ordering::SeqCst);
+}
+
+pub fn check_in(task: Task) {
+    TASKS_CHECKED_IN[task as usize].store(now_ms(), Ordering::SeqCst);
+}
+
+// The first registered task that haven't checked in on time, if any.
+fn overdue() -> Option<Task> {
+    let registered = TASKS_REGISTERED.load(Ordering::SeqCst);
+    let now = now_ms();
+
+    TASKS
+        .iter()
+        .filter(|info| registered & (1 << info.task as u8) != 0)
+        .find(|info| {
+            let last = TASKS_CHECKED_IN[info.task as usize].load(Ordering::SeqCst);
+            now.wrapping_sub(last) > info.deadline_ms
+        })
+        .map(|info| info.task)
+}
 
 pub enum StopWatchdog {
     Yes,
@@ -13,6 +388,7 @@ pub enum StopWatchdog {
 pub static CHANNEL_WATCHDOG: Channel<CriticalSectionRawMutex, StopWatchdog, 64> = Channel::new();
 
 // Doggy is hungry, needs to be feed every three quarter second, otherwise it gets cranky! :)
+// But only as long as all the registered tasks are checking in.
 #[embassy_executor::task]
 pub async fn feed_watchdog(doggy: PeriWatchdog) {
     info!("Watchdog timer running");
@@ -28,8 +404,28 @@ pub async fn feed_watchdog(doggy: PeriWatchdog) {
                 error!("StopWatchdog = Yes received");
                 return;
             }
+            // Someone gave up, let the watchdog reset us.
+            _ if reset_requested() => {
+                error!("Reset requested, stopping the watchdog");
+                return;
+            }
+            // Someone panicked, same thing. It's already logged.
+            _ if PANICKED.load(Ordering::SeqCst) => {
+                return;
+            }
             _ => {
                 Timer::after_millis(750).await;
+
+                // Someone's hung, let the watchdog reset us. Remember who it was.
+                if let Some(task) = overdue() {
+                    error!(
+                        "The {} task stopped checking in, stopping the watchdog",
+                        task.name()
+                    );
+                    watchdog.set_scratch(HUNG_TASK_SCRATCH, HUNG_TASK_MAGIC | task as u32);
+                    return;
+                }
+
                 watchdog.feed();
                 continue;
             }
@@ -3,14 +3,37 @@
 
 //! Connect to the actuator and move it 10mm backward.
 
-use defmt::info;
+use defmt::{error, info};
 
 use embassy_executor::Spawner;
 use embassy_rp::{adc::InterruptHandler, bind_interrupts};
 
-use actuator::{Actuator, RESISTANCE_THROW_1MM};
-
+// External "defines". All because we need the `Button` define!!
+pub mod lib_actuator;
+pub mod lib_buttons;
+pub mod lib_calibration;
+pub mod lib_can_bus;
+pub mod lib_can_frame;
+pub mod lib_config;
+pub mod lib_dc_actuator;
+pub mod lib_eventlog;
+pub mod lib_expander;
+pub mod lib_gear;
+pub mod lib_gear_actuator;
+pub mod lib_i2c;
+pub mod lib_leds;
+pub mod lib_linear_actuator;
+pub mod lib_motion;
+pub mod lib_motor_current;
+pub mod lib_pot;
 pub mod lib_resources;
+pub mod lib_status;
+pub mod lib_stepper_actuator;
+pub mod lib_ups;
+pub mod lib_watchdog;
+
+use crate::lib_buttons::Button;
+use crate::lib_gear_actuator::{Actuator, GearActuator};
 use crate::lib_resources::*;
 
 use {defmt_rtt as _, panic_probe as _};
@@ -20,28 +43,21 @@ bind_interrupts!(struct Irqs {
 });
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let r = split_resources! {p};
 
-    let mut actuator = Actuator::new(
-        r.actuator.mplus.into(),  // pin_motor_plus
-        r.actuator.mminus.into(), // pin_motor_minus
-        r.actuator.vsel.into(),   // pin_volt_select - UART0
-        r.actuator.pot,           // pin_pot         - ADC2
-        r.actuator.adc,
-        Irqs,
-    );
-
-    info!(
-        "Actuator potentiometer value (#1): {}Ω",
-        actuator.read_pot().await
-    );
-    actuator.move_actuator(RESISTANCE_THROW_1MM * 10).await;
-    info!(
-        "Actuator potentiometer value (#2): {}Ω",
-        actuator.read_pot().await
-    );
+    let mut actuator = Actuator::new(r.actuator, Irqs, spawner.make_send());
+
+    let position = actuator.read_position().await;
+    info!("Actuator position (#1): {}", position);
+    if let Err(e) = actuator
+        .move_to(position.saturating_sub(Actuator::POSITION_1MM * 10))
+        .await
+    {
+        error!("Actuator failed to move: {:?}", e);
+    }
+    info!("Actuator position (#2): {}", actuator.read_position().await);
 
     #[allow(clippy::empty_loop)]
     loop {}
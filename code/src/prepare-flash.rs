@@ -17,15 +17,14 @@ use embassy_rp::{
 
 use {defmt_rtt as _, panic_probe as _};
 
-pub mod lib_actuator;
-pub mod lib_buttons;
-pub mod lib_can_bus;
 pub mod lib_config;
+pub mod lib_eventlog;
+pub mod lib_gear;
 pub mod lib_resources;
-pub mod lib_ups;
 
-use crate::lib_buttons::Button;
 use crate::lib_config::init_flash;
+use crate::lib_eventlog::{EVENTLOG_ADDR, EVENTLOG_SIZE};
+use crate::lib_gear::Button;
 use crate::lib_resources::*;
 
 #[embassy_executor::main]
@@ -41,6 +40,7 @@ async fn main(_spawner: Spawner) {
     {
         let mut flash = flash.lock().await;
         erase_write_sector(&mut flash);
+        erase_event_log(&mut flash);
     }
 
     #[allow(clippy::empty_loop)]
@@ -87,3 +87,24 @@ fn erase_write_sector(flash: &mut Flash<'_, FLASH, Blocking, FLASH_SIZE>) {
         defmt::panic!("unexpected (2)");
     }
 }
+
+// The event log goes with the config, start it over as well. An erased sector is an empty log.
+fn erase_event_log(flash: &mut Flash<'_, FLASH, Blocking, FLASH_SIZE>) {
+    info!(">>>> [erase_event_log]");
+    let mut buf = [0u8; ERASE_SIZE];
+
+    info!(
+        "Addr of event log is {:x}",
+        EVENTLOG_ADDR + FLASH_BASE as u32
+    );
+    defmt::unwrap!(flash.blocking_erase(EVENTLOG_ADDR, EVENTLOG_ADDR + EVENTLOG_SIZE));
+
+    // All of the sectors.
+    for addr in (EVENTLOG_ADDR..EVENTLOG_ADDR + EVENTLOG_SIZE).step_by(ERASE_SIZE) {
+        defmt::unwrap!(flash.blocking_read(addr, &mut buf));
+        info!("Event log after erase starts with {=[u8]}", buf[0..4]);
+        if buf.iter().any(|x| *x != 0xFF) {
+            defmt::panic!("unexpected (3)");
+        }
+    }
+}
@@ -1,7 +1,8 @@
 #![no_std]
 #![no_main]
 
-//! Connect to the actuator and read the feedback potentiometer.
+//! Connect to the actuator and read its position (the feedback potentiometer, on the
+//! linear actuator).
 
 use defmt::info;
 
@@ -9,9 +10,32 @@ use embassy_executor::Spawner;
 use embassy_rp::{adc::InterruptHandler, bind_interrupts};
 use embassy_time::Timer;
 
-use actuator::Actuator;
-
+// External "defines". All because we need the `Button` define!!
+pub mod lib_actuator;
+pub mod lib_buttons;
+pub mod lib_calibration;
+pub mod lib_can_bus;
+pub mod lib_can_frame;
+pub mod lib_config;
+pub mod lib_dc_actuator;
+pub mod lib_eventlog;
+pub mod lib_expander;
+pub mod lib_gear;
+pub mod lib_gear_actuator;
+pub mod lib_i2c;
+pub mod lib_leds;
+pub mod lib_linear_actuator;
+pub mod lib_motion;
+pub mod lib_motor_current;
+pub mod lib_pot;
 pub mod lib_resources;
+pub mod lib_status;
+pub mod lib_stepper_actuator;
+pub mod lib_ups;
+pub mod lib_watchdog;
+
+use crate::lib_buttons::Button;
+use crate::lib_gear_actuator::{Actuator, GearActuator};
 use crate::lib_resources::*;
 
 use {defmt_rtt as _, panic_probe as _};
@@ -21,24 +45,14 @@ bind_interrupts!(struct Irqs {
 });
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let r = split_resources! {p};
 
-    let mut actuator = Actuator::new(
-        r.actuator.mplus.into(),  // pin_motor_plus
-        r.actuator.mminus.into(), // pin_motor_minus
-        r.actuator.vsel.into(),   // pin_volt_select - UART0
-        r.actuator.pot,           // pin_pot         - ADC2
-        r.actuator.adc,
-        Irqs,
-    );
+    let mut actuator = Actuator::new(r.actuator, Irqs, spawner.make_send());
 
     loop {
-        info!(
-            "Actuator potentiometer value: {}Ω",
-            actuator.read_pot().await
-        );
+        info!("Actuator position: {}", actuator.read_position().await);
 
         Timer::after_secs(5).await;
     }
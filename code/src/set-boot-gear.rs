@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+//! Set what gear to go to when the car starts, in the flash.
+//! Change `BOOT_GEAR` below to the policy wanted, then run it.
+
+use defmt::{error, info};
+use embassy_executor::Spawner;
+
+pub mod lib_config;
+pub mod lib_gear;
+pub mod lib_resources;
+
+use crate::lib_config::{init_flash, BootGear, DbwConfig};
+use crate::lib_gear::Button;
+use crate::lib_resources::*;
+
+use {defmt_rtt as _, panic_probe as _};
+
+// RestoreLast, Park or FollowTransmission.
+const BOOT_GEAR: BootGear = BootGear::Park;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let r = split_resources! {p};
+
+    info!("Setting boot gear to {} in flash", BOOT_GEAR);
+
+    // Instantiate the flash.
+    let flash = init_flash(r.flash);
+
+    // Read old values.
+    let mut flash = flash.lock().await;
+    match DbwConfig::read(&mut flash) {
+        Ok(mut config) => {
+            config.boot_gear = BOOT_GEAR;
+
+            // Write flash.
+            lib_config::write_flash(&mut flash, config).await;
+        }
+        Err(e) => error!("Failed to read flash: {:?}", e),
+    }
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
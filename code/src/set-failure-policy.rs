@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+//! Set what to do when the actuator (or the CAN-bus) fails, in the flash.
+//! Change `FAILURE_POLICY` below to the policy wanted, then run it.
+
+use defmt::{error, info};
+use embassy_executor::Spawner;
+
+pub mod lib_config;
+pub mod lib_gear;
+pub mod lib_resources;
+
+use crate::lib_config::{init_flash, DbwConfig, FailurePolicy};
+use crate::lib_gear::Button;
+use crate::lib_resources::*;
+
+use {defmt_rtt as _, panic_probe as _};
+
+// LimpHome or Reset.
+const FAILURE_POLICY: FailurePolicy = FailurePolicy::Reset;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let r = split_resources! {p};
+
+    info!("Setting failure policy to {} in flash", FAILURE_POLICY);
+
+    // Instantiate the flash.
+    let flash = init_flash(r.flash);
+
+    // Read old values.
+    let mut flash = flash.lock().await;
+    match DbwConfig::read(&mut flash) {
+        Ok(mut config) => {
+            config.failure_policy = FAILURE_POLICY;
+
+            // Write flash.
+            lib_config::write_flash(&mut flash, config).await;
+        }
+        Err(e) => error!("Failed to read flash: {:?}", e),
+    }
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+//! Set how to shut down on the UPS battery, in the flash.
+//! Change `HOLD_SECS`, `PARK` and `WITHOUT_CAN` below, then run it.
+
+use defmt::{error, info};
+use embassy_executor::Spawner;
+
+pub mod lib_config;
+pub mod lib_gear;
+pub mod lib_resources;
+
+use crate::lib_config::{init_flash, DbwConfig};
+use crate::lib_gear::Button;
+use crate::lib_resources::*;
+
+use {defmt_rtt as _, panic_probe as _};
+
+// How long to keep going on the battery before shutting down (1-254s).
+const HOLD_SECS: u8 = 60;
+
+// Move to (P)ark before shutting down, if the car is standing still.
+const PARK: bool = true;
+
+// Shut down even when there's no CAN-bus to tell that the ignition is off, and the car is
+// standing still. Only for a car where the power only ever goes with the ignition, the power
+// going is then all there is to go on.
+const WITHOUT_CAN: bool = false;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let r = split_resources! {p};
+
+    info!(
+        "Setting shutdown hold time to {}s, park {}, without CAN {} in flash",
+        HOLD_SECS, PARK, WITHOUT_CAN
+    );
+
+    // Instantiate the flash.
+    let flash = init_flash(r.flash);
+
+    // Read old values.
+    let mut flash = flash.lock().await;
+    match DbwConfig::read(&mut flash) {
+        Ok(mut config) => {
+            config.shutdown_hold_secs = HOLD_SECS;
+            config.park_on_shutdown = PARK;
+            config.shutdown_without_can = WITHOUT_CAN;
+
+            // Write flash.
+            lib_config::write_flash(&mut flash, config).await;
+        }
+        Err(e) => error!("Failed to read flash: {:?}", e),
+    }
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
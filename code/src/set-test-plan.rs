@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+//! Set what `test-actuator` runs, in the flash.
+//! Change `PLAN` below, then run it. Use `TestSequence::Skip` for the unused steps.
+
+use defmt::{error, info};
+use embassy_executor::Spawner;
+
+pub mod lib_config;
+pub mod lib_gear;
+pub mod lib_resources;
+
+use crate::lib_config::{init_flash, DbwConfig, TestPlan, TestSequence};
+use crate::lib_gear::Button;
+use crate::lib_resources::*;
+
+use {defmt_rtt as _, panic_probe as _};
+
+// The sequences to run, in order, and how many iterations of each.
+const PLAN: TestPlan = TestPlan {
+    steps: [
+        (TestSequence::Sweep, 10),
+        (TestSequence::GearCycle, 25),
+        (TestSequence::Random, 100),
+        (TestSequence::Skip, 0),
+    ],
+    // The same seed gives the same random targets. Change it for a different set.
+    seed: 0x2010_0171,
+};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let r = split_resources! {p};
+
+    info!("Setting the actuator test plan to {:?} in flash", PLAN);
+
+    // Instantiate the flash.
+    let flash = init_flash(r.flash);
+
+    // Read old values.
+    let mut flash = flash.lock().await;
+    match DbwConfig::read(&mut flash) {
+        Ok(mut config) => {
+            config.test_plan = PLAN;
+
+            // Write flash.
+            lib_config::write_flash(&mut flash, config).await;
+        }
+        Err(e) => error!("Failed to read flash: {:?}", e),
+    }
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
@@ -11,15 +11,12 @@
 use defmt::{error, info};
 use embassy_executor::Spawner;
 
-pub mod lib_actuator;
-pub mod lib_buttons;
-pub mod lib_can_bus;
 pub mod lib_config;
+pub mod lib_gear;
 pub mod lib_resources;
-pub mod lib_ups;
 
-use crate::lib_buttons::Button;
 use crate::lib_config::{init_flash, DbwConfig};
+use crate::lib_gear::Button;
 use crate::lib_resources::*;
 
 use {defmt_rtt as _, panic_probe as _};
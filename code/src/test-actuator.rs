@@ -1,9 +1,13 @@
 #![no_std]
 #![no_main]
 
-//! Connect to the actuator and move it back and forth in different ways.
+//! Endurance test of the actuator. Runs the moves in the test plan (end to end sweeps, gear
+//! cycles and random targets), measures every move and prints a summary with histograms at
+//! the end. Use it to compare the reliability before and after a change.
+//! The plan is read from the flash, set it with `set-test-plan`. Without one, it runs
+//! `DEFAULT_TEST_PLAN`.
 
-use defmt::{debug, info};
+use defmt::{debug, error, info, unwrap, warn};
 use {defmt_serial as _, panic_probe as _};
 
 use embassy_executor::Spawner;
@@ -17,19 +21,37 @@ use embassy_time::Timer;
 
 use static_cell::StaticCell;
 
-use actuator::{
-    Actuator, GearModes, RESISTANCE_THROW_1MM, RESISTANCE_THROW_MAX, RESISTANCE_THROW_MIN,
-};
-
 // External "defines". All because we need the `Button` define!!
 pub mod lib_actuator;
 pub mod lib_buttons;
+pub mod lib_calibration;
 pub mod lib_can_bus;
+pub mod lib_can_frame;
 pub mod lib_config;
+pub mod lib_dc_actuator;
+pub mod lib_eventlog;
+pub mod lib_expander;
+pub mod lib_gear;
+pub mod lib_gear_actuator;
+pub mod lib_i2c;
+pub mod lib_leds;
+pub mod lib_linear_actuator;
+pub mod lib_motion;
+pub mod lib_motor_current;
+pub mod lib_pot;
 pub mod lib_resources;
+pub mod lib_status;
+pub mod lib_stepper_actuator;
 pub mod lib_ups;
+pub mod lib_watchdog;
 
 use crate::lib_buttons::Button;
+use crate::lib_config::{
+    init_flash, Calibration, DbwConfig, TestSequence, DEFAULT_TEST_PLAN, TEST_PLAN_STEPS,
+};
+use crate::lib_gear_actuator::{uncalibrated, Actuator, GearActuator, MoveError, MoveReport};
+use crate::lib_i2c::init_i2c;
+use crate::lib_motor_current::motor_current_monitor;
 use crate::lib_resources::*;
 
 bind_interrupts!(struct Irqs {
@@ -37,8 +59,232 @@ bind_interrupts!(struct Irqs {
     ADC_IRQ_FIFO => ADCInterruptHandler;		// Actuator potentiometer
 });
 
+// Let the actuator come to rest before checking where it ended up.
+const SETTLE_MS: u64 = 100;
+
+// Between the moves, let the motor cool down a little.
+const PAUSE_MS: u64 = 250;
+
+const BAR: &str = "########################################";
+
+#[derive(Copy, Clone)]
+struct Histogram<const N: usize> {
+    width: u32,
+    buckets: [u32; N], // The last one takes everything above.
+}
+
+impl<const N: usize> Histogram<N> {
+    fn new(width: u32) -> Self {
+        Self {
+            width: width.max(1),
+            buckets: [0; N],
+        }
+    }
+
+    fn add(&mut self, value: u32) {
+        self.buckets[((value / self.width) as usize).min(N - 1)] += 1;
+    }
+
+    fn log(&self, name: &str) {
+        info!("  {}:", name);
+
+        let max = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (i, count) in self.buckets.iter().enumerate() {
+            let bar = &BAR[..(count * BAR.len() as u32 / max) as usize];
+            let low = i as u32 * self.width;
+            if i == N - 1 {
+                info!("    >={}: {} {}", low, count, bar);
+            } else {
+                info!("    {}-{}: {} {}", low, low + self.width - 1, count, bar);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Stats {
+    moves: u32,
+    timeouts: u32,
+    stalls: u32,
+    over_currents: u32,
+    out_of_window: u32,
+    error_sum: u32,
+    error_max: u16,
+    duration_sum: u64,
+    duration_max: u64,
+    overshoot_max: u16,
+    error: Histogram<8>,
+    duration: Histogram<10>,
+    overshoot: Histogram<8>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        // Quarter millimetres for the positions, 100ms for the time.
+        let quarter = (Actuator::POSITION_1MM / 4) as u32;
+        Self {
+            moves: 0,
+            timeouts: 0,
+            stalls: 0,
+            over_currents: 0,
+            out_of_window: 0,
+            error_sum: 0,
+            error_max: 0,
+            duration_sum: 0,
+            duration_max: 0,
+            overshoot_max: 0,
+            error: Histogram::new(quarter),
+            duration: Histogram::new(100),
+            overshoot: Histogram::new(quarter),
+        }
+    }
+
+    fn add(&mut self, result: Result<MoveReport, MoveError>, error: u16, tolerance: u16) {
+        self.moves += 1;
+
+        let report = match result {
+            Ok(report) => report,
+            Err(MoveError::Timeout { .. }) => {
+                self.timeouts += 1;
+                return;
+            }
+            Err(MoveError::Stall { .. }) => {
+                self.stalls += 1;
+                return;
+            }
+            Err(MoveError::OverCurrent { .. }) => {
+                self.over_currents += 1;
+                return;
+            }
+            // Nothing pre-empts the moves here, but don't count it if it happens.
+            Err(MoveError::Preempted { .. }) => {
+                self.moves -= 1;
+                return;
+            }
+        };
+
+        if error > tolerance {
+            self.out_of_window += 1;
+        }
+        self.error_sum += error as u32;
+        self.error_max = self.error_max.max(error);
+        self.error.add(error as u32);
+
+        self.duration_sum += report.duration_ms;
+        self.duration_max = self.duration_max.max(report.duration_ms);
+        self.duration.add(report.duration_ms as u32);
+
+        self.overshoot_max = self.overshoot_max.max(report.overshoot);
+        self.overshoot.add(report.overshoot as u32);
+    }
+
+    fn failures(&self) -> u32 {
+        self.timeouts + self.stalls + self.over_currents + self.out_of_window
+    }
+
+    fn log(&self, name: &str) {
+        info!(
+            "== {}: {} moves, {} failed",
+            name,
+            self.moves,
+            self.failures()
+        );
+        info!(
+            "  Timeouts: {}, stalls: {}, over-current: {}, outside the window: {}",
+            self.timeouts, self.stalls, self.over_currents, self.out_of_window
+        );
+
+        let done = self.moves - self.timeouts - self.stalls - self.over_currents;
+        if done == 0 {
+            return;
+        }
+        info!(
+            "  Error: avg {}, max {}. Duration: avg {}ms, max {}ms. Overshoot: max {}",
+            self.error_sum / done,
+            self.error_max,
+            self.duration_sum / done as u64,
+            self.duration_max,
+            self.overshoot_max
+        );
+
+        self.error.log("Positioning error");
+        self.duration.log("Move duration (ms)");
+        self.overshoot.log("Overshoot");
+    }
+}
+
+// Xorshift, nothing fancy needed here.
+struct Random(u32);
+
+impl Random {
+    // 0 - `range`
+    fn below(&mut self, range: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 % range
+    }
+}
+
+// Move, let it settle and record how it went. Failures are logged, but we keep going anyway.
+async fn measure(actuator: &mut Actuator<'static>, stats: &mut Stats, target: u16, tolerance: u16) {
+    let target = target.clamp(Actuator::POSITION_MIN, Actuator::POSITION_MAX);
+    let result = actuator.move_to(target).await;
+    if let Err(e) = result {
+        error!("Actuator failed to move to {}: {:?}", target, e);
+    }
+
+    Timer::after_millis(SETTLE_MS).await;
+    let position = actuator.read_position().await;
+    let error = position.abs_diff(target);
+    debug!(
+        "Moved to {}, ended at {} (error {})",
+        target, position, error
+    );
+
+    stats.add(result, error, tolerance);
+    Timer::after_millis(PAUSE_MS).await;
+}
+
+async fn run(
+    actuator: &mut Actuator<'static>,
+    calibration: &Calibration,
+    random: &mut Random,
+    sequence: TestSequence,
+    iterations: u16,
+) -> Stats {
+    let mut stats = Stats::new();
+    let tolerance = calibration.tolerance;
+
+    info!("Running {}, {} iterations", sequence, iterations);
+    for i in 1..=iterations {
+        debug!("{} {}/{}", sequence, i, iterations);
+        match sequence {
+            TestSequence::Skip => {}
+            TestSequence::Sweep => {
+                let min = Actuator::POSITION_MIN + Actuator::POSITION_1MM * 3;
+                let max = Actuator::POSITION_MAX - Actuator::POSITION_1MM * 3;
+                measure(actuator, &mut stats, max, tolerance).await;
+                measure(actuator, &mut stats, min, tolerance).await;
+            }
+            TestSequence::GearCycle => {
+                for gear in Button::iterator() {
+                    measure(actuator, &mut stats, calibration.position(gear), tolerance).await;
+                }
+            }
+            TestSequence::Random => {
+                let range = (Actuator::POSITION_MAX - Actuator::POSITION_MIN) as u32;
+                let target = Actuator::POSITION_MIN + random.below(range) as u16;
+                measure(actuator, &mut stats, target, tolerance).await;
+            }
+        }
+    }
+
+    stats
+}
+
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let r = split_resources! {p};
 
@@ -60,99 +306,80 @@ async fn main(_spawner: Spawner) {
         env!("GIT_HASH")
     );
 
+    // Measure the motor current, if there's a monitor for it on the I²C bus.
+    let i2c_bus = init_i2c(r.i2c);
+    spawner.spawn(unwrap!(motor_current_monitor(i2c_bus)));
+
     // Initialize the actuator.
-    info!("Initializing actuator");
-    let mut actuator = Actuator::new(
-        r.actuator.mplus.into(),  // pin_motor_plus
-        r.actuator.mminus.into(), // pin_motor_minus
-        r.actuator.vsel.into(),   // pin_volt_select - UART0
-        r.actuator.pot,           // pin_pot         - ADC2
-        r.actuator.adc,
-        Irqs,
-    );
+    info!("Initializing {} actuator", Actuator::NAME);
+    let mut actuator = Actuator::new(r.actuator, Irqs, spawner.make_send());
     info!("Actuator initialized");
 
-    // -----
-
-    // 0. Before we do anything, we move the actuator to fully retracted.
-    // LOOP:
-    // 1. Move the actuator to fully extended.
-    // 2. Move the actuator 10mm backward 10 times.
-    // 3. Move the actuator 10mm forward 10 times.
-    // 4. Move the actuator to fully retracted.
-    // 5. Move the actuator to each of the gear modes (from 'P' to 'D').
-    // 6. Move the actuator back to 'P'.
-
-    // TODO: What do we do if the actuator haven't moved?
-
-    let mut position: u16;
-
-    // Move to the fully retracted position.
-    // NOTE: Just so we start from somewhere :).
-    position = RESISTANCE_THROW_MIN + (RESISTANCE_THROW_1MM * 3);
-    info!("0.Move actuator to the MIN end position: {:04}Ω", position);
-    actuator.move_actuator(position).await;
-    Timer::after_secs(3).await;
-
-    loop {
-        // Move to the fully extended position.
-        position = RESISTANCE_THROW_MAX - (RESISTANCE_THROW_1MM * 3);
-        info!("1.Move actuator to the MAX end position: {:04}Ω", position);
-        actuator.move_actuator(position).await;
-        Timer::after_secs(3).await;
-
-        // Move backward 10mm at a time, 10 times
-        info!("2.Move actuator backward 10mm at a time, 10 times");
-        for i in 1..=10 {
-            let position_now: u16 = actuator.read_pot().await;
-            position = position_now - (RESISTANCE_THROW_1MM * 10);
-
-            info!("    Move backward 10mm/{:02}: {}Ω", i, position);
-            actuator.move_actuator(position).await;
-
-            Timer::after_secs(1).await;
+    // Use the calibrated gear positions, if there are any, and the test plan.
+    let (calibration, plan) = {
+        let flash = init_flash(r.flash);
+        let mut flash = flash.lock().await;
+        match DbwConfig::read(&mut flash) {
+            Ok(config) if config.calibration.valid => (config.calibration, config.test_plan),
+            Ok(config) => {
+                warn!("Actuator not calibrated, using the default gear positions");
+                (uncalibrated(), config.test_plan)
+            }
+            Err(_) => {
+                warn!("Failed to read the flash, using the default gear positions and test plan");
+                (uncalibrated(), DEFAULT_TEST_PLAN)
+            }
         }
-        Timer::after_secs(2).await;
+    };
+    info!("Test plan: {:?}", plan);
 
-        // Move forward 10mm at a time, 10 times.
-        info!("3.Move actuator forward 10mm at a time, 10 times");
-        for i in 1..=10 {
-            let position_now: u16 = actuator.read_pot().await;
-            position = position_now + (RESISTANCE_THROW_1MM * 10);
+    // Same as in the main app.
+    actuator.set_deadband(calibration.tolerance / 2);
 
-            info!("    Move forward 10mm/{:02}: {}Ω", i, position);
-            actuator.move_actuator(position).await;
+    // -----
 
-            Timer::after_secs(1).await;
-        }
-        Timer::after_secs(2).await;
-
-        // Move to the fully retracted position.
-        position = RESISTANCE_THROW_MIN + (RESISTANCE_THROW_1MM * 3);
-        info!("4.Move actuator to the MIN end position: {:04}Ω", position);
-        actuator.move_actuator(position).await;
-        Timer::after_secs(3).await;
-
-        // Move the actuator one gear mode at a time, starting with `P`.
-        info!("5. Move actuator to specific gear modes");
-        for mode in GearModes::iterator() {
-            info!("  Mode={}", mode);
-            actuator.change_gear_mode(mode).await;
-
-            info!("  Mode={} - DONE", mode);
-            Timer::after_secs(1).await;
-            debug!("After sleep (1)..");
+    // Xorshift never gets anywhere from zero.
+    let mut random = Random(plan.seed.max(1));
+    let mut failures = 0;
+    let mut moves = 0;
+    let mut results = [Stats::new(); TEST_PLAN_STEPS];
+    for (i, (sequence, iterations)) in plan.steps.iter().enumerate() {
+        if *sequence == TestSequence::Skip {
+            continue;
         }
+        let stats = run(
+            &mut actuator,
+            &calibration,
+            &mut random,
+            *sequence,
+            *iterations,
+        )
+        .await;
+        failures += stats.failures();
+        moves += stats.moves;
+        results[i] = stats;
+    }
 
-        Timer::after_secs(2).await;
-        debug!("After sleep (2)..");
+    // Leave it in (P)ark.
+    let _ = actuator.move_to_gear(&calibration, Button::P).await;
 
-        info!("6. Move actuator from 'D' back to 'P'");
-        actuator.change_gear_mode(GearModes::P).await;
+    info!("===== Summary");
+    for (i, stats) in results.iter().enumerate() {
+        match plan.steps[i].0 {
+            TestSequence::Skip => {}
+            TestSequence::Sweep => stats.log("Sweep"),
+            TestSequence::GearCycle => stats.log("Gear cycle"),
+            TestSequence::Random => stats.log("Random"),
+        }
+    }
 
-        Timer::after_secs(3).await;
-        debug!("After sleep (3)..");
+    if failures == 0 {
+        info!("PASSED: {} moves, no failures", moves);
+    } else {
+        error!("FAILED: {} of {} moves failed", failures, moves);
+    }
 
-        info!("--");
+    loop {
+        Timer::after_secs(600).await;
     }
 }
@@ -11,18 +11,15 @@
 use defmt::{error, info};
 use embassy_executor::Spawner;
 
-pub mod lib_actuator;
-pub mod lib_buttons;
-pub mod lib_can_bus;
 pub mod lib_config;
+pub mod lib_gear;
 pub mod lib_resources;
-pub mod lib_ups;
 
-use crate::lib_buttons::Button;
 use crate::lib_config::{init_flash, DbwConfig};
+use crate::lib_gear::Button;
 use crate::lib_resources::{
     AssignedResources, PeriActuator, PeriBuiltin, PeriButtons, PeriCan, PeriEis, PeriFPScanner,
-    PeriFlash, PeriNeopixel, PeriPowerMonitor, PeriSerial, PeriWatchdog,
+    PeriFlash, PeriI2c, PeriNeopixel, PeriSerial, PeriWatchdog,
 };
 
 use {defmt_rtt as _, panic_probe as _};